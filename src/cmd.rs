@@ -1,13 +1,10 @@
-use std::path::PathBuf;
+use std::cell::RefCell;
+use std::path::{Path, PathBuf};
 use std::fs;
-use std::fs::File;
 use std::io;
-use std::io::{Read, Write};
-use std::io::BufReader;
 
 use std::str;
-use dbg_hex::dbg_hex;
-use env_logger::Env;
+use std::time::Duration;
 
 use rusty_cmd::cmd::Cmd;
 use rusty_cmd::command_handler::{
@@ -18,41 +15,37 @@ use rusty_cmd::handlers::Quit;
 
 use sevenate::Ranged;
 use syxpack::{
-    Message,
-    Manufacturer
+    INITIATOR,
+    split_messages,
 };
 
+use sevenator::dx7::{find_messages, parse_bulk_dump};
+use sevenator::dx7::edit::{
+    SortKey,
+    move_voice,
+    swap_voices,
+    replace_voice,
+    copy_voices,
+    sort_voices,
+};
+use sevenator::error::Error;
+
+use sevenate::dx7::cartridge::{Cartridge, VOICE_COUNT};
 use sevenate::dx7::voice::Voice;
-use sevenate::dx7::cartridge::Cartridge;
 
 use sevenate::dx7::sysex::{
     Format,
     Header,
     SystemExclusiveData,
     MIDIChannel,
-    checksum
 };
 
-fn read_file(name: &PathBuf) -> Option<Vec<u8>> {
-    match fs::File::open(&name) {
-        Ok(mut f) => {
-            let mut buffer = Vec::new();
-            match f.read_to_end(&mut buffer) {
-                Ok(_) => Some(buffer),
-                Err(_) => None
-            }
-        },
-        Err(_) => {
-            eprintln!("Unable to open file {}", &name.display());
-            None
-        }
-    }
+fn read_file(path: &Path) -> Result<Vec<u8>, Error> {
+    fs::read(path).map_err(|e| Error::io(path, e))
 }
 
-fn write_file(path: &PathBuf, data: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
-    //let mut f = fs::File::create(&name).expect("create file");
-    fs::write(path, data)?;
-    Ok(())
+fn write_file(path: &Path, data: &[u8]) -> Result<(), Error> {
+    fs::write(path, data).map_err(|e| Error::io(path, e))
 }
 
 /// CommandHandler that prints out help message
@@ -71,6 +64,7 @@ impl<W> CommandHandler<W> for Help
 }
 
 use midir::{Ignore, MidiInput, MidiOutput};
+use sevenator::midi::{send_messages, receive_message, Request};
 
 /// Command handler for the `ports` command to list MIDI ports
 #[derive(Default)]
@@ -79,16 +73,22 @@ pub struct Ports;
 impl<W> CommandHandler<W> for Ports
         where W: std::io::Write, {
     fn execute(&self, output: &mut W, _args: &[&str]) -> CommandResult {
-        let mut midi_in = MidiInput::new("sevenator test input").unwrap();
+        let (mut midi_in, midi_out) = match (MidiInput::new("sevenator test input"), MidiOutput::new("sevenator test output")) {
+            (Ok(midi_in), Ok(midi_out)) => (midi_in, midi_out),
+            _ => {
+                writeln!(output, "Unable to access MIDI")
+                    .expect("Should be able to write to output");
+                return CommandResult::Continue;
+            }
+        };
         midi_in.ignore(Ignore::None);
-        let midi_out = MidiOutput::new("sevenator test output").unwrap();
         
         writeln!(output, "Available MIDI input ports:")
             .expect("Should be able to write to output");
         for (id, port) in midi_in.ports().iter().enumerate() {
             writeln!(
                 output, 
-                "{}: {} (ID: \"{}\")", id, midi_in.port_name(port).unwrap(), port.id())
+                "{}: {} (ID: \"{}\")", id, midi_in.port_name(port).unwrap_or_default(), port.id())
             .expect("Should be able to write to output");
         }
 
@@ -97,7 +97,7 @@ impl<W> CommandHandler<W> for Ports
         for (id, port) in midi_out.ports().iter().enumerate() {
             writeln!(
                 output, 
-                "{}: {} (ID: \"{}\")", id, midi_out.port_name(port).unwrap(), port.id())
+                "{}: {} (ID: \"{}\")", id, midi_out.port_name(port).unwrap_or_default(), port.id())
                 .expect("Should be able to write to output");
         }        
 
@@ -105,749 +105,1637 @@ impl<W> CommandHandler<W> for Ports
     }
 }
 
-pub fn run_repl() -> Result<(), std::io::Error> {
+/// Command handler for the `send` command to send a file to a MIDI port.
+/// Usage: `send <file> <port> [voice number]`
+#[derive(Default)]
+pub struct Send;
+
+impl<W> CommandHandler<W> for Send
+        where W: std::io::Write, {
+    fn execute(&self, output: &mut W, args: &[&str]) -> CommandResult {
+        if args.len() < 2 {
+            writeln!(output, "Usage: send <file> <port> [voice number]")
+                .expect("Should be able to write to output");
+            return CommandResult::Continue;
+        }
+
+        let path = PathBuf::from(args[0]);
+        let number = match args.get(2).map(|n| n.parse::<u8>()) {
+            Some(Ok(n)) => Some(n),
+            Some(Err(_)) => {
+                writeln!(output, "Invalid voice number '{}'", args[2])
+                    .expect("Should be able to write to output");
+                return CommandResult::Continue;
+            },
+            None => None,
+        };
+
+        if let Err(e) = run_send(&path, args[1], &number, DEFAULT_SEND_DELAY) {
+            writeln!(output, "Error: {}", e)
+                .expect("Should be able to write to output");
+        }
+        CommandResult::Continue
+    }
+}
+
+/// Command handler for the `receive` command to receive a bulk dump
+/// from a MIDI port and save it to a file.
+/// Usage: `receive <file> <port> [voice|cartridge]`
+#[derive(Default)]
+pub struct Receive;
+
+impl<W> CommandHandler<W> for Receive
+        where W: std::io::Write, {
+    fn execute(&self, output: &mut W, args: &[&str]) -> CommandResult {
+        if args.len() < 2 {
+            writeln!(output, "Usage: receive <file> <port> [voice|cartridge]")
+                .expect("Should be able to write to output");
+            return CommandResult::Continue;
+        }
+
+        let path = PathBuf::from(args[0]);
+        let request = match args.get(2) {
+            Some(kind) => match DumpKind::from_str(kind, true) {
+                Ok(kind) => Some(kind),
+                Err(_) => {
+                    writeln!(output, "Unknown dump kind '{}', expected voice or cartridge", kind)
+                        .expect("Should be able to write to output");
+                    return CommandResult::Continue;
+                }
+            },
+            None => None,
+        };
+
+        if let Err(e) = run_receive(&path, args[1], request, &None, 1, DEFAULT_RECEIVE_TIMEOUT) {
+            writeln!(output, "Error: {}", e)
+                .expect("Should be able to write to output");
+        }
+        CommandResult::Continue
+    }
+}
+
+// Parses the voice numbers in the arguments of a REPL command.
+fn parse_numbers(args: &[&str]) -> Result<Vec<usize>, String> {
+    args.iter()
+        .map(|arg| arg.parse::<usize>().map_err(|_| format!("Invalid voice number '{}'", arg)))
+        .collect()
+}
+
+// Writes the result of a REPL command that edits a cartridge.
+fn write_edit_result<W: std::io::Write>(output: &mut W, result: Result<(), Error>) -> CommandResult {
+    if let Err(e) = result {
+        writeln!(output, "Error: {}", e)
+            .expect("Should be able to write to output");
+    }
+    CommandResult::Continue
+}
+
+/// Command handler for the `move` command to move a voice in a cartridge file.
+/// Usage: `move <file> <from> <to>`
+#[derive(Default)]
+pub struct Move;
+
+impl<W> CommandHandler<W> for Move
+        where W: std::io::Write, {
+    fn execute(&self, output: &mut W, args: &[&str]) -> CommandResult {
+        let numbers = match args {
+            [_, rest @ ..] if rest.len() == 2 => parse_numbers(rest),
+            _ => Err(String::from("Usage: move <file> <from> <to>")),
+        };
+        match numbers {
+            Ok(n) => write_edit_result(output, run_move(&PathBuf::from(args[0]), n[0], n[1], &None)),
+            Err(message) => {
+                writeln!(output, "{}", message).expect("Should be able to write to output");
+                CommandResult::Continue
+            }
+        }
+    }
+}
+
+/// Command handler for the `swap` command to swap two voices in a cartridge file.
+/// Usage: `swap <file> <a> <b>`
+#[derive(Default)]
+pub struct Swap;
+
+impl<W> CommandHandler<W> for Swap
+        where W: std::io::Write, {
+    fn execute(&self, output: &mut W, args: &[&str]) -> CommandResult {
+        let numbers = match args {
+            [_, rest @ ..] if rest.len() == 2 => parse_numbers(rest),
+            _ => Err(String::from("Usage: swap <file> <a> <b>")),
+        };
+        match numbers {
+            Ok(n) => write_edit_result(output, run_swap(&PathBuf::from(args[0]), n[0], n[1], &None)),
+            Err(message) => {
+                writeln!(output, "{}", message).expect("Should be able to write to output");
+                CommandResult::Continue
+            }
+        }
+    }
+}
+
+/// Command handler for the `replace` command to replace a voice
+/// in a cartridge file with the voice in a single voice file.
+/// Usage: `replace <file> <number> <voice file>`
+#[derive(Default)]
+pub struct Replace;
+
+impl<W> CommandHandler<W> for Replace
+        where W: std::io::Write, {
+    fn execute(&self, output: &mut W, args: &[&str]) -> CommandResult {
+        let numbers = match args {
+            [_, number, _] => parse_numbers(&[number]),
+            _ => Err(String::from("Usage: replace <file> <number> <voice file>")),
+        };
+        match numbers {
+            Ok(n) => write_edit_result(output,
+                run_replace(&PathBuf::from(args[0]), n[0], &PathBuf::from(args[2]), &None)),
+            Err(message) => {
+                writeln!(output, "{}", message).expect("Should be able to write to output");
+                CommandResult::Continue
+            }
+        }
+    }
+}
+
+/// Command handler for the `copy` command to copy voices from one
+/// cartridge file to another.
+/// Usage: `copy <source> <first> <last> <target> <to>`
+#[derive(Default)]
+pub struct CopyVoices;
+
+impl<W> CommandHandler<W> for CopyVoices
+        where W: std::io::Write, {
+    fn execute(&self, output: &mut W, args: &[&str]) -> CommandResult {
+        let numbers = match args {
+            [_, first, last, _, to] => parse_numbers(&[first, last, to]),
+            _ => Err(String::from("Usage: copy <source> <first> <last> <target> <to>")),
+        };
+        match numbers {
+            Ok(n) => write_edit_result(output,
+                run_copy(&PathBuf::from(args[0]), n[0], n[1], &PathBuf::from(args[3]), n[2], &None)),
+            Err(message) => {
+                writeln!(output, "{}", message).expect("Should be able to write to output");
+                CommandResult::Continue
+            }
+        }
+    }
+}
+
+/// Command handler for the `sort` command to sort the voices in a cartridge file.
+/// Usage: `sort <file> <name|algorithm>`
+#[derive(Default)]
+pub struct Sort;
+
+impl<W> CommandHandler<W> for Sort
+        where W: std::io::Write, {
+    fn execute(&self, output: &mut W, args: &[&str]) -> CommandResult {
+        let key = match args {
+            [_, key] => key.parse::<SortKey>(),
+            _ => Err(String::from("Usage: sort <file> <name|algorithm>")),
+        };
+        match key {
+            Ok(key) => write_edit_result(output, run_sort(&PathBuf::from(args[0]), key, &None)),
+            Err(message) => {
+                writeln!(output, "{}", message).expect("Should be able to write to output");
+                CommandResult::Continue
+            }
+        }
+    }
+}
+
+/// Command handler for the `perf` command to edit a performance
+/// in a TX802 performance memory file.
+/// Usage: `perf <file> <performance> <action> [arguments]`, where the action is
+/// `new [name]`, `show`, `assign <tg> <voice>`, `set <tg> <parameter> <value>`,
+/// `limits <tg> <low> <high>`, `split <note> <lower voice> <upper voice>`
+/// or `layer <voice>...`
+#[derive(Default)]
+pub struct Perf;
+
+impl<W> CommandHandler<W> for Perf
+        where W: std::io::Write, {
+    fn execute(&self, output: &mut W, args: &[&str]) -> CommandResult {
+        let usage = "Usage: perf <file> <performance> new [name] | show | assign <tg> <voice> | set <tg> <parameter> <value> \
+            | limits <tg> <low> <high> | split <note> <lower> <upper> | layer <voice>...";
+        let [file, number, action, rest @ ..] = args else {
+            writeln!(output, "{}", usage).expect("Should be able to write to output");
+            return CommandResult::Continue;
+        };
+
+        let path = PathBuf::from(file);
+        let Ok(number) = number.parse::<usize>() else {
+            writeln!(output, "Invalid performance number '{}'", number).expect("Should be able to write to output");
+            return CommandResult::Continue;
+        };
+        let tg = |arg: &str| arg.parse::<usize>().map_err(|_| Error::Range(format!("Invalid tone generator number '{}'", arg)));
+
+        let result = match (*action, rest) {
+            ("new", []) => run_perf_new(&Some(path), number, INIT_NAME, &None),
+            ("new", name) => run_perf_new(&Some(path), number, &name.join(" "), &None),
+            ("show", []) => run_perf_show(&path, number),
+            ("assign", [t, voice]) => tg(t).and_then(|t| run_perf_assign(&path, number, t, voice, &None)),
+            ("set", [t, parameter, value]) => match value.parse::<u8>() {
+                Ok(value) => tg(t).and_then(|t| run_perf_set(&path, number, t, parameter, value, &None)),
+                Err(_) => Err(Error::Range(format!("Invalid value '{}'", value))),
+            },
+            ("limits", [t, low, high]) => tg(t).and_then(|t| run_perf_limits(&path, number, t, low, high, &None)),
+            ("split", [note, lower, upper]) => run_perf_split(&path, number, note, lower, upper, &None),
+            ("layer", voices) if !voices.is_empty() => {
+                let voices: Vec<String> = voices.iter().map(|v| v.to_string()).collect();
+                run_perf_layer(&path, number, &voices, &None)
+            },
+            _ => {
+                writeln!(output, "{}", usage).expect("Should be able to write to output");
+                return CommandResult::Continue;
+            }
+        };
+        write_edit_result(output, result)
+    }
+}
+
+/// Command handler for the `setup` command to write a complete TX802 setup
+/// into one file.
+/// Usage: `setup <output file> <performance file> <cartridge file> [cartridge file]`
+#[derive(Default)]
+pub struct Setup;
+
+impl<W> CommandHandler<W> for Setup
+        where W: std::io::Write, {
+    fn execute(&self, output: &mut W, args: &[&str]) -> CommandResult {
+        let [output_file, pmem_file, cartridge_files @ ..] = args else {
+            writeln!(output, "Usage: setup <output file> <performance file> <cartridge file> [cartridge file]")
+                .expect("Should be able to write to output");
+            return CommandResult::Continue;
+        };
+
+        let cartridge_paths: Vec<PathBuf> = cartridge_files.iter().map(PathBuf::from).collect();
+        write_edit_result(output,
+            run_export_setup(&PathBuf::from(pmem_file), &cartridge_paths, &PathBuf::from(output_file)))
+    }
+}
+
+/// Command handler for the `set` command to change a parameter of the voice
+/// in the edit buffer of the synth right away, for editing sounds live.
+/// The connection to the port stays open between commands.
+/// Usage: `set <port> <parameter> <value>`, or `set <port> name <voice name>`
+#[derive(Default)]
+pub struct Set {
+    connection: RefCell<Option<OutputConnection>>,
+}
+
+impl Set {
+    // Sends messages over the open connection, opening it first
+    // if there is none or if it is to another port.
+    fn send(&self, port: &str, messages: &[Vec<u8>]) -> Result<(), Error> {
+        let midi_error = |e: Box<dyn std::error::Error>| Error::Midi(format!("Error sending to MIDI port: {}", e));
+
+        let mut connection = self.connection.borrow_mut();
+        if connection.as_ref().is_none_or(|c| c.port() != port) {
+            *connection = None;
+            *connection = Some(OutputConnection::open(port).map_err(midi_error)?);
+        }
+
+        // Open the connection again next time if the port went away.
+        let result = messages.iter()
+            .try_for_each(|message| connection.as_mut().expect("connection should be open").send(message));
+        if result.is_err() {
+            *connection = None;
+        }
+        result.map_err(midi_error)
+    }
+}
+
+impl<W> CommandHandler<W> for Set
+        where W: std::io::Write, {
+    fn execute(&self, output: &mut W, args: &[&str]) -> CommandResult {
+        let [port, parameter, value @ ..] = args else {
+            writeln!(output, "Usage: set <port> <parameter> <value> | set <port> name <voice name>")
+                .expect("Should be able to write to output");
+            return CommandResult::Continue;
+        };
+        if value.is_empty() {
+            writeln!(output, "Missing value for {}", parameter).expect("Should be able to write to output");
+            return CommandResult::Continue;
+        }
+
+        let value = value.join(" ");
+        let result = parameter_changes(parameter, &value, 1)
+            .and_then(|(name, messages)| self.send(port, &messages).map(|_| name));
+        match result {
+            Ok(name) => writeln!(output, "{} = {}", name, value).expect("Should be able to write to output"),
+            Err(e) => writeln!(output, "Error: {}", e).expect("Should be able to write to output"),
+        }
+        CommandResult::Continue
+    }
+}
+
+/// Runs the REPL until the `quit` command or the end of input.
+pub fn run_repl() -> Result<(), Error> {
+    start_repl().map_err(|e| Error::io(&PathBuf::from("standard input"), e))
+}
+
+fn start_repl() -> Result<(), std::io::Error> {
     let mut cmd = Cmd::new(io::BufReader::new(io::stdin()), io::stdout());
     let help = Help;
     let quit = Quit::default();
     let ports = Ports;
+    let send = Send;
+    let receive = Receive;
 
     cmd.add_cmd(String::from("help"), help)?;
     cmd.add_cmd(String::from("quit"), quit)?;
     cmd.add_cmd(String::from("ports"), ports)?;
+    cmd.add_cmd(String::from("send"), send)?;
+    cmd.add_cmd(String::from("receive"), receive)?;
+    cmd.add_cmd(String::from("move"), Move)?;
+    cmd.add_cmd(String::from("swap"), Swap)?;
+    cmd.add_cmd(String::from("replace"), Replace)?;
+    cmd.add_cmd(String::from("copy"), CopyVoices)?;
+    cmd.add_cmd(String::from("sort"), Sort)?;
+    cmd.add_cmd(String::from("perf"), Perf)?;
+    cmd.add_cmd(String::from("setup"), Setup)?;
+    cmd.add_cmd(String::from("set"), Set::default())?;
 
     cmd.run()?;
 
     Ok(())
 }
 
-pub fn run_list(path: &PathBuf) {
-    if let Some(buffer) = read_file(&path) {
-        match Message::from_bytes(&buffer) {
-            Ok(Message::ManufacturerSpecific { manufacturer, payload }) => {
-                println!("Manufacturer = {}", manufacturer);
-                println!("Payload = {} bytes", payload.len());
-
-                match Header::parse(&payload) {
-                    Ok(header) => {
-                        println!("{}", header);
-
-                        let data = &payload[4..payload.len() - 1];
-                        println!("data length = {}", data.len());
-
-                        match header.format {
-                            Format::Voice => {
-                                let name_data = &data[145..155];
-                                let name = str::from_utf8(&name_data).expect("invalid UTF-8");
-                                println!("{}", name);
-                            },
-                            Format::Cartridge => {
-                                // For a cartridge, pick out the data for each
-                                // of the 32 voices and extract the name.
-                                // The voice data is packed into chunks of 128 bytes,
-                                // and the name is in the last 10 bytes.
-                                let mut voice_number = 1;
-                                for voice_data in data.chunks(128) {
-                                    let name_data = &voice_data[118..128];
-                                    let name = str::from_utf8(&name_data).expect("invalid UTF-8");
-                                    println!("{:2} {}", voice_number, name);
-                                    voice_number += 1;
-                                }
-                            }
-                        }
-                    },
-                    Err(e) => {
-                        eprintln!("{}", e);
-                    }
-                }
-            },
-            Err(err) => {
-                eprintln!("Error in message: {:?}", err);
-            },
-            _ => {
-                eprintln!("Not a manufacturer-specific System Exclusive message");
-            }
+// Makes the error for a file without any System Exclusive messages.
+fn no_messages(path: &Path) -> Error {
+    Error::SysEx(format!("No System Exclusive messages found in {}", path.display()))
+}
+
+// Checks that a voice number is 1...32.
+fn check_voice_number(number: u8) -> Result<usize, Error> {
+    let n = number as usize;
+    if (1..=VOICE_COUNT).contains(&n) {
+        Ok(n)
+    } else {
+        Err(Error::Range(format!("Voice number must be 1...{}, got {}", VOICE_COUNT, number)))
+    }
+}
+
+/// Lists the voices in a file with one or more single voice or cartridge
+/// bulk dumps, like a library of cartridges back to back.
+/// Reports the result for each message separately. If any message is
+/// invalid, fails with the error of the first one after listing the rest.
+pub fn run_list(path: &Path) -> Result<(), Error> {
+    let buffer = read_file(path)?;
+
+    let messages = find_messages(&buffer);
+    if messages.is_empty() {
+        return Err(no_messages(path));
+    }
+
+    let mut errors = Vec::<Error>::new();
+    for (index, (offset, message)) in messages.iter().enumerate() {
+        println!("Message {} at offset {} ({} bytes)", index + 1, offset, message.len());
+
+        if let Err(e) = list_message(message) {
+            eprintln!("Error in message {}: {}", index + 1, e);
+            errors.push(e);
         }
     }
+
+    if errors.is_empty() {
+        return Ok(());
+    }
+    eprintln!("{} of {} message(s) failed", errors.len(), messages.len());
+    Err(errors.remove(0))
 }
 
-pub fn run_extract(path: &PathBuf) {
-    if let Some(buffer) = read_file(&path) {
-        match Message::from_bytes(&buffer) {
-            Ok(Message::ManufacturerSpecific { manufacturer, payload }) => {
-                println!("Manufacturer = {}", manufacturer);
-                println!("Payload = {} bytes", payload.len());
-
-                match Header::parse(&payload) {
-                    Ok(mut header) => {
-                        println!("{}", header);
-
-                        let data = &payload[4..payload.len() - 1];
-                        println!("data length = {}", data.len());
-                        match header.format {
-                            Format::Voice => {
-                                println!("Not extracting an individual voice.")
-                            },
-                            Format::Cartridge => {
-                                // For a cartridge, pick out the data for each
-                                // of the 32 voices. Then unpack the voice data
-                                // and write out a new file.
-                                let mut voice_number = 1;
-                                let stem = path.file_stem().unwrap().to_str().unwrap();
-                                for packed_voice_data in data.chunks(128) {
-                                    let voice_data = Voice::unpack(packed_voice_data);
-
-                                    let mut payload = Vec::<u8>::new();
-
-                                    // Change the format and byte count in the header,
-                                    // then add it to the file data. Use the original channel.
-                                    header.format = Format::Voice;
-                                    header.byte_count = 155;
-                                    payload.extend(header.to_bytes());
-                                    payload.extend(&voice_data);
-
-                                    payload.push(checksum(&voice_data.clone()));
-
-                                    let message = Message::ManufacturerSpecific {
-                                        manufacturer: Manufacturer::Standard(0x43),
-                                        payload
-                                    };
-
-                                    let filename = format!("{}-{:02}.syx", stem, voice_number);
-                                    match File::create(filename) {
-                                        Ok(mut file) => {
-                                            match file.write_all(&message.to_bytes()) {
-                                                Ok(_) => {
-
-                                                },
-                                                Err(e) => {
-                                                    eprintln!("Error writing file: {}", e);
-                                                }
-                                            }
-                                        },
-                                        Err(e) => {
-                                            eprintln!("Error creating file: {}", e);
-                                        }
-                                    }
-
-                                    voice_number += 1;
-                                }
-                            }
-                        }
-                    },
-                    Err(e) => {
-                        eprintln!("{}", e);
-                    }
-                }
-            },
-            Err(err) => {
-                eprintln!("Error in message: {:?}", err);
-            },
-            _ => {
-                eprintln!("Not a manufacturer-specific System Exclusive message");
+// Lists the contents of one message in a file.
+fn list_message(message: &[u8]) -> Result<(), Error> {
+    if tuning_kind(message).is_some() {
+        let table = parse_tuning(message)?;
+        println!("{} micro-tuning table", table.kind);
+        return Ok(());
+    }
+
+    if supplement_format(message).is_some() {
+        let supplements = parse_supplement_dump(message)?;
+        println!("DX7II supplements for {} voice(s)", supplements.len());
+        return Ok(());
+    }
+
+    if is_pmem(message) {
+        let memory = parse_pmem(message)?;
+        for (number, performance) in memory.performances.iter().enumerate() {
+            println!("{:2} {}", number + 1, performance.name);
+        }
+        return Ok(());
+    }
+
+    match parse_bulk_dump(message)? {
+        Document::Voice(voice) => {
+            println!("{}", voice.name.value());
+        },
+        Document::Cartridge(cartridge) => {
+            for (number, voice) in cartridge.voices.iter().enumerate() {
+                println!("{:2} {}", number + 1, voice.name.value());
             }
+        },
+    }
+    Ok(())
+}
+
+/// Extracts the voices of a cartridge bulk dump into separate files
+/// in the given format, named by the template, like "rom1a-01.syx".
+/// By default the files go into the directory of the cartridge file.
+/// Existing files are only overwritten if `force` is true; otherwise
+/// nothing is written if any of the files already exists.
+pub fn run_extract(path: &Path, output_dir: &Option<PathBuf>, template: &str,
+        format: FileFormat, force: bool) -> Result<(), Error> {
+    let buffer = read_file(path)?;
+
+    let header = check_bulk_dump(&buffer)?;
+    println!("{}", header);
+
+    if let Format::Voice = header.format {
+        println!("Not extracting an individual voice.");
+        return Ok(());
+    }
+
+    let stem = path.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+    let directory = match output_dir {
+        Some(dir) => dir.clone(),
+        None => path.parent().map(PathBuf::from).unwrap_or_default(),
+    };
+
+    // Work out all the file names first, so that nothing is written
+    // if any of them is a problem.
+    let voices = extract_voices(&buffer, format)?;
+    let mut output_paths = Vec::<PathBuf>::new();
+    for voice in &voices {
+        let output_path = directory.join(voice_file_name(template, &stem, voice, format)?);
+        if output_paths.contains(&output_path) {
+            return Err(Error::io(&output_path, io::Error::new(io::ErrorKind::AlreadyExists,
+                "more than one voice would be written into this file, add {number} to the name template")));
+        }
+        if !force && output_path.exists() {
+            return Err(Error::io(&output_path, io::Error::new(io::ErrorKind::AlreadyExists,
+                "file already exists, use --force to overwrite")));
         }
+        output_paths.push(output_path);
+    }
+
+    fs::create_dir_all(&directory).map_err(|e| Error::io(&directory, e))?;
+    for (voice, output_path) in voices.iter().zip(&output_paths) {
+        write_file(output_path, &voice.data)?;
+        println!("{:2} {} -> {}", voice.number, voice.name, output_path.display());
     }
+
+    Ok(())
 }
 
-/// Dumps the contents of the file. It is assumed to be either a single voice,
-/// or a cartridge of 32 voices, based on the format byte at offset 3.
-/// Voice number is 1...32 for cartridges, ignored for single voices.
-pub fn run_dump(path: &PathBuf, number: &Option<u8>) {
-    let Some(buffer) = read_file(&path) else {
-        eprintln!("Unable to read from {}", path.display());
-        return;
+/// Packs single voice bulk dumps into a cartridge bulk dump, in order.
+/// The voice files come from the input files, which can also be directories
+/// of .syx files, and then from the list file if there is one.
+/// If there are less than 32 voices, the rest are initialized voices.
+pub fn run_pack(input_paths: &[PathBuf], list_path: &Option<PathBuf>, output_path: &Path) -> Result<(), Error> {
+    let mut files = expand_directories(input_paths)?;
+    if let Some(list_path) = list_path {
+        files.append(&mut read_list(list_path)?);
+    }
+
+    let mut voices = Vec::<Voice>::new();
+    for file in &files {
+        let document = load_syx(file)
+            .inspect_err(|_| eprintln!("Unable to pack {}", file.display()))?;
+        let Document::Voice(voice) = document else {
+            return Err(Error::Header(format!("{} is a cartridge, not a single voice", file.display())));
+        };
+        println!("{:2} {} <- {}", voices.len() + 1, voice.name.value(), file.display());
+        voices.push(*voice);
+    }
+
+    let count = voices.len();
+    let cartridge = pack_voices(voices)?;
+    if count < VOICE_COUNT {
+        println!("Filled voices {}...{} with initialized voices", count + 1, VOICE_COUNT);
+    }
+
+    save_syx(&Document::Cartridge(cartridge), output_path)?;
+    println!("Wrote a cartridge with {} voice(s) to {}", count, output_path.display());
+    Ok(())
+}
+
+// Reads a list of file paths, one per line. Empty lines and lines
+// starting with '#' are skipped. Relative paths are relative to
+// the directory of the list file.
+fn read_list(list_path: &Path) -> Result<Vec<PathBuf>, Error> {
+    let text = read_text(list_path)?;
+    let directory = list_path.parent().map(PathBuf::from).unwrap_or_default();
+    Ok(text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| directory.join(line))
+        .collect())
+}
+
+// Reads a cartridge bulk dump, with its MIDI channel.
+fn read_cartridge(path: &Path) -> Result<(Cartridge, MIDIChannel), Error> {
+    let buffer = read_file(path)?;
+    let header = check_bulk_dump(&buffer)?;
+    let Document::Cartridge(cartridge) = parse_bulk_dump(&buffer)? else {
+        return Err(Error::Header(format!("{} is a single voice, not a cartridge", path.display())));
     };
+    Ok((cartridge, header.channel))
+}
 
-    println!("File size = {} bytes", buffer.len());
+// Reads a cartridge, edits it, and writes it into the output file,
+// or back into the input file if there is no output file.
+// The cartridge keeps its MIDI channel.
+fn edit_cartridge<F>(input_path: &Path, output_path: &Option<PathBuf>, edit: F) -> Result<(), Error>
+        where F: FnOnce(&mut Cartridge) -> Result<(), Error> {
+    let (mut cartridge, channel) = read_cartridge(input_path)?;
+    edit(&mut cartridge)?;
+
+    let output_path = output_path.as_deref().unwrap_or(input_path);
+    write_file(output_path, &to_syx(&Document::Cartridge(cartridge), channel))?;
+    println!("Wrote the cartridge to {}", output_path.display());
+    Ok(())
+}
 
-    let Ok(Message::ManufacturerSpecific { manufacturer, payload }) 
-            = Message::from_bytes(&buffer) else {
-        eprintln!("Error in message");
-        return;
+/// Moves a voice of a cartridge to another slot, moving the voices
+/// in between by one slot.
+pub fn run_move(input_path: &Path, from: usize, to: usize, output_path: &Option<PathBuf>) -> Result<(), Error> {
+    edit_cartridge(input_path, output_path, |cartridge| {
+        move_voice(cartridge, from, to)?;
+        println!("Moved voice {} to {}", from, to);
+        Ok(())
+    })
+}
+
+/// Swaps two voices of a cartridge.
+pub fn run_swap(input_path: &Path, a: usize, b: usize, output_path: &Option<PathBuf>) -> Result<(), Error> {
+    edit_cartridge(input_path, output_path, |cartridge| {
+        swap_voices(cartridge, a, b)?;
+        println!("Swapped voices {} and {}", a, b);
+        Ok(())
+    })
+}
+
+/// Replaces a voice of a cartridge with the voice in a single voice file.
+pub fn run_replace(input_path: &Path, number: usize, voice_path: &Path, output_path: &Option<PathBuf>) -> Result<(), Error> {
+    let Document::Voice(voice) = load_syx(voice_path)? else {
+        return Err(Error::Header(format!("{} is a cartridge, not a single voice", voice_path.display())));
     };
 
-    let Ok(header) = Header::parse(&payload) else {
-        eprintln!("Error parsing header");
-        return;
+    edit_cartridge(input_path, output_path, |cartridge| {
+        println!("Replacing voice {} with '{}'", number, voice.name.value());
+        replace_voice(cartridge, number, *voice)
+    })
+}
+
+/// Copies the voices `first...last` of the source cartridge into the target
+/// cartridge, starting from the slot `to`. The result is written into
+/// the output file, or back into the target file if there is no output file.
+pub fn run_copy(source_path: &Path, first: usize, last: usize, target_path: &Path, to: usize,
+        output_path: &Option<PathBuf>) -> Result<(), Error> {
+    let (source, _) = read_cartridge(source_path)?;
+
+    edit_cartridge(target_path, output_path, |target| {
+        copy_voices(&source, first, last, target, to)?;
+        println!("Copied voices {}...{} of {} to {}", first, last, source_path.display(), to);
+        Ok(())
+    })
+}
+
+/// Sorts the voices of a cartridge by name or by algorithm.
+pub fn run_sort(input_path: &Path, key: SortKey, output_path: &Option<PathBuf>) -> Result<(), Error> {
+    edit_cartridge(input_path, output_path, |cartridge| {
+        sort_voices(cartridge, key);
+        for (index, voice) in cartridge.voices.iter().enumerate() {
+            println!("{:2} {}", index + 1, voice.name.value());
+        }
+        Ok(())
+    })
+}
+
+// Reads a TX802 performance memory bulk dump, and its MIDI channel.
+fn read_pmem(path: &Path) -> Result<(PerformanceMemory, MIDIChannel), Error> {
+    let buffer = read_file(path)?;
+    let memory = parse_pmem(&buffer)?;
+    Ok((memory, MIDIChannel::new((buffer[2] & 0x0f) as i32 + 1)))
+}
+
+// Writes a TX802 performance memory bulk dump.
+fn write_pmem(memory: &PerformanceMemory, channel: MIDIChannel, path: &Path) -> Result<(), Error> {
+    write_file(path, &make_pmem_message(memory, channel))?;
+    println!("Wrote the performance memory to {}", path.display());
+    Ok(())
+}
+
+// Reads a TX802 performance memory, edits one of its performances,
+// and writes it into the output file, or back into the input file
+// if there is no output file. Shows the edited performance.
+fn edit_performance<F>(input_path: &Path, number: usize, output_path: &Option<PathBuf>, edit: F) -> Result<(), Error>
+        where F: FnOnce(&mut Performance) -> Result<(), Error> {
+    let (mut memory, channel) = read_pmem(input_path)?;
+    let performance = performance_mut(&mut memory, number)?;
+    edit(performance)?;
+    print!("{}", performance);
+
+    write_pmem(&memory, channel, output_path.as_deref().unwrap_or(input_path))
+}
+
+/// Initializes a performance of a TX802 performance memory and names it.
+/// Without an input file, starts from a performance memory where all
+/// the performances are initialized. The result is written into the
+/// output file, or back into the input file if there is no output file.
+pub fn run_perf_new(input_path: &Option<PathBuf>, number: usize, name: &str, output_path: &Option<PathBuf>) -> Result<(), Error> {
+    let (mut memory, channel) = match input_path {
+        Some(path) => read_pmem(path)?,
+        None => (PerformanceMemory::new(), MIDIChannel::new(1)),
     };
+    init_performance(&mut memory, number, name)?;
+    println!("Initialized performance {} as '{}'", number, name);
 
-    println!("Manufacturer = {}", manufacturer);
-    println!("Payload = {} bytes (between F0 43 ... F7)", payload.len());
-    println!("Header = {} (length = {} bytes)", header, Header::DATA_SIZE);
+    let Some(output_path) = output_path.as_deref().or(input_path.as_deref()) else {
+        return Err(Error::Range(String::from("Need an input file or an output file")));
+    };
+    write_pmem(&memory, channel, output_path)
+}
 
-    let data_start = Header::DATA_SIZE;
-    let data_end = payload.len() - 1;
-    let data = &payload[data_start .. data_end];
-    println!("data length = {} [{}..{}]", data.len(), data_start, data_end);
+/// Shows the tone generator settings of a performance.
+pub fn run_perf_show(input_path: &Path, number: usize) -> Result<(), Error> {
+    let (memory, _) = read_pmem(input_path)?;
+    print!("{}", performance(&memory, number)?);
+    Ok(())
+}
 
-    match header.format {
-        Format::Voice => {
-            match Voice::parse(&data) {
-                Ok(voice) => {
-                    println!("{}", voice);
-                },
-                Err(e) => {
-                    eprintln!("{}", e);
-                }
+/// Assigns a voice like "I01" or "C32" to a tone generator of a performance.
+pub fn run_perf_assign(input_path: &Path, number: usize, tg: usize, voice: &str, output_path: &Option<PathBuf>) -> Result<(), Error> {
+    let voice = parse_voice_number(voice)?;
+    edit_performance(input_path, number, output_path, |performance| {
+        assign_voice(performance, tg, voice)
+    })
+}
 
+/// Sets a parameter of a tone generator of a performance to a raw value.
+pub fn run_perf_set(input_path: &Path, number: usize, tg: usize, parameter: &str, value: u8,
+        output_path: &Option<PathBuf>) -> Result<(), Error> {
+    edit_performance(input_path, number, output_path, |performance| {
+        set_parameter(performance, tg, parameter, value)
+    })
+}
+
+/// Sets the key range of a tone generator of a performance.
+/// The notes are MIDI note numbers or names like "C3".
+pub fn run_perf_limits(input_path: &Path, number: usize, tg: usize, low: &str, high: &str,
+        output_path: &Option<PathBuf>) -> Result<(), Error> {
+    let low = parse_note(low)?;
+    let high = parse_note(high)?;
+    edit_performance(input_path, number, output_path, |performance| {
+        set_note_limits(performance, tg, low, high)
+    })
+}
+
+/// Makes a performance into a key split of two voices at the split note.
+pub fn run_perf_split(input_path: &Path, number: usize, split: &str, lower: &str, upper: &str,
+        output_path: &Option<PathBuf>) -> Result<(), Error> {
+    let split = parse_note(split)?;
+    let lower = parse_voice_number(lower)?;
+    let upper = parse_voice_number(upper)?;
+    edit_performance(input_path, number, output_path, |performance| {
+        make_split(performance, split, lower, upper)
+    })
+}
+
+/// Makes a performance into a layer of one to eight voices.
+pub fn run_perf_layer(input_path: &Path, number: usize, voices: &[String], output_path: &Option<PathBuf>) -> Result<(), Error> {
+    let voices = voices.iter()
+        .map(|voice| parse_voice_number(voice))
+        .collect::<Result<Vec<_>, _>>()?;
+    edit_performance(input_path, number, output_path, |performance| {
+        make_layer(performance, &voices)
+    })
+}
+
+/// Writes a complete TX802 setup into one System Exclusive file:
+/// one or two cartridges for the internal voices, and the performance memory.
+/// The messages are on the MIDI channel of the performance memory.
+pub fn run_export_setup(pmem_path: &Path, cartridge_paths: &[PathBuf], output_path: &Path) -> Result<(), Error> {
+    let (memory, channel) = read_pmem(pmem_path)?;
+    let cartridges = cartridge_paths.iter()
+        .map(|path| read_cartridge(path).map(|(cartridge, _)| cartridge))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let data = setup_to_syx(&cartridges, &memory, channel)?;
+    write_file(output_path, &data)?;
+    println!("Wrote {} cartridge(s) and {} performances to {}",
+        cartridges.len(), memory.performances.len(), output_path.display());
+    Ok(())
+}
+
+/// Makes a micro-tuning table in equal temperament.
+pub fn run_tuning_new(kind: TuningKind, output_path: &Path) -> Result<(), Error> {
+    let table = TuningTable::equal_temperament(kind);
+    save_tuning(&table, output_path)?;
+    println!("Wrote the {} tuning table to {}", kind, output_path.display());
+    Ok(())
+}
+
+/// Makes a micro-tuning table from a Scala scale file, and optionally
+/// a keyboard mapping file, and shows it.
+pub fn run_tuning_scala(scl_path: &Path, kbm_path: &Option<PathBuf>, kind: TuningKind, output_path: &Path) -> Result<(), Error> {
+    let (scale, mapping) = load_scala(scl_path, kbm_path.as_deref())?;
+    println!("{} ({} notes)", scale.description, scale.cents.len());
+
+    let table = tuning_from_scala(&scale, &mapping, kind)?;
+    print!("{}", table);
+    save_tuning(&table, output_path)?;
+    println!("Wrote the {} tuning table to {}", kind, output_path.display());
+    Ok(())
+}
+
+/// Shows a micro-tuning table as the pitch of each key in cents.
+pub fn run_tuning_show(path: &Path) -> Result<(), Error> {
+    print!("{}", load_tuning(path)?);
+    Ok(())
+}
+
+/// Default delay after each message sent over MIDI, in milliseconds.
+pub const DEFAULT_SEND_DELAY: u64 = 100;
+
+// Gets the messages to send from the file. If a voice number is given,
+// the file must contain a cartridge, and the result is a single voice
+// message with the voice, for the voice edit buffer of the synth.
+fn messages_to_send(path: &Path, number: &Option<u8>) -> Result<Vec<Vec<u8>>, Error> {
+    let buffer = read_file(path)?;
+
+    let messages: Vec<Vec<u8>> = split_messages(buffer)
+        .into_iter()
+        .filter(|m| m.first() == Some(&INITIATOR))
+        .collect();
+
+    if messages.is_empty() {
+        return Err(no_messages(path));
+    }
+
+    let Some(n) = number else {
+        return Ok(messages);
+    };
+
+    let n = check_voice_number(*n)?;
+    let header = check_bulk_dump(&messages[0])?;
+    let Document::Cartridge(cartridge) = parse_bulk_dump(&messages[0])? else {
+        return Err(Error::Header(format!("Voice number given, but {} is not a cartridge", path.display())));
+    };
+
+    let voice = &cartridge.voices[n - 1];
+    println!("Sending voice {} '{}'", n, voice.name.value());
+    Ok(vec![make_voice_message(voice, header.channel).to_bytes()])
+}
+
+/// Sends the System Exclusive messages in the file to a MIDI output port,
+/// identified by index or by part of its name. If a voice number is given,
+/// sends just that voice from a cartridge. Waits for `delay` milliseconds
+/// after each message.
+pub fn run_send(path: &Path, port: &str, number: &Option<u8>, delay: u64) -> Result<(), Error> {
+    let messages = messages_to_send(path, number)?;
+
+    send_messages(port, &messages, Duration::from_millis(delay))
+        .map_err(|e| Error::Midi(format!("Error sending to MIDI port: {}", e)))?;
+
+    println!("Sent {} message(s) to port '{}'", messages.len(), port);
+    Ok(())
+}
+
+/// The kind of bulk dump to request from the synth.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum DumpKind {
+    /// The voice in the edit buffer
+    Voice,
+    /// All 32 voices in the internal memory
+    Cartridge,
+}
+
+// Checks that a MIDI channel is 1...16.
+fn midi_channel(channel: u8) -> Result<MIDIChannel, Error> {
+    if (MIDIChannel::FIRST..=MIDIChannel::LAST).contains(&(channel as i32)) {
+        Ok(MIDIChannel::new(channel as i32))
+    } else {
+        Err(Error::Range(format!("MIDI channel must be {}...{}, got {}",
+            MIDIChannel::FIRST, MIDIChannel::LAST, channel)))
+    }
+}
+
+/// Default time to wait for a bulk dump from the synth, in seconds.
+pub const DEFAULT_RECEIVE_TIMEOUT: u64 = 30;
+
+/// Receives a single voice or cartridge bulk dump from a MIDI input port
+/// and writes it to a file. If a dump kind is given, sends a dump request
+/// to the synth first, to the request port or if not given, to the output port
+/// with the same name as the input port.
+/// Nothing is written unless a valid bulk dump arrives within the timeout.
+pub fn run_receive(output_path: &Path, port: &str, request: Option<DumpKind>,
+        request_port: &Option<String>, channel: u8, timeout: u64) -> Result<(), Error> {
+    let channel = midi_channel(channel)?;
+
+    let request = request.map(|kind| {
+        let format = match kind {
+            DumpKind::Voice => Format::Voice,
+            DumpKind::Cartridge => Format::Cartridge,
+        };
+        Request {
+            port: request_port.as_deref().unwrap_or(port),
+            message: make_dump_request(format, channel),
+        }
+    });
+
+    // Accept only DX7 bulk dumps, ignore anything else the synth sends.
+    let accept = |message: &[u8]| {
+        match check_bulk_dump(message) {
+            Ok(_) => true,
+            Err(e) => {
+                eprintln!("Invalid bulk dump: {}", e);
+                false
             }
+        }
+    };
+
+    println!("Waiting for a bulk dump from port '{}'...", port);
+    let message = receive_message(port, request, Duration::from_secs(timeout), accept)
+        .map_err(|e| Error::Midi(format!("Error receiving from MIDI port: {}", e)))?;
+
+    println!("Received {}", check_bulk_dump(&message)?);
+
+    write_file(output_path, &message)?;
+    println!("Wrote {} bytes to {}", message.len(), output_path.display());
+    Ok(())
+}
+
+use sevenator::dx7::parameter::{find_parameter, make_parameter_change, make_name_changes};
+use sevenator::midi::OutputConnection;
+
+// Makes the parameter change messages that set a voice parameter in the
+// edit buffer, and returns them with the name of the parameter.
+// The parameter "name" sets the whole voice name, the others take a raw value.
+fn parameter_changes(parameter: &str, value: &str, channel: u8) -> Result<(String, Vec<Vec<u8>>), Error> {
+    let channel = midi_channel(channel)?;
+    if parameter.eq_ignore_ascii_case("name") {
+        return Ok((String::from("name"), make_name_changes(value, channel)?));
+    }
+
+    let parameter = find_parameter(parameter)?;
+    let value = value.parse::<u8>()
+        .map_err(|_| Error::Range(format!("Invalid value '{}' for {}", value, parameter.name)))?;
+    Ok((parameter.name.clone(), vec![make_parameter_change(&parameter, value, channel)?]))
+}
+
+/// Sets a parameter of the voice in the edit buffer of the synth with
+/// parameter change messages, sent to a MIDI output port identified by
+/// index or by part of its name. The parameter is a field like
+/// "op1.output_level", a name like "OP1 output level", or a number.
+pub fn run_set_parameter(port: &str, parameter: &str, value: &str, channel: u8) -> Result<(), Error> {
+    let (name, messages) = parameter_changes(parameter, value, channel)?;
+
+    send_messages(port, &messages, Duration::ZERO)
+        .map_err(|e| Error::Midi(format!("Error sending to MIDI port: {}", e)))?;
+
+    println!("Set {} to {} on port '{}'", name, value, port);
+    Ok(())
+}
+
+/// Dumps the contents of the file. It is assumed to be either a single voice,
+/// or a cartridge of 32 voices, based on the format byte at offset 3.
+/// Voice number is 1...32 for cartridges, ignored for single voices.
+pub fn run_dump(path: &Path, number: &Option<u8>) -> Result<(), Error> {
+    let buffer = read_file(path)?;
+
+    println!("File size = {} bytes", buffer.len());
+
+    let header = check_bulk_dump(&buffer)?;
+    println!("Header = {} (length = {} bytes)", header, Header::DATA_SIZE);
+
+    // The data is between F0 43 (header) and (checksum) F7.
+    let data_start = 2 + Header::DATA_SIZE;
+    let data_end = buffer.len() - 2;
+    println!("data length = {} [{}..{}]", data_end - data_start, data_start, data_end);
+
+    match parse_bulk_dump(&buffer)? {
+        Document::Voice(voice) => {
+            println!("{}", voice);
         },
-        Format::Cartridge => {
-            match Cartridge::parse(&data) {
-                Ok(cartridge) => {
-                    if let Some(n) = number {
-                        println!("{}", cartridge.voices[(*n as usize) - 1]);
-                    }
-                    else {
-                        for voice in cartridge.voices.iter() {
-                            println!("{}", voice);
-                        }
-                    }
-                },
-                Err(e) => {
-                    eprintln!("{}", e);
+        Document::Cartridge(cartridge) => {
+            if let Some(n) = number {
+                let n = check_voice_number(*n)?;
+                println!("{}", cartridge.voices[n - 1]);
+            }
+            else {
+                for voice in cartridge.voices.iter() {
+                    println!("{}", voice);
                 }
             }
         }
     }
+
+    Ok(())
 }
 
-use xml_builder::{XMLBuilder, XMLElement, XMLVersion};
-use sevenate::dx7::lfo::Lfo;
-use sevenate::dx7::envelope::Envelope;
-use sevenate::dx7::operator::{KeyboardLevelScaling, Operator, ScalingCurve};
+use clap::{Args, ValueEnum};
+use sevenate::dx7::Level;
+use rand::Rng;
+use sevenator::dx7::randomizer::{
+    RandomizationParameters,
+    TimbreParameters,
+    EnvelopeParameters,
+    MovementParameters,
+};
+use sevenator::dx7::{
+    make_voice_message,
+    make_cartridge_message,
+    make_dump_request,
+    check_bulk_dump,
+};
 
-trait ToXml {
-    fn to_xml(&self) -> XMLElement;
-    fn to_xml_named(&self, name: &str) -> XMLElement;
+/// The kind of bulk dump to generate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum GenerateKind {
+    /// A single voice
+    Voice,
+    /// A cartridge of 32 voices
+    Cartridge,
 }
 
-impl ToXml for Cartridge {
-    fn to_xml(&self) -> XMLElement {
-        self.to_xml_named("cartridge")
-    }
+/// How to fill in the generated voices.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum GenerateMode {
+    /// Initialized voices with the DX7 defaults
+    Init,
+    /// Completely random voices
+    Random,
+    /// Random voices shaped by the randomizer parameters
+    Randomizer,
+}
+
+/// The parameters of the Synthmata-style randomizer, all 0...99.
+#[derive(Debug, Clone, Copy, Args)]
+pub struct RandomizerArgs {
+    /// How far the operator frequencies stray from harmonic ratios
+    #[arg(long, default_value_t = 50, value_parser = clap::value_parser!(u8).range(0..=99))]
+    pub atonality: u8,
+
+    /// How complex the algorithm and feedback are
+    #[arg(long, default_value_t = 50, value_parser = clap::value_parser!(u8).range(0..=99))]
+    pub complexity: u8,
+
+    /// How loud and high the modulators are
+    #[arg(long, default_value_t = 50, value_parser = clap::value_parser!(u8).range(0..=99))]
+    pub brightness: u8,
+
+    /// How fast the attack is
+    #[arg(long, default_value_t = 50, value_parser = clap::value_parser!(u8).range(0..=99))]
+    pub hardness: u8,
+
+    /// How percussive the amplitude is
+    #[arg(long, default_value_t = 50, value_parser = clap::value_parser!(u8).range(0..=99))]
+    pub hitness: u8,
+
+    /// How much the modulation decays after the attack
+    #[arg(long, default_value_t = 50, value_parser = clap::value_parser!(u8).range(0..=99))]
+    pub twang: u8,
 
-    fn to_xml_named(&self, name: &str) -> XMLElement {
-        let mut e = XMLElement::new(name);
+    /// How long the decay and release are
+    #[arg(long, default_value_t = 50, value_parser = clap::value_parser!(u8).range(0..=99))]
+    pub longness: u8,
+
+    /// Amount of LFO pitch modulation
+    #[arg(long, default_value_t = 50, value_parser = clap::value_parser!(u8).range(0..=99))]
+    pub wobble: u8,
+
+    /// Amount of LFO amplitude modulation
+    #[arg(long, default_value_t = 50, value_parser = clap::value_parser!(u8).range(0..=99))]
+    pub wubble: u8,
+
+    /// Amount of key velocity sensitivity
+    #[arg(long, default_value_t = 50, value_parser = clap::value_parser!(u8).range(0..=99))]
+    pub velocity: u8,
+}
+
+impl From<RandomizerArgs> for RandomizationParameters {
+    fn from(args: RandomizerArgs) -> Self {
+        RandomizationParameters::new(
+            TimbreParameters {
+                atonality: Level::from(args.atonality),
+                complexity: Level::from(args.complexity),
+                brightness: Level::from(args.brightness),
+            },
+            EnvelopeParameters {
+                hardness: Level::from(args.hardness),
+                hitness: Level::from(args.hitness),
+                twang: Level::from(args.twang),
+                longness: Level::from(args.longness),
+            },
+            MovementParameters {
+                wobble: Level::from(args.wobble),
+                wubble: Level::from(args.wubble),
+                velocity: Level::from(args.velocity),
+            })
+    }
+}
 
-        let mut voices_element = XMLElement::new("voices");
+/// Generates a single voice or a cartridge and writes it out
+/// as a System Exclusive bulk dump.
+/// The randomizer parameters are only used with `GenerateMode::Randomizer`.
+/// For the random modes, the seed is recorded in a sidecar file next to
+/// the output file (with the extension `.seed`), so that the same
+/// voices can be generated again by passing the seed back in.
+pub fn run_generate(kind: GenerateKind, mode: GenerateMode, params: RandomizationParameters,
+        seed: Option<u64>, output_path: &Path) -> Result<(), Error> {
+    // Use the seed if one was given, otherwise make up a new one.
+    let seed = seed.unwrap_or_else(|| rand::rng().random());
+
+    let generator = match mode {
+        GenerateMode::Init => Generator::Init,
+        GenerateMode::Random => Generator::Random,
+        GenerateMode::Randomizer => Generator::Randomizer(params),
+    };
 
-        for voice in &self.voices {
-            voices_element.add_child(voice.to_xml()).unwrap();
+    let document = match kind {
+        GenerateKind::Voice => {
+            let voice = generate_voice(generator, seed);
+            println!("Generated voice '{}'", voice.name.value());
+            Document::Voice(Box::new(voice))
+        },
+        GenerateKind::Cartridge => {
+            let cartridge = generate_cartridge(generator, seed);
+            println!("Generated cartridge with {} voices", cartridge.voices.len());
+            Document::Cartridge(cartridge)
         }
+    };
+
+    save_syx(&document, output_path)?;
 
-        let _ = e.add_child(voices_element);
-        e
+    if mode != GenerateMode::Init {
+        println!("Seed = {}", seed);
+
+        let seed_path = output_path.with_extension("seed");
+        let seed_info = make_seed_info(kind, mode, &params, seed);
+        write_file(&seed_path, seed_info.as_bytes())?;
     }
+
+    Ok(())
 }
 
-impl ToXml for Voice {
-    fn to_xml(&self) -> XMLElement {
-        self.to_xml_named("voice")
+// Makes the contents of the seed sidecar file. It has everything
+// needed to run the same `generate` command again.
+fn make_seed_info(kind: GenerateKind, mode: GenerateMode, params: &RandomizationParameters, seed: u64) -> String {
+    let mut lines = vec![
+        format!("seed = {}", seed),
+        format!("kind = \"{}\"", kind.to_possible_value().unwrap().get_name()),
+        format!("mode = \"{}\"", mode.to_possible_value().unwrap().get_name()),
+    ];
+
+    if mode == GenerateMode::Randomizer {
+        lines.push(format!("atonality = {}", params.timbre.atonality));
+        lines.push(format!("complexity = {}", params.timbre.complexity));
+        lines.push(format!("brightness = {}", params.timbre.brightness));
+        lines.push(format!("hardness = {}", params.envelope.hardness));
+        lines.push(format!("hitness = {}", params.envelope.hitness));
+        lines.push(format!("twang = {}", params.envelope.twang));
+        lines.push(format!("longness = {}", params.envelope.longness));
+        lines.push(format!("wobble = {}", params.movement.wobble));
+        lines.push(format!("wubble = {}", params.movement.wubble));
+        lines.push(format!("velocity = {}", params.movement.velocity));
     }
 
-    fn to_xml_named(&self, name: &str) -> XMLElement {
-        let mut e = XMLElement::new(name);
+    let mut info = lines.join("\n");
+    info.push('\n');
+    info
+}
 
-        e.add_attribute("name", &self.name.value());
-        e.add_attribute("algorithm", &self.alg.value().to_string());
-        e.add_attribute("transpose", &self.transpose.value().to_string());
-        e.add_attribute("feedback", &self.feedback.value().to_string());
-        e.add_attribute("oscillatorSync", &self.osc_sync.to_string());
-        e.add_attribute("pitchModulationSensitivity", &self.pitch_mod_sens.value().to_string());
+use sevenator::{
+    Document,
+    FileFormat,
+    Generator,
+    read_xml,
+    save_syx,
+    save_xml,
+    extract_voices,
+    voice_file_name,
+    load_syx,
+    pack_voices,
+    to_syx,
+    generate_voice,
+    generate_cartridge,
+    pmem_to_xml,
+    save_pmem,
+    save_supplemented,
+    supplemented_xml,
+    load_scala,
+    tuning_from_scala,
+    load_tuning,
+    save_tuning,
+    setup_to_syx,
+};
+use sevenator::tx802::{
+    Performance,
+    PerformanceMemory,
+    INIT_NAME,
+    is_pmem,
+    parse_pmem,
+    make_pmem_message,
+    parse_performances,
+};
+use sevenator::tx802::edit::{
+    performance,
+    performance_mut,
+    parse_voice_number,
+    parse_note,
+    init_performance,
+    assign_voice,
+    set_parameter,
+    set_note_limits,
+    make_split,
+    make_layer,
+};
+use sevenator::tuning::{TuningKind, TuningTable, tuning_kind, parse_tuning};
+use sevenator::dx7::supplement::{
+    supplement_format,
+    parse_supplement_dump,
+    parse_supplemented,
+    parse_supplemented_document,
+};
 
-        e.add_child(self.peg.to_xml_named("peg")).unwrap();
-        e.add_child(self.lfo.to_xml()).unwrap();
+// Reads a single voice or cartridge bulk dump from a System Exclusive file.
+fn read_bulk_dump(input_path: &Path) -> Result<Document, Error> {
+    let buffer = read_file(input_path)?;
 
-        let mut op_e = XMLElement::new("operators");
-        for op in self.operators {
-            op_e.add_child(op.to_xml()).unwrap();
-        }
-        let _ = e.add_child(op_e);
+    let header = check_bulk_dump(&buffer)?;
+    println!("Header = {}", header);
 
-        e
-    }
+    parse_bulk_dump(&buffer)
+}
+
+// Writes a voice or a cartridge as a single bulk dump.
+fn write_bulk_dump(document: &Document, output_path: &Path) -> Result<(), Error> {
+    println!("{}", describe_document(document));
+    save_syx(document, output_path)
 }
 
-impl ToXml for Lfo {
-    fn to_xml(&self) -> XMLElement {
-        self.to_xml_named("lfo")
+/// Makes an XML document from a single voice or cartridge bulk dump
+/// with or without the DX7II supplements of its voices,
+/// or from a TX802 performance memory bulk dump. The root element is `voice`,
+/// `cartridge` or `performances` respectively.
+pub fn run_make_xml(input_path: &Path, output_path: &Path) -> Result<(), Error> {
+    let buffer = read_file(input_path)?;
+    if is_pmem(&buffer) {
+        let memory = parse_pmem(&buffer)?;
+        return write_file(output_path, pmem_to_xml(&memory).as_bytes());
     }
 
-    fn to_xml_named(&self, name: &str) -> XMLElement {
-        let mut e = XMLElement::new(name);
-    
-        e.add_attribute("speed", &self.speed.value().to_string());
-        e.add_attribute("delay", &self.delay.value().to_string());
-        e.add_attribute("pmd", &self.pmd.value().to_string());
-        e.add_attribute("amd", &self.amd.value().to_string());
-        e.add_attribute("sync", &self.sync.to_string());
-        e.add_attribute("wave", &self.waveform.to_string());
-        //e.add_attribute("pms", &lfo.pms.value().to_string());
-    
-        e
-    }    
+    if find_messages(&buffer).iter().any(|(_, message)| supplement_format(message).is_some()) {
+        let document = parse_supplemented(&buffer)?;
+        println!("{}, with DX7II supplements", describe_document(&document.document));
+        return write_file(output_path, supplemented_xml(&document).as_bytes());
+    }
+
+    let document = read_bulk_dump(input_path)?;
+    save_xml(&document, output_path)
 }
 
-impl ToXml for Envelope {
-    fn to_xml(&self) -> XMLElement {
-        unimplemented!();
+/// Validates an XML document against the rules of the dx7-xml schema.
+/// If it is not valid, the error has every violation.
+pub fn run_validate_xml(input_path: &Path) -> Result<(), Error> {
+    let root = read_xml(input_path)?;
+    println!("{} is a valid {} document", input_path.display(), root.name);
+    Ok(())
+}
+
+/// Makes a System Exclusive file from an XML document. A document with
+/// a `voice` root element makes a single voice bulk dump, and one with
+/// a `cartridge` root element makes a cartridge bulk dump, and one with
+/// a `performances` root element makes a TX802 performance memory bulk dump.
+/// If the voices have DX7II supplements, their bulk dump comes first.
+/// The document is validated first, and if there are any errors
+/// in it, nothing is written.
+pub fn run_make_syx(input_path: &Path, output_path: &Path) -> Result<(), Error> {
+    let root = read_xml(input_path)?;
+    let xml_error = |e| Error::Xml { path: input_path.to_path_buf(), errors: vec![e] };
+
+    if root.name == "performances" {
+        let memory = parse_performances(&root).map_err(xml_error)?;
+        println!("TX802 performance memory, {} performances", memory.performances.len());
+        return save_pmem(&memory, output_path);
     }
 
-    fn to_xml_named(&self, name: &str) -> XMLElement {
-        let mut e = XMLElement::new(name);
+    let document = parse_supplemented_document(&root).map_err(xml_error)?;
+    if document.supplements.is_empty() {
+        return write_bulk_dump(&document.document, output_path);
+    }
 
-        let mut rates_element = XMLElement::new("rates");
-        let mut rates_string = String::new();
-        let mut count = 0;
-        for r in self.rates.iter() {
-            rates_string.push_str(&r.to_string());
-            count += 1;
-            if count < 4 {
-                rates_string.push_str(" ");
+    println!("{}, with DX7II supplements", describe_document(&document.document));
+    save_supplemented(&document, output_path)
+}
+
+use sevenator::patch::{to_json, from_json, to_toml, from_toml};
+
+// Reads a text document for conversion into a System Exclusive file.
+fn read_text(input_path: &Path) -> Result<String, Error> {
+    fs::read_to_string(input_path).map_err(|e| Error::io(input_path, e))
+}
+
+// Makes the error for a JSON or TOML document.
+fn document_error(input_path: &Path, message: String) -> Error {
+    Error::Document { path: input_path.to_path_buf(), message }
+}
+
+/// Makes a JSON document from a single voice or cartridge bulk dump.
+pub fn run_make_json(input_path: &Path, output_path: &Path) -> Result<(), Error> {
+    let document = read_bulk_dump(input_path)?;
+    write_file(output_path, to_json(&document).as_bytes())
+}
+
+/// Makes a System Exclusive file from a JSON document.
+/// If there are any errors in the document, nothing is written.
+pub fn run_from_json(input_path: &Path, output_path: &Path) -> Result<(), Error> {
+    let text = read_text(input_path)?;
+    let document = from_json(&text).map_err(|e| document_error(input_path, e))?;
+    write_bulk_dump(&document, output_path)
+}
+
+/// Makes a TOML document from a single voice or cartridge bulk dump.
+pub fn run_make_toml(input_path: &Path, output_path: &Path) -> Result<(), Error> {
+    let document = read_bulk_dump(input_path)?;
+    write_file(output_path, to_toml(&document).as_bytes())
+}
+
+/// Makes a System Exclusive file from a TOML document.
+/// If there are any errors in the document, nothing is written.
+pub fn run_from_toml(input_path: &Path, output_path: &Path) -> Result<(), Error> {
+    let text = read_text(input_path)?;
+    let document = from_toml(&text).map_err(|e| document_error(input_path, e))?;
+    write_bulk_dump(&document, output_path)
+}
+
+/// Splits a file with many single voice or cartridge bulk dumps into files
+/// with one dump each, named after the input file and the number of the
+/// message, like "library-01.syx". Micro-tuning, supplement and performance
+/// memory dumps are written as they are. Invalid messages are reported and skipped.
+pub fn run_split(input_path: &Path, output_dir: &Option<PathBuf>) -> Result<(), Error> {
+    let buffer = read_file(input_path)?;
+
+    let messages = find_messages(&buffer);
+    if messages.is_empty() {
+        return Err(no_messages(input_path));
+    }
+
+    let stem = input_path.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+    let directory = match output_dir {
+        Some(dir) => dir.clone(),
+        None => input_path.parent().map(PathBuf::from).unwrap_or_default(),
+    };
+    fs::create_dir_all(&directory).map_err(|e| Error::io(&directory, e))?;
+
+    let mut written = 0;
+    for (index, (offset, message)) in messages.iter().enumerate() {
+        let number = index + 1;
+        let description = check_other_dump(message)
+            .unwrap_or_else(|| parse_bulk_dump(message).map(|document| describe_document(&document)));
+        match description {
+            Ok(description) => {
+                let output_path = directory.join(format!("{}-{:02}.syx", stem, number));
+                write_file(&output_path, message)?;
+                println!("Message {}: {} -> {}", number, description, output_path.display());
+                written += 1;
+            },
+            Err(e) => {
+                eprintln!("Skipping message {} at offset {}: {}", number, offset, e);
             }
         }
+    }
+
+    println!("Wrote {} of {} message(s)", written, messages.len());
+    Ok(())
+}
 
-        rates_element.add_text(rates_string).unwrap();
-        let _ = e.add_child(rates_element);
+// Expands directories in the paths into the .syx files in them.
+fn expand_directories(paths: &[PathBuf]) -> Result<Vec<PathBuf>, Error> {
+    let mut files = Vec::<PathBuf>::new();
+    for path in paths {
+        if path.is_dir() {
+            let mut found = syx_files_in(path).map_err(|e| Error::io(path, e))?;
+            files.append(&mut found);
+        } else {
+            files.push(path.clone());
+        }
+    }
+    Ok(files)
+}
 
-        let mut levels_element = XMLElement::new("levels");
-        let mut levels_string = String::new();
-        let mut count = 0;
-        for level in self.levels.iter() {
-            levels_string.push_str(&level.to_string());
-            count += 1;
-            if count < 4 {
-                levels_string.push_str(" ");
+/// Joins the single voice and cartridge bulk dumps in the input files
+/// into one file, in order. Micro-tuning, supplement and performance memory
+/// dumps are copied as they are. A directory stands for all the .syx files
+/// in it, sorted by name. Invalid messages are reported and left out.
+pub fn run_join(input_paths: &[PathBuf], output_path: &Path) -> Result<(), Error> {
+    let files = expand_directories(input_paths)?;
+
+    let mut output = Vec::<u8>::new();
+    let mut count = 0;
+    for file in &files {
+        let buffer = read_file(file)?;
+
+        for (index, (offset, message)) in find_messages(&buffer).iter().enumerate() {
+            let description = check_other_dump(message)
+                .unwrap_or_else(|| parse_bulk_dump(message).map(|document| describe_document(&document)));
+            match description {
+                Ok(description) => {
+                    count += 1;
+                    println!("{:3} {} message {}: {}", count, file.display(), index + 1, description);
+                    output.extend_from_slice(message);
+                },
+                Err(e) => {
+                    eprintln!("Skipping {} message {} at offset {}: {}", file.display(), index + 1, offset, e);
+                }
             }
         }
-        levels_element.add_text(levels_string).unwrap();
-        let _ = e.add_child(levels_element);
+    }
 
-        e
+    if count == 0 {
+        return Err(Error::SysEx(String::from("No valid bulk dumps found, nothing written")));
     }
+
+    write_file(output_path, &output)?;
+    println!("Wrote {} message(s), {} bytes to {}", count, output.len(), output_path.display());
+    Ok(())
 }
 
-impl ToXml for Operator {
-    fn to_xml(&self) -> XMLElement {
-        self.to_xml_named("operator")
+// Gets the paths of the System Exclusive files in a directory, sorted by name.
+fn syx_files_in(directory: &PathBuf) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::<PathBuf>::new();
+    for entry in fs::read_dir(directory)? {
+        let path = entry?.path();
+        let is_syx = path.extension()
+            .map(|e| e.eq_ignore_ascii_case("syx"))
+            .unwrap_or(false);
+        if path.is_file() && is_syx {
+            files.push(path);
+        }
     }
+    files.sort();
+    Ok(files)
+}
 
-    fn to_xml_named(&self, name: &str) -> XMLElement {
-        let mut e = XMLElement::new(name);
-
-        e.add_attribute("level", &self.output_level.value().to_string());
-        e.add_attribute("mode", &self.mode.to_string());
-        e.add_attribute("coarse", &self.coarse.value().to_string());
-        e.add_attribute("fine", &self.fine.value().to_string());
-        e.add_attribute("detune", &self.detune.value().to_string());
-        e.add_attribute("amplitudeModulationSensitivity", &self.amp_mod_sens.value().to_string());
-        e.add_attribute("keyVelocitySensitivity", &self.key_vel_sens.value().to_string());
-        e.add_attribute("keyboardRateScaling", &self.kbd_rate_scaling.value().to_string());
+// Describes the contents of a bulk dump in a few words.
+fn describe_document(document: &Document) -> String {
+    match document {
+        Document::Voice(voice) => format!("voice '{}'", voice.name.value()),
+        Document::Cartridge(cartridge) => format!("cartridge, first voice '{}'",
+            cartridge.voices.first().map(|v| v.name.value()).unwrap_or_default()),
+    }
+}
 
-        e.add_child(self.eg.to_xml_named("eg")).unwrap();
-        e.add_child(self.kbd_level_scaling.to_xml()).unwrap();
+use sevenator::dx7::repair::{salvage, split_parts};
+use sevenator::dx7::diff::diff_documents;
+
+/// Compares two single voice or cartridge bulk dumps parameter by parameter,
+/// and prints the differences, as text or as JSON.
+/// Fails if the files are different.
+pub fn run_diff(first_path: &Path, second_path: &Path, json: bool) -> Result<(), Error> {
+    let first = load_syx(first_path)?;
+    let second = load_syx(second_path)?;
+
+    let differences = diff_documents(&first, &second)?;
+    if json {
+        let text = serde_json::to_string_pretty(&differences)
+            .expect("differences should serialize into JSON");
+        println!("{}", text);
+    } else {
+        for difference in &differences {
+            println!("{}", difference);
+        }
+    }
 
-        e
+    if differences.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::Different { count: differences.len() })
     }
 }
 
-impl ToXml for KeyboardLevelScaling {
-    fn to_xml(&self) -> XMLElement {
-        self.to_xml_named("keyboardLevelScaling")
+// Checks a message that is not a DX7 voice or cartridge bulk dump but
+// another bulk dump that Sevenator knows: a micro-tuning table, DX7II
+// supplements or a TX802 performance memory. Returns none for other
+// messages, and otherwise the description of the dump or its error.
+fn check_other_dump(message: &[u8]) -> Option<Result<String, Error>> {
+    if tuning_kind(message).is_some() {
+        return Some(parse_tuning(message).map(|table| format!("{} micro-tuning table", table.kind)));
     }
 
-    fn to_xml_named(&self, name: &str) -> XMLElement {
-        let mut elem = XMLElement::new(name);
+    if supplement_format(message).is_some() {
+        return Some(parse_supplement_dump(message)
+            .map(|supplements| format!("DX7II supplements for {} voice(s)", supplements.len())));
+    }
 
-        elem.add_attribute("breakpoint", &self.breakpoint.value().to_string());
+    if is_pmem(message) {
+        return Some(parse_pmem(message)
+            .map(|memory| format!("TX802 performance memory, {} performances", memory.performances.len())));
+    }
 
-        let mut depth_element = XMLElement::new("depth");
-        depth_element.add_attribute("left", &self.left.depth.value().to_string());
-        depth_element.add_attribute("right", &self.right.depth.value().to_string());
-        let _ = elem.add_child(depth_element);
+    None
+}
 
-        let mut curve_element = XMLElement::new("curve");
-        curve_element.add_attribute("left", &self.left.curve.to_string());
-        curve_element.add_attribute("right", &self.right.curve.to_string());
-        let _ = elem.add_child(curve_element);
+/// Checks a file with one or more bulk dumps for damage, explains what is
+/// wrong with each of them, and salvages the voices. Raw voice or cartridge
+/// data without a header is also accepted. If an output file is given,
+/// writes the salvaged bulk dumps into it with correct headers and checksums.
+/// Valid micro-tuning, supplement and performance memory dumps are copied
+/// as they are. If any message can be neither salvaged nor copied, nothing
+/// is written, since it would be lost.
+pub fn run_repair(input_path: &Path, output_path: &Option<PathBuf>) -> Result<(), Error> {
+    let buffer = read_file(input_path)?;
+
+    let mut output = Vec::<u8>::new();
+    let mut problem_count = 0;
+    let mut lost = Vec::<usize>::new();
+    for (index, part) in split_parts(&buffer).iter().enumerate() {
+        let number = index + 1;
+        if let Some(result) = check_other_dump(part) {
+            match result {
+                Ok(description) => {
+                    println!("Message {}: {}", number, description);
+                    println!("    no problems found");
+                    output.extend_from_slice(part);
+                },
+                Err(e) => {
+                    eprintln!("Message {}: unable to repair: {}", number, e);
+                    problem_count += 1;
+                    lost.push(number);
+                }
+            }
+            continue;
+        }
+
+        match salvage(part) {
+            Ok(salvaged) => {
+                println!("Message {}: {}", number, describe_document(&salvaged.document));
+                if salvaged.problems.is_empty() {
+                    println!("    no problems found");
+                }
+                for problem in &salvaged.problems {
+                    println!("    {}", problem);
+                }
+                problem_count += salvaged.problems.len();
 
-        elem
+                let message = match &salvaged.document {
+                    Document::Voice(voice) => make_voice_message(voice, salvaged.channel),
+                    Document::Cartridge(cartridge) => make_cartridge_message(cartridge, salvaged.channel),
+                };
+                output.extend(message.to_bytes());
+            },
+            Err(e) => {
+                eprintln!("Message {}: unable to salvage: {}", number, e);
+                problem_count += 1;
+                lost.push(number);
+            }
+        }
     }
-}
 
-pub fn run_make_xml(input_path: &PathBuf, output_path: &PathBuf) {
-    let Some(buffer) = read_file(&input_path) else {
-        eprintln!("Unable to read from {}", input_path.display());
-        return;
-    };
+    println!("Found {} problem(s) in {}", problem_count, input_path.display());
 
-    let Ok(Message::ManufacturerSpecific { manufacturer: _, payload }) 
-            = Message::from_bytes(&buffer) else {
-        eprintln!("Error in message");
-        return;
+    let Some(output_path) = output_path else {
+        return Ok(());
     };
 
-    let Ok(header) = Header::parse(&payload) else {
-        eprintln!("Error parsing header");
-        return;
-    };
+    if !lost.is_empty() {
+        let numbers: Vec<String> = lost.iter().map(|number| number.to_string()).collect();
+        return Err(Error::SysEx(format!("Message(s) {} cannot be kept, not writing {}",
+            numbers.join(", "), output_path.display())));
+    }
 
-    println!("Header = {}", header);
+    if output.is_empty() {
+        return Err(Error::SysEx(format!("Nothing was salvaged, not writing {}", output_path.display())));
+    }
 
-    let data = &payload[Header::DATA_SIZE .. payload.len() - 1];
-    //dbg_hex!(data);
+    write_file(output_path, &output)?;
+    println!("Wrote the repaired data to {}", output_path.display());
+    Ok(())
+}
 
-    match header.format {
-        Format::Voice => {
-            eprintln!("Don't know how to make voice XML, only cartridge");
-            return;
-        },
-        Format::Cartridge => {
-            println!("data length = {}", data.len());
-
-            let Ok(cartridge) = Cartridge::parse(&data) else {
-                eprintln!("Error parsing cartridge data");
-                return;
-            };
-
-            let mut xml = XMLBuilder::new()
-                .version(XMLVersion::XML1_1)
-                .encoding("UTF-8".into())
-                .build();
-            
-            let cartridge_element = cartridge.to_xml();
-            xml.set_root_element(cartridge_element);
-            
-            let mut writer: Vec<u8> = Vec::new();
-            xml.generate(&mut writer).unwrap();
-        
-            let output = File::create(output_path);
-            output
-                .expect("to create output file")
-                .write_all(&writer)
-                .expect("to write XML data into the output file");
-            
-        }
-    }    
-}
-
-use xml::reader::{EventReader, XmlEvent};
-use sevenate::dx7::voice::VoiceName;
-use sevenate::dx7::{Algorithm, Transpose, Depth, Level, Coarse, Detune, Sensitivity};
-use sevenate::dx7::operator::{OperatorMode, Key};
-use sevenate::dx7::envelope::{Rate, Rates, Levels};
-use sevenate::dx7::lfo::LfoWaveform;
-
-pub fn run_make_syx(input_path: &PathBuf, output_path: &PathBuf) {
-    let file = match File::open(input_path) {
-        Ok(file) => file,
-        Err(err) => {
-            eprintln!("Unable to read from {}, error = {}", 
-                input_path.display(), err);
-            return;    
+/// Verifies System Exclusive files without converting them: the manufacturer,
+/// header, byte count and checksum of each bulk dump, and the range of every
+/// voice parameter. A directory stands for all the .syx files in it.
+/// Fails if any of the files is not valid.
+pub fn run_verify(paths: &[PathBuf]) -> Result<(), Error> {
+    let files = expand_directories(paths)?;
+
+    let mut failed = 0;
+    for file in &files {
+        if !verify_file(file) {
+            failed += 1;
+        }
+    }
+
+    println!("Verified {} file(s), {} failed", files.len(), failed);
+    if failed > 0 {
+        return Err(Error::Verify { failed, total: files.len() });
+    }
+    Ok(())
+}
+
+// Verifies one file, and reports the problems found in it.
+fn verify_file(path: &Path) -> bool {
+    let buffer = match read_file(path) {
+        Ok(buffer) => buffer,
+        Err(e) => {
+            println!("{}: FAILED", path.display());
+            println!("    {}", e);
+            return false;
         }
     };
 
-    let file = BufReader::new(file);
-    let parser = EventReader::new(file);
-
-    // Creating a default cartridge also creates 32 default voices. 
-    let mut cartridge: Cartridge = Default::default();
-    let mut voice_index: usize = 0;  // index of next voice to save in the cartridge
-    let mut voice: Voice = Default::default();
-    let mut operator_index: usize = 1; // index of operator to save in voice
-    let mut operator: Operator = Operator::new();
-    let mut keyboard_level_scaling: KeyboardLevelScaling = KeyboardLevelScaling::new();
-    let mut eg: Envelope = Envelope::new();
-    let mut inside_eg: bool = false;
-    let mut inside_rates: bool = false;
-    let mut inside_levels: bool = false;
-    let mut rates: Rates = [Default::default(); 4];
-    let mut levels: Levels = [Default::default(); 4];
-    let mut inside_operator: bool = false;
-    let mut inside_voice: bool = false;
-    let mut lfo: Lfo = Lfo::new();
-
-    for element in parser {
-        match element {
-            Ok(XmlEvent::StartElement { name, attributes, namespace }) => {
-                //println!("start {}", name);
-
-                match name.local_name.as_str() {
-                    "cartridge" => {},
-                    "voice" => {
-                        inside_voice = true;
-                        for attr in attributes {
-                            match attr.name.local_name.as_str() {
-                                "name" => {
-                                    voice.name = VoiceName::from_string(attr.value);
-                                },
-                                "algorithm" => {
-                                    voice.alg = Algorithm::new(attr.value.parse().expect("valid algorithm"));
-                                },
-                                "transpose" => {
-                                    voice.transpose = Transpose::new(attr.value.parse().expect("valid transpose"));
-                                },
-                                "feedback" => {
-                                    voice.feedback = Depth::new(attr.value.parse().expect("valid feedback"));
-                                },
-                                "oscillatorSync" => {
-                                    voice.osc_sync = attr.value.parse().expect("valid boolean");
-                                },
-                                "pitchModulationSensitivity" => {
-                                    voice.pitch_mod_sens = Depth::new(attr.value.parse().expect("valid PMS"));
-                                }
-                                _ => {}
-                            }
-                        }
-                    },
-                    "operator" => {
-                        inside_operator = true;
-                        for attr in attributes {
-                            match attr.name.local_name.as_str() {
-                                "level" => {
-                                    operator.output_level = Level::new(attr.value.parse().expect("valid level"));
-                                },
-                                "mode" => {
-                                    operator.mode = if attr.value == "ratio" { OperatorMode::Ratio } else { OperatorMode::Fixed }; 
-                                },
-                                "coarse" => {
-                                    operator.coarse = Coarse::new(attr.value.parse().expect("valid coarse"));
-                                },
-                                "fine" => {
-                                    operator.fine = Level::new(attr.value.parse().expect("valid fine"));
-                                },
-                                "detune" => {
-                                    operator.detune = Detune::new(attr.value.parse().expect("valid detune"));
-                                },
-                                "amplitudeModulationSensitivity" => {
-                                    operator.amp_mod_sens = Sensitivity::new(attr.value.parse().expect("valid AMS"));
-                                },
-                                "keyVelocitySensitivity" => {
-                                    operator.key_vel_sens = Depth::new(attr.value.parse().expect("valid KLS"));
-                                },
-                                "keyboardRateScaling" => {
-                                    operator.kbd_rate_scaling = Depth::new(attr.value.parse().expect("valid keyboard rate scaling"));
-                                },
-                                _ => {}
-                            }
-                        }
-                    },
-                    "keyboardLevelScaling" => {
-                        for attr in attributes {
-                            match attr.name.local_name.as_str() {
-                                "breakpoint" => {
-                                    keyboard_level_scaling.breakpoint = Key::new(attr.value.parse().expect("valid key"));
-                                },
-                                _ => {}
-                            }
-                        }
-                    },
-                    "depth" => {  // must be inside keyboardLevelScaling
-                        for attr in attributes {
-                            match attr.name.local_name.as_str() {
-                                "left" => {
-                                    keyboard_level_scaling.left.depth = Level::new(attr.value.parse().expect("valid depth"));
-                                },
-                                "right" => {
-                                    keyboard_level_scaling.right.depth = Level::new(attr.value.parse().expect("valid depth"));
-                                },
-                                _ => {}
-                            }
-                        }
-                    },
-                    "curve" => {  // must be inside keyboardLevelScaling
-                        for attr in attributes {
-                            match attr.name.local_name.as_str() {
-                                "left" => {
-                                    keyboard_level_scaling.left.curve = match attr.value.as_str() {
-                                        "+LIN" => ScalingCurve::lin_pos(),
-                                        "-LIN" => ScalingCurve::lin_neg(),
-                                        "+EXP" => ScalingCurve::exp_pos(),
-                                        "-EXP" => ScalingCurve::exp_neg(),
-                                        _ => { ScalingCurve::lin_pos() }
-                                    };
-                                },
-                                "right" => {
-                                    keyboard_level_scaling.right.curve = match attr.value.as_str() {
-                                        "+LIN" => ScalingCurve::lin_pos(),
-                                        "-LIN" => ScalingCurve::lin_neg(),
-                                        "+EXP" => ScalingCurve::exp_pos(),
-                                        "-EXP" => ScalingCurve::exp_neg(),
-                                        _ => { ScalingCurve::lin_pos() }
-                                    };
-                                },
-                                _ => {}
-                            }
-                        }
-                    },
-                    "eg" => { 
-                        inside_eg = true; 
-                    },
-                    "rates" => {
-                        inside_rates = true;
-                    },
-                    "levels" => {
-                        inside_levels = true;
-                    },
-                    "lfo" => {
-                        for attr in attributes {
-                            match attr.name.local_name.as_str() {
-                                "speed" => {
-                                    lfo.speed = Level::new(attr.value.parse().expect("valid speed"));
-                                },
-                                "delay" => {
-                                    lfo.delay = Level::new(attr.value.parse().expect("valid delay"));
-                                },
-                                "pmd" => {
-                                    lfo.pmd = Level::new(attr.value.parse().expect("valid PMD"));
-                                },
-                                "amd" => {
-                                    lfo.amd = Level::new(attr.value.parse().expect("valid AMD"));
-                                },
-                                "sync" => {
-                                    lfo.sync = attr.value.parse().expect("valid sync");
-                                },
-                                "wave" => {
-                                    lfo.waveform = match attr.value.as_str() {
-                                        "triangle" => LfoWaveform::Triangle,
-                                        "saw-down" => LfoWaveform::SawDown,
-                                        "saw-up" => LfoWaveform::SawUp,
-                                        "square" => LfoWaveform::Square,
-                                        "sine" => LfoWaveform::Sine,
-                                        "sample-and-hold" => LfoWaveform::SampleAndHold,
-                                        _ => LfoWaveform::Sine
-                                    }
-                                },
-                                _ => {}
-                            }
-                        }
-                    },
-                    "operators" => {
-                        operator_index = 0;
-                    }
-                    "peg" => {  // voice PEG
-                        inside_eg = true;
-                    }
-                    _ => {}
-                };
-            },
-            Ok(XmlEvent::Characters(text)) => {
-                if inside_rates {
-                    //println!("rates = {}", text);
-                    let parts: Vec<&str> = text.split(" ").collect();
-                    let mut index = 0;
-                    for part in parts.iter() {
-                        rates[index] = Rate::new(part.parse().expect("valid rate"));
-                        //println!("rates[{}] = {}", index, rates[index]);
-                        index += 1;
-                    }
-                    eg.rates = rates;
-                } else if inside_levels {
-                    //println!("levels = {}", text);
-                    let parts: Vec<&str> = text.split(" ").collect();
-                    let mut index = 0;
-                    for part in parts.iter() {
-                        levels[index] = Level::new(part.parse().expect("valid level"));
-                        //println!("levels[{}] = {}", index, levels[index]);
-                        index += 1;
-                    }
-                    eg.levels = levels;
-                } else {
-                    println!("???, text = {}", text);
+    let parts = split_parts(&buffer);
+    let mut valid = true;
+    for (index, part) in parts.iter().enumerate() {
+        let prefix = if parts.len() > 1 {
+            format!("{} message {}", path.display(), index + 1)
+        } else {
+            path.display().to_string()
+        };
+
+        if let Some(result) = check_other_dump(part) {
+            match result {
+                Ok(description) => {
+                    println!("{}: OK, {}", prefix, description);
+                },
+                Err(e) => {
+                    println!("{}: FAILED", prefix);
+                    println!("    {}", e);
+                    valid = false;
                 }
+            }
+            continue;
+        }
+
+        match salvage(part) {
+            Ok(salvaged) if salvaged.problems.is_empty() => {
+                println!("{}: OK, {}", prefix, describe_document(&salvaged.document));
             },
-            Ok(XmlEvent::CData(content)) => {
-                println!("CDATA = {}", content);
-            },
-            Ok(XmlEvent::EndElement { name }) => {
-                //println!("end {}", name);
-                match name.local_name.as_str() {
-                    "cartridge" => {
-                        println!("cartridge done");
-                    },
-                    "voice" => {
-                        inside_voice = false;
-                        cartridge.voices[voice_index] = voice.clone();
-                        println!("voice #{} added to cartridge:", voice_index + 1);
-                        println!("{}", voice);
-                        voice_index += 1;
-                        operator_index = 0;  // voice added, reset operator count
-                    },
-                    "operator" => {
-                        println!("voice {}, operator {}", voice_index, operator_index);
-                        inside_operator = false;
-                        voice.operators[operator_index] = operator.clone();
-                        operator_index += 1;
-                    },
-                    "operators" => {
-                        operator_index = 0;  // definitely need to reset
-                    },
-                    "keyboardLevelScaling" => {
-                        println!("KLS = {}", keyboard_level_scaling);
-                        operator.kbd_level_scaling = keyboard_level_scaling;
-                    },
-                    "rates" => {
-                        inside_rates = false;
-                        eg.rates = rates;
-                    },
-                    "levels" => {
-                        inside_levels = false;
-                        eg.levels = levels;
-                    },
-                    "eg" => {
-                        inside_eg = false;
-                        //println!("assigning operator EG to {}", eg);
-                        operator.eg = eg;
-                    },
-                    "lfo" => {
-                        voice.lfo = lfo;
-                    },
-                    "peg" => {
-                        //println!("assigning voice PEG to {}", eg);
-                        voice.peg = eg;
-                    }
-                    _ => {}
+            Ok(salvaged) => {
+                println!("{}: FAILED", prefix);
+                for problem in &salvaged.problems {
+                    println!("    {}", problem);
                 }
-            }
+                valid = false;
+            },
             Err(e) => {
-                eprintln!("Error: {e}");
-                break;
+                println!("{}: FAILED", prefix);
+                println!("    {}", e);
+                valid = false;
             }
-            _ => {}
         }
     }
+    valid
+}
 
-    // Print the results for debugging:
-    /*
-    for voice in cartridge.voices {
-        println!("{} {}", voice.name.value(), voice.alg.value());
-        for op in voice.operators {
-            println!("{}", op);
-        }
-        println!();
-    }
-     */
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sevenator::dx7::make_init_voice;
+    use syxpack::TERMINATOR;
 
-    let header = Header { 
-        channel: MIDIChannel::new(1), 
-        sub_status: 0,
-        format: Format::Cartridge,
-        byte_count: 4096
-    };
+    // Makes a path in the temporary directory that no other test uses.
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("sevenator-{}-{}", std::process::id(), name))
+    }
 
-    let mut data = Vec::<u8>::new();
-    data.extend(header.to_bytes());
+    #[test]
+    fn repair_writes_nothing_if_a_message_is_lost() {
+        let input_path = temp_path("lost-input.syx");
+        let output_path = temp_path("lost-output.syx");
 
-    let cartridge_data = cartridge.to_bytes();
-    data.extend(&cartridge_data);
-    data.push(checksum(&cartridge_data));
+        // A valid voice, then a message from another manufacturer.
+        let mut buffer = make_voice_message(&make_init_voice(), MIDIChannel::new(1)).to_bytes();
+        buffer.extend([INITIATOR, 0x41, 0x10, 0x42, 0x12, 0x00, TERMINATOR]);
+        fs::write(&input_path, &buffer).unwrap();
 
-    let message = Message::ManufacturerSpecific { 
-        manufacturer: Manufacturer::Standard(0x43), 
-        payload: data
-    };
+        let result = run_repair(&input_path, &Some(output_path.clone()));
+        let _ = fs::remove_file(&input_path);
+        assert!(matches!(result, Err(Error::SysEx(_))));
+        assert!(!output_path.exists());
+    }
 
-    let _ = write_file(output_path, &message.to_bytes());
+    #[test]
+    fn repair_writes_salvaged_messages() {
+        let input_path = temp_path("salvaged-input.syx");
+        let output_path = temp_path("salvaged-output.syx");
+
+        // A voice with a wrong checksum.
+        let mut buffer = make_voice_message(&make_init_voice(), MIDIChannel::new(1)).to_bytes();
+        let last = buffer.len() - 2;
+        buffer[last] ^= 0x01;
+        fs::write(&input_path, &buffer).unwrap();
+
+        let result = run_repair(&input_path, &Some(output_path.clone()));
+        let written = fs::read(&output_path);
+        let _ = fs::remove_file(&input_path);
+        let _ = fs::remove_file(&output_path);
+        assert!(result.is_ok());
+        assert!(parse_bulk_dump(&written.unwrap()).is_ok());
+    }
 }
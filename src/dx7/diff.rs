@@ -0,0 +1,165 @@
+//! Comparing voices and cartridges parameter by parameter.
+
+use std::fmt;
+
+use serde::Serialize;
+
+use sevenate::dx7::voice::Voice;
+use sevenate::dx7::sysex::SystemExclusiveData;
+
+use crate::dx7::{Document, VOICE_NAME_LENGTH};
+use crate::dx7::repair::{voice_parameters, NAME_OFFSET};
+use crate::error::Error;
+
+/// A parameter that is different in two voices.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Difference {
+    /// Voice number in a cartridge, or none for single voices
+    pub voice: Option<usize>,
+    /// Name of the parameter, like "OP2 EG rate 1"
+    pub parameter: String,
+    /// Value in the first voice
+    pub old: String,
+    /// Value in the second voice
+    pub new: String,
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(voice) = self.voice {
+            write!(f, "voice {} ", voice)?;
+        }
+        write!(f, "{}: {} → {}", self.parameter, self.old, self.new)
+    }
+}
+
+/// Compares two voices. The voice number is for reporting
+/// the differences of voices in cartridges.
+pub fn diff_voices(a: &Voice, b: &Voice, number: Option<usize>) -> Vec<Difference> {
+    let a_data = a.to_bytes();
+    let b_data = b.to_bytes();
+
+    let mut differences = Vec::<Difference>::new();
+    for ((parameter, _), (&old, &new)) in voice_parameters().into_iter().zip(a_data.iter().zip(&b_data)) {
+        if old != new {
+            differences.push(Difference {
+                voice: number,
+                old: describe_value(&parameter, old),
+                new: describe_value(&parameter, new),
+                parameter,
+            });
+        }
+    }
+
+    let name = NAME_OFFSET..NAME_OFFSET + VOICE_NAME_LENGTH;
+    if a_data[name.clone()] != b_data[name] {
+        differences.push(Difference {
+            voice: number,
+            parameter: String::from("name"),
+            old: format!("'{}'", a.name.value()),
+            new: format!("'{}'", b.name.value()),
+        });
+    }
+
+    differences
+}
+
+/// Compares two voices or two cartridges. The cartridges are
+/// compared slot by slot. Fails if one is a voice and the other
+/// a cartridge.
+pub fn diff_documents(a: &Document, b: &Document) -> Result<Vec<Difference>, Error> {
+    match (a, b) {
+        (Document::Voice(a), Document::Voice(b)) => Ok(diff_voices(a, b, None)),
+        (Document::Cartridge(a), Document::Cartridge(b)) => {
+            Ok(a.voices.iter()
+                .zip(&b.voices)
+                .enumerate()
+                .flat_map(|(index, (a, b))| diff_voices(a, b, Some(index + 1)))
+                .collect())
+        },
+        _ => Err(Error::Header(String::from("Cannot compare a single voice with a cartridge"))),
+    }
+}
+
+// Describes a value in unpacked voice data in the terms used on the synth.
+fn describe_value(parameter: &str, value: u8) -> String {
+    const CURVES: [&str; 4] = ["-LIN", "-EXP", "+EXP", "+LIN"];
+    const WAVEFORMS: [&str; 6] = ["triangle", "saw down", "saw up", "square", "sine", "sample and hold"];
+
+    let named = |names: &[&str]| names.get(value as usize).map(|s| s.to_string()).unwrap_or_else(|| value.to_string());
+
+    if parameter.ends_with("curve") {
+        named(&CURVES)
+    } else if parameter.ends_with("mode") {
+        named(&["ratio", "fixed"])
+    } else if parameter.ends_with("detune") {
+        format!("{:+}", value as i32 - 7)
+    } else if parameter.ends_with("sync") {
+        named(&["off", "on"])
+    } else {
+        match parameter {
+            "algorithm" => (value + 1).to_string(),
+            "transpose" => format!("{:+}", value as i32 - 24),
+            "LFO waveform" => named(&WAVEFORMS),
+            _ => value.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sevenate::dx7::cartridge::Cartridge;
+    use sevenate::dx7::voice::VoiceName;
+
+    const ROM1A: [u8; 4096] = include!("../rom1asyx.in");
+
+    fn rom1a_voice() -> Voice {
+        Cartridge::parse(&ROM1A).expect("ROM1A should parse").voices[0].clone()
+    }
+
+    // Makes a copy of the voice with one byte of its data changed.
+    fn with_byte(voice: &Voice, offset: usize, value: u8) -> Voice {
+        let mut data = voice.to_bytes();
+        data[offset] = value;
+        Voice::parse(&data).expect("voice should parse")
+    }
+
+    #[test]
+    fn identical_voices_have_no_differences() {
+        let voice = rom1a_voice();
+        assert!(diff_voices(&voice, &voice.clone(), None).is_empty());
+    }
+
+    #[test]
+    fn finds_changed_operator_parameter() {
+        // OP1 is the last operator, and output level is its 17th parameter.
+        let a = rom1a_voice();
+        let offset = 5 * 21 + 16;
+        let b = with_byte(&a, offset, 42);
+        assert_eq!(diff_voices(&a, &b, Some(3)), vec![Difference {
+            voice: Some(3),
+            parameter: String::from("OP1 output level"),
+            old: a.to_bytes()[offset].to_string(),
+            new: String::from("42"),
+        }]);
+    }
+
+    #[test]
+    fn finds_changed_name() {
+        let a = rom1a_voice();
+        let mut b = a.clone();
+        b.name = VoiceName::new("NEW NAME");
+        let differences = diff_voices(&a, &b, None);
+        assert_eq!(differences.len(), 1);
+        assert_eq!(differences[0].parameter, "name");
+        assert_eq!(differences[0].new, "'NEW NAME'");
+    }
+
+    #[test]
+    fn cannot_compare_voice_with_cartridge() {
+        let voice = Document::Voice(Box::new(rom1a_voice()));
+        let cartridge = Document::Cartridge(Cartridge::parse(&ROM1A).expect("ROM1A should parse"));
+        assert!(matches!(diff_documents(&voice, &cartridge), Err(Error::Header(_))));
+    }
+}
@@ -0,0 +1,226 @@
+//! Editing the voices of a cartridge: moving, swapping, replacing,
+//! copying and sorting them. Voice numbers are 1...32 like on the synth.
+
+use std::fmt;
+use std::str::FromStr;
+
+use sevenate::Ranged;
+use sevenate::dx7::voice::Voice;
+use sevenate::dx7::cartridge::{Cartridge, VOICE_COUNT};
+
+use crate::error::Error;
+
+// Checks that a voice number is 1...32, and returns the index of the voice.
+fn index_of(number: usize) -> Result<usize, Error> {
+    if (1..=VOICE_COUNT).contains(&number) {
+        Ok(number - 1)
+    } else {
+        Err(Error::Range(format!("Voice number must be 1...{}, got {}", VOICE_COUNT, number)))
+    }
+}
+
+/// Moves a voice to another slot. The voices in between
+/// move by one slot to make room for it.
+pub fn move_voice(cartridge: &mut Cartridge, from: usize, to: usize) -> Result<(), Error> {
+    let from = index_of(from)?;
+    let to = index_of(to)?;
+    let voice = cartridge.voices.remove(from);
+    cartridge.voices.insert(to, voice);
+    Ok(())
+}
+
+/// Swaps the voices in two slots.
+pub fn swap_voices(cartridge: &mut Cartridge, a: usize, b: usize) -> Result<(), Error> {
+    let a = index_of(a)?;
+    let b = index_of(b)?;
+    cartridge.voices.swap(a, b);
+    Ok(())
+}
+
+/// Replaces the voice in a slot.
+pub fn replace_voice(cartridge: &mut Cartridge, number: usize, voice: Voice) -> Result<(), Error> {
+    let index = index_of(number)?;
+    cartridge.voices[index] = voice;
+    Ok(())
+}
+
+/// Copies the voices `first...last` of the source cartridge into
+/// the target cartridge, starting from the slot `to`.
+pub fn copy_voices(source: &Cartridge, first: usize, last: usize,
+        target: &mut Cartridge, to: usize) -> Result<(), Error> {
+    let first = index_of(first)?;
+    let last = index_of(last)?;
+    let to = index_of(to)?;
+    if first > last {
+        return Err(Error::Range(format!("First voice {} is after the last voice {}", first + 1, last + 1)));
+    }
+
+    let count = last - first + 1;
+    if to + count > VOICE_COUNT {
+        return Err(Error::Range(format!("{} voice(s) do not fit in the target cartridge from voice {}", count, to + 1)));
+    }
+
+    target.voices[to .. to + count].clone_from_slice(&source.voices[first ..= last]);
+    Ok(())
+}
+
+/// The order to sort the voices of a cartridge in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortKey {
+    /// Alphabetical by voice name, ignoring case
+    Name,
+    /// By algorithm number, and by name within each algorithm
+    Algorithm,
+}
+
+impl fmt::Display for SortKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SortKey::Name => write!(f, "name"),
+            SortKey::Algorithm => write!(f, "algorithm"),
+        }
+    }
+}
+
+impl FromStr for SortKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "name" => Ok(SortKey::Name),
+            "algorithm" => Ok(SortKey::Algorithm),
+            _ => Err(format!("unknown sort key '{}', expected name or algorithm", s)),
+        }
+    }
+}
+
+/// Sorts the voices of a cartridge. Voices that compare equal
+/// stay in their original order.
+pub fn sort_voices(cartridge: &mut Cartridge, key: SortKey) {
+    let name = |voice: &Voice| voice.name.value().trim().to_lowercase();
+    match key {
+        SortKey::Name => cartridge.voices.sort_by_key(name),
+        SortKey::Algorithm => cartridge.voices.sort_by_key(|voice| (voice.alg.value(), name(voice))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sevenate::dx7::sysex::SystemExclusiveData;
+    use sevenate::dx7::voice::VoiceName;
+
+    const ROM1A: [u8; 4096] = include!("../rom1asyx.in");
+
+    // Makes a cartridge with the ROM1A voices named "V1"..."V32".
+    fn numbered_cartridge() -> Cartridge {
+        let mut cartridge = Cartridge::parse(&ROM1A).expect("ROM1A should parse");
+        for (index, voice) in cartridge.voices.iter_mut().enumerate() {
+            voice.name = VoiceName::new(&format!("V{}", index + 1));
+        }
+        cartridge
+    }
+
+    fn names(cartridge: &Cartridge) -> Vec<String> {
+        cartridge.voices.iter().map(|voice| voice.name.value().trim().to_string()).collect()
+    }
+
+    fn numbered(numbers: &[usize]) -> Vec<String> {
+        numbers.iter().map(|number| format!("V{}", number)).collect()
+    }
+
+    #[test]
+    fn moves_voice_to_lower_slot() {
+        let mut cartridge = numbered_cartridge();
+        move_voice(&mut cartridge, 5, 2).unwrap();
+        assert_eq!(names(&cartridge)[..6], numbered(&[1, 5, 2, 3, 4, 6]));
+        assert_eq!(cartridge.voices.len(), VOICE_COUNT);
+    }
+
+    #[test]
+    fn moves_voice_to_higher_slot() {
+        let mut cartridge = numbered_cartridge();
+        move_voice(&mut cartridge, 2, 5).unwrap();
+        assert_eq!(names(&cartridge)[..6], numbered(&[1, 3, 4, 5, 2, 6]));
+        assert_eq!(cartridge.voices.len(), VOICE_COUNT);
+    }
+
+    #[test]
+    fn rejects_voice_numbers_out_of_range() {
+        let mut cartridge = numbered_cartridge();
+        assert!(matches!(move_voice(&mut cartridge, 0, 1), Err(Error::Range(_))));
+        assert!(matches!(move_voice(&mut cartridge, 1, 33), Err(Error::Range(_))));
+        assert_eq!(names(&cartridge), numbered(&(1..=32).collect::<Vec<usize>>()));
+    }
+
+    #[test]
+    fn swaps_voices() {
+        let mut cartridge = numbered_cartridge();
+        swap_voices(&mut cartridge, 1, 32).unwrap();
+        assert_eq!(names(&cartridge)[0], "V32");
+        assert_eq!(names(&cartridge)[31], "V1");
+    }
+
+    #[test]
+    fn swapping_voice_with_itself_changes_nothing() {
+        let mut cartridge = numbered_cartridge();
+        let before = cartridge.to_bytes();
+        swap_voices(&mut cartridge, 7, 7).unwrap();
+        assert_eq!(cartridge.to_bytes(), before);
+    }
+
+    #[test]
+    fn copies_voices() {
+        let source = numbered_cartridge();
+        let mut target = numbered_cartridge();
+        copy_voices(&source, 1, 3, &mut target, 30).unwrap();
+        assert_eq!(names(&target)[28..], numbered(&[29, 1, 2, 3]));
+    }
+
+    #[test]
+    fn rejects_copy_past_last_slot() {
+        let source = numbered_cartridge();
+        let mut target = numbered_cartridge();
+        let before = target.to_bytes();
+        assert!(matches!(copy_voices(&source, 1, 4, &mut target, 30), Err(Error::Range(_))));
+        assert!(matches!(copy_voices(&source, 4, 1, &mut target, 1), Err(Error::Range(_))));
+        assert_eq!(target.to_bytes(), before);
+    }
+
+    #[test]
+    fn sorts_by_name_keeping_equal_voices_in_order() {
+        // Name the voices alternately "B" and "A", so that only
+        // the rest of the voice data tells them apart.
+        let mut cartridge = Cartridge::parse(&ROM1A).expect("ROM1A should parse");
+        for (index, voice) in cartridge.voices.iter_mut().enumerate() {
+            voice.name = VoiceName::new(if index % 2 == 0 { "B" } else { "a" });
+        }
+        let original: Vec<Vec<u8>> = cartridge.voices.iter().map(|voice| voice.to_bytes()).collect();
+
+        sort_voices(&mut cartridge, SortKey::Name);
+
+        let expected: Vec<Vec<u8>> = original.iter().skip(1).step_by(2)
+            .chain(original.iter().step_by(2))
+            .cloned()
+            .collect();
+        let sorted: Vec<Vec<u8>> = cartridge.voices.iter().map(|voice| voice.to_bytes()).collect();
+        assert_eq!(sorted, expected);
+    }
+
+    #[test]
+    fn sorts_by_algorithm_then_name() {
+        let mut cartridge = numbered_cartridge();
+        sort_voices(&mut cartridge, SortKey::Algorithm);
+        for pair in cartridge.voices.windows(2) {
+            let key = |voice: &Voice| (voice.alg.value(), voice.name.value().trim().to_lowercase());
+            assert!(key(&pair[0]) <= key(&pair[1]));
+        }
+    }
+
+    #[test]
+    fn parses_sort_keys() {
+        assert_eq!("Name".parse::<SortKey>(), Ok(SortKey::Name));
+        assert_eq!("algorithm".parse::<SortKey>(), Ok(SortKey::Algorithm));
+        assert!("voice".parse::<SortKey>().is_err());
+    }
+}
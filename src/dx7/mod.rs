@@ -1,12 +1,11 @@
-use std::path::PathBuf;
-use std::fs::File;
-use std::io::Write;
 use rand::Rng;
 
 use sevenate::dx7::lfo::Lfo;
 use syxpack::{
     Message,
-    Manufacturer
+    Manufacturer,
+    INITIATOR,
+    TERMINATOR
 };
 use sevenate::Ranged;
 use sevenate::dx7::{
@@ -20,6 +19,7 @@ use sevenate::dx7::voice::{
     Voice,
     VoiceName,
     OPERATOR_COUNT,
+    VOICE_PACKED_SIZE,
 };
 use sevenate::dx7::operator::{
     KeyboardLevelScaling,
@@ -35,151 +35,314 @@ use sevenate::dx7::sysex::{
     SystemExclusiveData,
     Header,
     Format,
+    MIDIChannel,
     checksum
 };
 
+pub mod diff;
+pub mod edit;
+pub mod parameter;
 pub mod randomizer;
+pub mod repair;
+pub mod supplement;
+
+use crate::error::Error;
+use crate::dx7::repair::{Problem, check_voice_data};
+
+/// Length of a voice name.
+pub const VOICE_NAME_LENGTH: usize = 10;
+
+/// Checks if a character can be in a voice name. Only printable ASCII
+/// characters are accepted, so that the name survives every format.
+pub fn is_name_character(c: char) -> bool {
+    (' '..='~').contains(&c)
+}
+
+/// Checks that a voice name has at most 10 characters, all of them
+/// printable ASCII.
+pub fn is_valid_voice_name(name: &str) -> bool {
+    name.chars().count() <= VOICE_NAME_LENGTH && name.chars().all(is_name_character)
+}
+
+/// The contents of a bulk dump or a document: either a single voice or a cartridge.
+#[derive(Debug)]
+pub enum Document {
+    Voice(Box<Voice>),
+    Cartridge(Cartridge),
+}
+
+/// Makes a single voice bulk dump message (format 0, 155 bytes of data)
+/// with the header and checksum.
+pub fn make_voice_message(voice: &Voice, channel: MIDIChannel) -> Message {
+    let header = Header {
+        channel,
+        sub_status: 0,
+        format: Format::Voice,
+        byte_count: 155,
+    };
+
+    let voice_data = voice.to_bytes();
+
+    let mut payload = Vec::<u8>::new();
+    payload.extend(header.to_bytes());
+    payload.extend(&voice_data);
+    payload.push(checksum(&voice_data));
+
+    Message::ManufacturerSpecific {
+        manufacturer: Manufacturer::Standard(0x43),
+        payload
+    }
+}
+
+/// Makes a cartridge bulk dump message (format 9, 4096 bytes of data)
+/// with the header and checksum.
+pub fn make_cartridge_message(cartridge: &Cartridge, channel: MIDIChannel) -> Message {
+    let header = Header {
+        channel,
+        sub_status: 0,
+        format: Format::Cartridge,
+        byte_count: 4096,
+    };
+
+    let cartridge_data = cartridge.to_bytes();
+
+    let mut payload = Vec::<u8>::new();
+    payload.extend(header.to_bytes());
+    payload.extend(&cartridge_data);
+    payload.push(checksum(&cartridge_data));
+
+    Message::ManufacturerSpecific {
+        manufacturer: Manufacturer::Standard(0x43),
+        payload
+    }
+}
+
+/// Makes a dump request message, which asks the synth to send
+/// its current voice (format 0) or all its voices (format 9).
+pub fn make_dump_request(format: Format, channel: MIDIChannel) -> Vec<u8> {
+    let message = Message::ManufacturerSpecific {
+        manufacturer: Manufacturer::Standard(0x43),
+        payload: vec![0x20 | channel.as_byte(), format.into()],
+    };
+    message.to_bytes()
+}
+
+/// Checks that the bytes are a complete DX7 single voice or
+/// cartridge bulk dump message, with the correct length and checksum.
+/// Returns the header of the dump if it is valid.
+pub fn check_bulk_dump(data: &[u8]) -> Result<Header, Error> {
+    if data.len() < 5 {
+        return Err(Error::SysEx(format!("Message is too short ({} bytes)", data.len())));
+    }
+
+    if data[data.len() - 1] != TERMINATOR {
+        return Err(Error::SysEx(format!("Message is incomplete, no F7 at the end ({} bytes)", data.len())));
+    }
+
+    let payload = match Message::from_bytes(data) {
+        Ok(Message::ManufacturerSpecific { manufacturer: Manufacturer::Standard(0x43), payload }) => payload,
+        Ok(Message::ManufacturerSpecific { manufacturer, payload: _ }) => {
+            return Err(Error::SysEx(format!("Not a Yamaha message (manufacturer = {})", manufacturer)));
+        },
+        Ok(_) => {
+            return Err(Error::SysEx(String::from("Not a manufacturer-specific System Exclusive message")));
+        },
+        Err(e) => {
+            return Err(Error::SysEx(e.to_string()));
+        }
+    };
+
+    if payload.len() < Header::DATA_SIZE + 1 {
+        return Err(Error::Header(format!("Payload is too short ({} bytes)", payload.len())));
+    }
+
+    if Format::try_from(payload[1]).is_err() {
+        return Err(Error::Header(format!("Unknown bulk dump format {}", payload[1])));
+    }
+
+    let header = Header::parse(&payload).map_err(|e| Error::Header(e.to_string()))?;
+
+    let expected_size = match header.format {
+        Format::Voice => Voice::DATA_SIZE,
+        Format::Cartridge => Cartridge::DATA_SIZE,
+    };
+
+    // The byte count is a 14-bit number in two bytes, most significant first.
+    // The parsed header always has the count of its format, so check the bytes.
+    let byte_count = ((payload[2] as usize) << 7) | payload[3] as usize;
+    if byte_count != expected_size {
+        return Err(Error::Header(format!("Byte count is {} in the header, expected {} for {} data",
+            byte_count, expected_size, header.format)));
+    }
+
+    let data = &payload[Header::DATA_SIZE .. payload.len() - 1];
+    if data.len() != expected_size {
+        return Err(Error::Header(format!("Got {} bytes of {} data, expected {} bytes",
+            data.len(), header.format, expected_size)));
+    }
+
+    let expected_checksum = checksum(data);
+    let actual_checksum = payload[payload.len() - 1];
+    if actual_checksum != expected_checksum {
+        return Err(Error::Checksum { found: actual_checksum, expected: expected_checksum });
+    }
+
+    Ok(header)
+}
+
+/// Converts a single voice or cartridge bulk dump message
+/// into a voice or a cartridge, after checking it.
+/// Parameter values out of range are errors.
+pub fn parse_bulk_dump(message: &[u8]) -> Result<Document, Error> {
+    let header = check_bulk_dump(message)?;
+
+    // The message is F0 43 (header) (data) (checksum) F7
+    let data = &message[2 + Header::DATA_SIZE .. message.len() - 2];
+    match header.format {
+        Format::Voice => {
+            check_range(&check_voice_data(data, 1))?;
+            let voice = Voice::parse(data).map_err(|e| Error::SysEx(e.to_string()))?;
+            Ok(Document::Voice(Box::new(voice)))
+        },
+        Format::Cartridge => {
+            for (index, packed) in data.chunks_exact(VOICE_PACKED_SIZE).enumerate() {
+                check_range(&check_voice_data(&Voice::unpack(packed), index + 1))?;
+            }
+            let cartridge = Cartridge::parse(data).map_err(|e| Error::SysEx(e.to_string()))?;
+            Ok(Document::Cartridge(cartridge))
+        }
+    }
+}
+
+// Makes a range error from the first of the problems, if there are any.
+fn check_range(problems: &[Problem]) -> Result<(), Error> {
+    match problems {
+        [] => Ok(()),
+        [problem] => Err(Error::Range(problem.to_string())),
+        [problem, rest @ ..] => Err(Error::Range(format!("{} (and {} more)", problem, rest.len()))),
+    }
+}
+
+/// Finds the System Exclusive messages in data that may contain
+/// many of them back to back, like a library of cartridges.
+/// Returns the offset and the bytes of each message, from F0 to F7.
+/// If a message is cut short by the start of the next one or the end
+/// of the data, it is returned without the F7. Bytes outside messages
+/// are skipped.
+pub fn find_messages(data: &[u8]) -> Vec<(usize, &[u8])> {
+    let mut messages = Vec::new();
+    let mut start: Option<usize> = None;
+
+    for (offset, &b) in data.iter().enumerate() {
+        if b == INITIATOR {
+            if let Some(s) = start {
+                messages.push((s, &data[s..offset]));
+            }
+            start = Some(offset);
+        } else if b == TERMINATOR {
+            if let Some(s) = start.take() {
+                messages.push((s, &data[s..=offset]));
+            }
+        }
+    }
+
+    if let Some(s) = start {
+        messages.push((s, &data[s..]));
+    }
+
+    messages
+}
+
+/// Makes a cartridge filled with initialized voices.
+pub fn make_init_cartridge() -> Cartridge {
+    let mut voices: Vec<Voice> = Vec::new();
+    for _ in 0..VOICE_COUNT {
+        voices.push(make_init_voice());
+    }
+    Cartridge { voices }
+}
 
 // Makes a cartridge filled with random voices.
-pub fn make_random_cartridge() -> Cartridge {
+pub fn make_random_cartridge<R: Rng + ?Sized>(rng: &mut R) -> Cartridge {
     let mut voices: Vec<Voice> = Vec::new();
     for _ in 0..VOICE_COUNT {
-        voices.push(make_random_voice());
+        voices.push(make_random_voice(rng));
     }
     Cartridge { voices }
 }
 
-pub fn make_random_voice() -> Voice {
+pub fn make_random_voice<R: Rng + ?Sized>(rng: &mut R) -> Voice {
     let mut voice = Voice::new();
 
     for i in 0..OPERATOR_COUNT {
-        voice.operators[i] = Operator::random();
+        voice.operators[i] = random_operator(rng);
     }
 
-    voice.peg = Envelope::random();
-    voice.alg = Algorithm::random();
-    voice.feedback = Depth::random();
-
-    let mut rng = rand::rng();
+    voice.peg = random_envelope(rng);
+    voice.alg = random_ranged(rng);
+    voice.feedback = random_ranged(rng);
     voice.osc_sync = rng.random();
-
-    voice.lfo = Lfo::random();
-    voice.pitch_mod_sens = Depth::random();
-    voice.transpose = Transpose::random();
-    voice.name = VoiceName::random();
+    voice.lfo = random_lfo(rng);
+    voice.pitch_mod_sens = random_ranged(rng);
+    voice.transpose = random_ranged(rng);
+    voice.name = random_voice_name(rng);
 
     voice
 }
 
-pub fn list_cartridge(filedata: &[u8]) {
-    match Message::from_bytes(&filedata) {
-        Ok(Message::ManufacturerSpecific { manufacturer: _, payload }) => {
-            println!("message payload length = {}", payload.len());
-            let cartridge_data = &payload[Header::DATA_SIZE..];
-            println!("cartridge data length = {}", cartridge_data.len());
-            let cartridge = Cartridge::parse(&cartridge_data).unwrap();
+//
+// Random values from an explicit random number generator.
+// These mirror the `random()` functions in sevenate, which always
+// use the thread-local generator and so can't be reproduced from a seed.
+//
 
-            for voice in cartridge.voices.iter() {
-                println!("'{}'", voice.name.value());
-            }
-        },
-        _ => {
-            eprintln!("invalid SysEx message");
-        }
-    }
+/// Makes a random value of a ranged type.
+pub fn random_ranged<T: Ranged, R: Rng + ?Sized>(rng: &mut R) -> T {
+    T::new(rng.random_range(T::FIRST..=T::LAST))
 }
 
-pub fn dump_cartridge(filedata: &[u8]) {
-    match Message::from_bytes(&filedata) {
-        Ok(Message::ManufacturerSpecific { manufacturer: _, payload }) => {
-            println!("message payload length = {}", payload.len());
+/// Makes a new EG with random rates and levels.
+pub fn random_envelope<R: Rng + ?Sized>(rng: &mut R) -> Envelope {
+    Envelope::new_rate_level(
+        [random_ranged(rng), random_ranged(rng), random_ranged(rng), random_ranged(rng)],
+        [random_ranged(rng), random_ranged(rng), random_ranged(rng), random_ranged(rng)])
+}
 
-            let cartridge_data = &payload[Header::DATA_SIZE..];
-            println!("cartridge data length = {}", cartridge_data.len());
-            let cartridge = Cartridge::parse(&cartridge_data).unwrap();
+/// Makes a new random operator, like `Operator::random()`.
+pub fn random_operator<R: Rng + ?Sized>(rng: &mut R) -> Operator {
+    Operator {
+        eg: random_envelope(rng),
+        output_level: random_ranged(rng),
+        ..Operator::new()
+    }
+}
 
-            for (index, voice) in cartridge.voices.iter().enumerate() {
-                println!("VOICE {}: {}\n", index + 1, voice);
-            }
-        },
-        _ => {
-            eprintln!("invalid SysEx message");
-        }
+/// Makes a new LFO with random settings, like `Lfo::random()`.
+pub fn random_lfo<R: Rng + ?Sized>(rng: &mut R) -> Lfo {
+    Lfo {
+        speed: random_ranged(rng),
+        delay: random_ranged(rng),
+        pmd: random_ranged(rng),
+        amd: random_ranged(rng),
+        sync: true,
+        waveform: LfoWaveform::Triangle,
     }
 }
 
-pub fn extract_voices(filedata: &[u8], path: &PathBuf) {
-    match Message::from_bytes(&filedata) {
-        Ok(Message::ManufacturerSpecific { manufacturer: _, payload }) => {
-            println!("message payload length = {}", payload.len());
-
-            match Header::parse(&payload) {
-                Ok(mut header) => {
-                    println!("{}", header);
-
-                    let data = &payload[4..payload.len() - 1];
-                    println!("data length = {}", data.len());
-
-                    match header.format {
-                        Format::Voice => {
-                            eprintln!("Not extracting an individual voice");
-                        },
-                        Format::Cartridge => {
-                            // For a cartridge, pick out the data for each
-                            // of the 32 voices. Then unpack the voice data
-                            // and write it out to a new file.
-                            let mut voice_number = 1;
-                            let stem = path.file_stem().unwrap().to_str().unwrap();
-                            for packed_voice_data in data.chunks(128) {
-                                let voice_data = Voice::unpack(packed_voice_data);
-                                let mut payload = Vec::<u8>::new();
-
-                                // Change the format and byte count in the header,
-                                // then add it to the file data. Use the original channel.
-                                header.format = Format::Voice;
-                                header.byte_count = 155;
-                                payload.extend(header.to_bytes());
-                                payload.extend(&voice_data);
-
-                                payload.push(checksum(&voice_data.clone()));
-
-                                let message = Message::ManufacturerSpecific {
-                                    manufacturer: Manufacturer::Standard(0x43),
-                                    payload
-                                };
-
-                                let filename = format!("{}-{:02}.syx", stem, voice_number);
-                                match File::create(filename) {
-                                    Ok(mut file) => {
-                                        match file.write_all(&message.to_bytes()) {
-                                            Ok(_) => {},
-                                            Err(e) => {
-                                                eprintln!("Error writing file: {}", e);
-                                            }
-                                        }
-                                    },
-                                    Err(e) => {
-                                        eprintln!("Error creating file: {}", e);
-                                    }
-                                }
-
-                                voice_number += 1;
-                            }
-                        }
-                    }
-                },
-                Err(e) => {
-                    eprintln!("{}", e);
-                }
-            }
-        },
-        Err(e) => {
-            eprintln!("Error in message: {:?}", e);
-        },
-        _ => {
-            eprintln!("Not a manufacturer-specific System Exclusive message");
-        }
+/// Makes a random voice name of five syllables, like `VoiceName::random()`.
+pub fn random_voice_name<R: Rng + ?Sized>(rng: &mut R) -> VoiceName {
+    let consonants = [
+        'k', 's', 't', 'n', 'h', 'm', 'y', 'r',
+        'w', 'g', 'z', 'd', 'b', 'p'
+    ];
+    let vowels = ['a', 'i', 'u', 'e', 'o'];
+
+    let mut name = String::new();
+    for _ in 0..5 {
+        name.push(consonants[rng.random_range(0..consonants.len())]);
+        name.push(vowels[rng.random_range(0..vowels.len())]);
     }
+    VoiceName::from_string(name)
 }
 
 //
@@ -192,7 +355,7 @@ pub fn make_init_voice() -> Voice {
     let init_eg = Envelope::new();
 
     let init_op1 = Operator {
-        eg: init_eg.clone(),
+        eg: init_eg,
         kbd_level_scaling: KeyboardLevelScaling::new(),
         kbd_rate_scaling: Depth::new(0),
         amp_mod_sens: Sensitivity::new(0),
@@ -213,12 +376,12 @@ pub fn make_init_voice() -> Voice {
 
     Voice {
         operators: [
-            init_op1.clone(),
-            init_op_rest.clone(),
-            init_op_rest.clone(),
-            init_op_rest.clone(),
-            init_op_rest.clone(),
-            init_op_rest.clone(),
+            init_op1,
+            init_op_rest,
+            init_op_rest,
+            init_op_rest,
+            init_op_rest,
+            init_op_rest,
         ],
         peg: Envelope::new_rate_level(
             [Rate::new(99), Rate::new(99), Rate::new(99), Rate::new(99)],
@@ -239,3 +402,33 @@ pub fn make_init_voice() -> Voice {
         name: VoiceName::new("INIT VOICE"),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn voice_message() -> Vec<u8> {
+        let voice = make_init_voice();
+        make_voice_message(&voice, MIDIChannel::new(1)).to_bytes()
+    }
+
+    #[test]
+    fn accepts_voice_bulk_dump() {
+        let header = check_bulk_dump(&voice_message()).unwrap();
+        assert!(matches!(header.format, Format::Voice));
+        assert_eq!(header.byte_count, 155);
+    }
+
+    #[test]
+    fn rejects_wrong_byte_count() {
+        // The byte count 01 1B (155) is at offsets 4 and 5, after F0 43 0n 00.
+        let mut message = voice_message();
+        message[5] = 0x1c;
+        assert!(matches!(check_bulk_dump(&message), Err(Error::Header(_))));
+
+        let mut message = voice_message();
+        message[4] = 0x20;
+        message[5] = 0x00;
+        assert!(matches!(check_bulk_dump(&message), Err(Error::Header(_))));
+    }
+}
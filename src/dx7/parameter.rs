@@ -0,0 +1,230 @@
+//! Parameter change messages, which set one parameter of the voice
+//! in the edit buffer of the synth without sending the whole voice:
+//! `F0 43 1n gg pp vv F7`, where n is the MIDI channel, gg has the
+//! parameter group (0 for voice parameters) in bits 2...6 and the high
+//! bits of the parameter number in bits 0...1, pp has the low seven bits
+//! of the parameter number, and vv is the value.
+//!
+//! The voice parameter numbers are the offsets of the parameters
+//! in unpacked voice data (VCED), with OP6 first, so the values are the
+//! raw values of a single voice bulk dump.
+
+use sevenate::dx7::sysex::MIDIChannel;
+use syxpack::{INITIATOR, TERMINATOR};
+
+use crate::dx7::is_valid_voice_name;
+use crate::dx7::repair::{voice_parameters, NAME_OFFSET};
+use crate::error::Error;
+
+/// The Yamaha manufacturer ID.
+const YAMAHA: u8 = 0x43;
+
+/// Sub-status of parameter change messages.
+const PARAMETER_CHANGE: u8 = 0x10;
+
+/// Parameter group of the voice parameters.
+const VOICE_GROUP: u8 = 0;
+
+/// Number of the operator on/off parameter, which comes after the name.
+pub const OPERATOR_SWITCH: u8 = 155;
+
+// The fields of an operator in the order of its parameters,
+// named after the fields of `Operator`, `Envelope` and `KeyboardLevelScaling`.
+const OPERATOR_FIELDS: [&str; 21] = [
+    "eg.rate1", "eg.rate2", "eg.rate3", "eg.rate4",
+    "eg.level1", "eg.level2", "eg.level3", "eg.level4",
+    "kbd_level_scaling.breakpoint", "kbd_level_scaling.left.depth", "kbd_level_scaling.right.depth",
+    "kbd_level_scaling.left.curve", "kbd_level_scaling.right.curve",
+    "kbd_rate_scaling", "amp_mod_sens", "key_vel_sens",
+    "output_level", "mode", "coarse", "fine", "detune",
+];
+
+// The fields of the voice after the operators in the order of its parameters,
+// named after the fields of `Voice`, `Envelope` and `Lfo`.
+const VOICE_FIELDS: [&str; 19] = [
+    "peg.rate1", "peg.rate2", "peg.rate3", "peg.rate4",
+    "peg.level1", "peg.level2", "peg.level3", "peg.level4",
+    "alg", "feedback", "osc_sync",
+    "lfo.speed", "lfo.delay", "lfo.pmd", "lfo.amd",
+    "lfo.sync", "lfo.waveform", "pitch_mod_sens",
+    "transpose",
+];
+
+/// A voice parameter that can be changed with a parameter change message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VoiceParameter {
+    /// Parameter number 0...155
+    pub number: u8,
+    /// Path of the field in `Voice`, like "op6.eg.rate1" or "lfo.speed"
+    pub field: String,
+    /// Name of the parameter, like "OP6 EG rate 1"
+    pub name: String,
+    /// Largest raw value
+    pub maximum: u8,
+}
+
+/// Gets all the voice parameters in the order of their numbers:
+/// the parameters of each operator from OP6 to OP1, the voice parameters,
+/// the ten characters of the name, and the operator on/off switch
+/// where bit 5 is OP1 and bit 0 is OP6.
+pub fn parameters() -> Vec<VoiceParameter> {
+    let fields = (1..=6).rev()
+        .flat_map(|op| OPERATOR_FIELDS.iter().map(move |field| format!("op{}.{}", op, field)))
+        .chain(VOICE_FIELDS.iter().map(|field| field.to_string()));
+
+    let mut parameters: Vec<VoiceParameter> = fields
+        .zip(voice_parameters())
+        .enumerate()
+        .map(|(number, (field, (name, maximum)))| VoiceParameter { number: number as u8, field, name, maximum })
+        .collect();
+
+    for position in 1..=OPERATOR_SWITCH as usize - NAME_OFFSET {
+        parameters.push(VoiceParameter {
+            number: (NAME_OFFSET + position - 1) as u8,
+            field: format!("name{}", position),
+            name: format!("name character {}", position),
+            maximum: 0x7e,
+        });
+    }
+
+    parameters.push(VoiceParameter {
+        number: OPERATOR_SWITCH,
+        field: String::from("operator_switch"),
+        name: String::from("operator on/off"),
+        maximum: 0x3f,
+    });
+
+    parameters
+}
+
+/// Finds a voice parameter by its field path like "op6.eg.rate1",
+/// by its name like "OP6 EG rate 1", or by its number, ignoring case
+/// and everything but letters and digits, so that "op6-eg-rate-1" and
+/// "OP6_EG_RATE_1" also work.
+pub fn find_parameter(name: &str) -> Result<VoiceParameter, Error> {
+    let simplify = |s: &str| s.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .collect::<String>()
+        .to_lowercase();
+
+    let wanted = simplify(name);
+    let number = name.parse::<u8>().ok();
+    parameters().into_iter()
+        .find(|p| Some(p.number) == number || simplify(&p.field) == wanted || simplify(&p.name) == wanted)
+        .ok_or_else(|| Error::Range(format!("Unknown voice parameter '{}', expected a field like op1.output_level, \
+            a name like 'OP1 output level' or a number 0...{}", name, OPERATOR_SWITCH)))
+}
+
+/// Makes a parameter change message that sets a voice parameter
+/// of the voice in the edit buffer. The value is the raw value in
+/// a single voice bulk dump, and values out of range are errors.
+pub fn make_parameter_change(parameter: &VoiceParameter, value: u8, channel: MIDIChannel) -> Result<Vec<u8>, Error> {
+    if value > parameter.maximum {
+        return Err(Error::Range(format!("{} must be 0...{}, got {}", parameter.name, parameter.maximum, value)));
+    }
+    let is_name = (NAME_OFFSET..OPERATOR_SWITCH as usize).contains(&(parameter.number as usize));
+    if is_name && value < 0x20 {
+        return Err(Error::Range(format!("{} must be a printable ASCII character, got {}", parameter.name, value)));
+    }
+
+    Ok(vec![
+        INITIATOR,
+        YAMAHA,
+        PARAMETER_CHANGE | channel.as_byte(),
+        (VOICE_GROUP << 2) | (parameter.number >> 7),
+        parameter.number & 0x7f,
+        value,
+        TERMINATOR,
+    ])
+}
+
+/// Makes the parameter change messages that set the name of the voice
+/// in the edit buffer, padded with spaces.
+pub fn make_name_changes(name: &str, channel: MIDIChannel) -> Result<Vec<Vec<u8>>, Error> {
+    let length = OPERATOR_SWITCH as usize - NAME_OFFSET;
+    if !is_valid_voice_name(name) {
+        return Err(Error::Range(format!("Voice name must be at most {} printable ASCII characters, got '{}'", length, name)));
+    }
+
+    let parameters = parameters();
+    format!("{:<width$}", name, width = length).bytes()
+        .zip(&parameters[NAME_OFFSET..OPERATOR_SWITCH as usize])
+        .map(|(c, parameter)| make_parameter_change(parameter, c, channel))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sevenate::Ranged;
+
+    fn channel() -> MIDIChannel {
+        MIDIChannel::new(1)
+    }
+
+    #[test]
+    fn parameters_are_numbered_in_order() {
+        let parameters = parameters();
+        assert_eq!(parameters.len(), 156);
+        for (index, parameter) in parameters.iter().enumerate() {
+            assert_eq!(parameter.number as usize, index);
+        }
+    }
+
+    #[test]
+    fn finds_parameters_by_field() {
+        assert_eq!(find_parameter("op6.eg.rate1").unwrap().number, 0);
+        assert_eq!(find_parameter("op1.detune").unwrap().number, 125);
+        assert_eq!(find_parameter("transpose").unwrap().number, 144);
+        assert_eq!(find_parameter("operator_switch").unwrap().number, OPERATOR_SWITCH);
+    }
+
+    #[test]
+    fn finds_parameters_by_simplified_name() {
+        assert_eq!(find_parameter("OP6_EG_RATE_1").unwrap().number, 0);
+        assert_eq!(find_parameter("op6-eg-rate-1").unwrap().number, 0);
+        assert!(matches!(find_parameter("op7.eg.rate1"), Err(Error::Range(_))));
+    }
+
+    #[test]
+    fn splits_high_parameter_numbers() {
+        let parameter = find_parameter("transpose").unwrap();
+        let message = make_parameter_change(&parameter, 24, channel()).unwrap();
+        assert_eq!(message, vec![0xf0, 0x43, 0x10, 0x01, 144 & 0x7f, 24, 0xf7]);
+        assert_eq!(message[3], parameter.number >> 7);
+
+        let parameter = find_parameter("op6.eg.rate1").unwrap();
+        let message = make_parameter_change(&parameter, 99, channel()).unwrap();
+        assert_eq!(message[3], 0);
+        assert_eq!(message[4], 0);
+    }
+
+    #[test]
+    fn rejects_values_out_of_range() {
+        let parameter = find_parameter("op1.output_level").unwrap();
+        assert!(make_parameter_change(&parameter, 99, channel()).is_ok());
+        assert!(matches!(make_parameter_change(&parameter, 100, channel()), Err(Error::Range(_))));
+    }
+
+    #[test]
+    fn rejects_characters_that_are_not_printable() {
+        let parameter = find_parameter("name1").unwrap();
+        assert!(make_parameter_change(&parameter, b'~', channel()).is_ok());
+        assert!(matches!(make_parameter_change(&parameter, 0x1f, channel()), Err(Error::Range(_))));
+        assert!(matches!(make_parameter_change(&parameter, 0x7f, channel()), Err(Error::Range(_))));
+        assert!(matches!(make_name_changes("BRASS\u{1}", channel()), Err(Error::Range(_))));
+        assert!(matches!(make_name_changes("BRASS 1 AND 2", channel()), Err(Error::Range(_))));
+    }
+
+    #[test]
+    fn pads_names_with_spaces() {
+        let messages = make_name_changes("BRASS", channel()).unwrap();
+        assert_eq!(messages.len(), 10);
+        let name: Vec<u8> = messages.iter().map(|message| message[5]).collect();
+        assert_eq!(name, b"BRASS     ");
+        let numbers: Vec<usize> = messages.iter()
+            .map(|message| ((message[3] as usize & 0x03) << 7) | message[4] as usize)
+            .collect();
+        assert_eq!(numbers, (NAME_OFFSET..NAME_OFFSET + 10).collect::<Vec<usize>>());
+    }
+}
@@ -8,89 +8,194 @@
 
 use std::cmp;
 
+use log::debug;
 use rand::Rng;
 
 use sevenate::Ranged;
 use sevenate::dx7::{
     Level,
-    Algorithm
+    Algorithm,
+    Coarse,
+    Depth,
+    Detune,
+    Sensitivity,
+};
+use sevenate::dx7::voice::{
+    Voice,
+    OPERATOR_COUNT,
+};
+use sevenate::dx7::operator::{
+    Key,
+    KeyboardLevelScaling,
+    OperatorMode,
+    Scaling,
+    ScalingCurve,
+};
+use sevenate::dx7::envelope::{
+    Envelope,
+    Rate,
+};
+use sevenate::dx7::lfo::{
+    Lfo,
+    LfoWaveform,
 };
-use sevenate::dx7::voice::Voice;
 
-struct TimbreParameters {
-    atonality: Level,
-    complexity: Level,
-    brightness: Level,
+use crate::dx7::random_voice_name;
+
+/// Parameters that affect the tone color of the voice.
+#[derive(Debug, Clone, Copy)]
+pub struct TimbreParameters {
+    /// How far the operator frequencies stray from harmonic ratios.
+    pub atonality: Level,
+    /// How complex the algorithm and the feedback are.
+    pub complexity: Level,
+    /// How loud and high the modulators are.
+    pub brightness: Level,
 }
 
-struct EnvelopeParameters {
-    hardness: Level,
-    hitness: Level,
-    twang: Level,
-    longness: Level,
+/// Parameters that affect the shape of the envelopes.
+#[derive(Debug, Clone, Copy)]
+pub struct EnvelopeParameters {
+    /// How fast the attack is.
+    pub hardness: Level,
+    /// How percussive the amplitude of the carriers is.
+    pub hitness: Level,
+    /// How much the modulators decay after the attack.
+    pub twang: Level,
+    /// How long the decay and release are.
+    pub longness: Level,
 }
 
-struct MovementParameters {
-    wobble: Level,
-    wubble: Level,
-    velocity: Level,
+/// Parameters that affect the modulation of the voice.
+#[derive(Debug, Clone, Copy)]
+pub struct MovementParameters {
+    /// Amount of LFO pitch modulation.
+    pub wobble: Level,
+    /// Amount of LFO amplitude modulation.
+    pub wubble: Level,
+    /// Amount of key velocity sensitivity.
+    pub velocity: Level,
 }
 
+/// All the parameters for the randomizer.
+#[derive(Debug, Clone, Copy)]
 pub struct RandomizationParameters {
-    timbre: TimbreParameters,
-    envelope: EnvelopeParameters,
-    movement: MovementParameters,
-}
-
-pub fn randomize(params: RandomizationParameters) -> Voice {
-    // DX7 algorithms from least complex to most.
-    let algorithm_lookup = [
-        32, 31, 25, 24, 30, 29, 23, 22, 21, 5, 6, 28, 27, 26, 19, 20,
-        1, 2, 4, 3, 9, 11, 10, 12, 13, 8, 7, 15, 14, 17, 16, 18,
-    ];
-
-    // Which operators are carriers in a given algorithm.
-    // Indexed by algorithm# - 1.
-    let carrier_lookup = [
-        vec![1, 3],
-        vec![1, 3],
-        vec![1, 4],
-        vec![1, 4],
-        vec![1, 3, 5],
-        vec![1, 3, 5],
-        vec![1, 3],
-        vec![1, 3],
-        vec![1, 3],
-        vec![1, 4],
-        vec![1, 4],
-        vec![1, 3],
-        vec![1, 3],
-        vec![1, 3],
-        vec![1, 3],
-        vec![1],
-        vec![1],
-        vec![1],
-        vec![1, 4, 5],
-        vec![1, 2, 4],
-        vec![1, 2, 4, 5],
-        vec![1, 3, 4, 5],
-        vec![1, 2, 4, 5],
-        vec![1, 2, 3, 4, 5],
-        vec![1, 2, 3, 4, 5],
-        vec![1, 2, 4],
-        vec![1, 2, 4],
-        vec![1, 3, 6],
-        vec![1, 2, 3, 5],
-        vec![1, 2, 3, 6],
-        vec![1, 2, 3, 4, 5],
-        vec![1, 2, 3, 4, 5, 6],
-    ];
-
-    // Select an algorithm.
-
-    let count = algorithm_lookup.len() as i32;
+    pub timbre: TimbreParameters,
+    pub envelope: EnvelopeParameters,
+    pub movement: MovementParameters,
+}
+
+impl RandomizationParameters {
+    pub fn new(timbre: TimbreParameters, envelope: EnvelopeParameters, movement: MovementParameters) -> Self {
+        Self { timbre, envelope, movement }
+    }
+}
+
+impl Default for RandomizationParameters {
+    /// Makes randomization parameters with every value in the middle of the range.
+    fn default() -> Self {
+        let middle = Level::new(50);
+        RandomizationParameters {
+            timbre: TimbreParameters {
+                atonality: middle,
+                complexity: middle,
+                brightness: middle,
+            },
+            envelope: EnvelopeParameters {
+                hardness: middle,
+                hitness: middle,
+                twang: middle,
+                longness: middle,
+            },
+            movement: MovementParameters {
+                wobble: middle,
+                wubble: middle,
+                velocity: middle,
+            },
+        }
+    }
+}
+
+// DX7 algorithms from least complex to most.
+const ALGORITHM_LOOKUP: [i32; 32] = [
+    32, 31, 25, 24, 30, 29, 23, 22, 21, 5, 6, 28, 27, 26, 19, 20,
+    1, 2, 4, 3, 9, 11, 10, 12, 13, 8, 7, 15, 14, 17, 16, 18,
+];
+
+// Which operators are carriers in a given algorithm.
+// Indexed by algorithm# - 1.
+const CARRIER_LOOKUP: [&[usize]; 32] = [
+    &[1, 3],
+    &[1, 3],
+    &[1, 4],
+    &[1, 4],
+    &[1, 3, 5],
+    &[1, 3, 5],
+    &[1, 3],
+    &[1, 3],
+    &[1, 3],
+    &[1, 4],
+    &[1, 4],
+    &[1, 3],
+    &[1, 3],
+    &[1, 3],
+    &[1, 3],
+    &[1],
+    &[1],
+    &[1],
+    &[1, 4, 5],
+    &[1, 2, 4],
+    &[1, 2, 4, 5],
+    &[1, 3, 4, 5],
+    &[1, 2, 4, 5],
+    &[1, 2, 3, 4, 5],
+    &[1, 2, 3, 4, 5],
+    &[1, 2, 4],
+    &[1, 2, 4],
+    &[1, 3, 6],
+    &[1, 2, 3, 5],
+    &[1, 2, 3, 6],
+    &[1, 2, 3, 4, 5],
+    &[1, 2, 3, 4, 5, 6],
+];
+
+// Harmonic frequency ratios, used when atonality is low.
+const HARMONIC_RATIOS: [i32; 8] = [1, 1, 2, 2, 3, 4, 5, 6];
+
+/// Returns true if the operator (1...6) is a carrier in the algorithm.
+pub fn is_carrier(algorithm: Algorithm, operator: usize) -> bool {
+    CARRIER_LOOKUP[(algorithm.value() - 1) as usize].contains(&operator)
+}
+
+// Maps a 0...99 parameter value linearly into the range low...high
+// (high may be less than low, to invert the mapping).
+fn scale(value: Level, low: i32, high: i32) -> i32 {
+    low + ((high - low) as f32 * value.value() as f32 / 99.0).round() as i32
+}
+
+// Adds a random offset of at most `amount` in either direction to the value,
+// and clamps the result to the range first...last.
+fn jitter<R: Rng + ?Sized>(rng: &mut R, value: i32, amount: i32, first: i32, last: i32) -> i32 {
+    let offset = if amount > 0 { rng.random_range(-amount..=amount) } else { 0 };
+    num::clamp(value + offset, first, last)
+}
+
+fn jitter_level<R: Rng + ?Sized>(rng: &mut R, value: i32, amount: i32) -> Level {
+    Level::new(jitter(rng, value, amount, Level::FIRST, Level::LAST))
+}
+
+fn jitter_rate<R: Rng + ?Sized>(rng: &mut R, value: i32, amount: i32) -> Rate {
+    Rate::new(jitter(rng, value, amount, Rate::FIRST, Rate::LAST))
+}
+
+fn jitter_depth<R: Rng + ?Sized>(rng: &mut R, value: i32, amount: i32) -> Depth {
+    Depth::new(jitter(rng, value, amount, Depth::FIRST, Depth::LAST))
+}
+
+// Picks an algorithm based on the complexity.
+fn select_algorithm<R: Rng + ?Sized>(rng: &mut R, complexity: Level) -> Algorithm {
+    let count = ALGORITHM_LOOKUP.len() as i32;
     let q = count / 8;
-    let mut rng = rand::rng();
     let x = rng.random_range(-q..=q);
 
     /*
@@ -99,25 +204,202 @@ pub fn randomize(params: RandomizationParameters) -> Voice {
                  Math.floor(ALGO_COMPLEXITY_LOOKUP.length/100.0*complexity) + randomInt(-ALGO_COMPLEXITY_LOOKUP.length/8, ALGO_COMPLEXITY_LOOKUP.length/8)))
 
     ) */
-    let chosen_alg = algorithm_lookup[
-        cmp::max(
-            0,
-            cmp::min(
-                count - 1,
-                (((count as f32) / 100.0 * params.timbre.complexity.value() as f32).floor()) as i32 + x)) as usize];
-    let algorithm = Algorithm::new(chosen_alg + 1);
-    println!("Algorithm = {}", algorithm.value());
+    let index = cmp::max(
+        0,
+        cmp::min(
+            count - 1,
+            (((count as f32) / 100.0 * complexity.value() as f32).floor()) as i32 + x));
+    Algorithm::new(ALGORITHM_LOOKUP[index as usize])
+}
+
+// Makes the amplitude envelope of a carrier operator.
+fn make_carrier_eg<R: Rng + ?Sized>(rng: &mut R, params: &EnvelopeParameters) -> Envelope {
+    // Hardness makes the attack faster, hitness makes the
+    // sustain lower and the decay faster, longness slows down
+    // the decay and the release.
+    let attack = scale(params.hardness, 40, 99);
+    let decay1 = scale(params.hitness, 35, 85) - scale(params.longness, 0, 25);
+    let decay2 = scale(params.hitness, 20, 70) - scale(params.longness, 0, 30);
+    let release = scale(params.longness, 80, 25);
+
+    let peak = 99;
+    let level2 = scale(params.hitness, 95, 70);
+    let sustain = scale(params.hitness, 90, 0);
+
+    Envelope::new_rate_level(
+        [
+            jitter_rate(rng, attack, 5),
+            jitter_rate(rng, decay1, 8),
+            jitter_rate(rng, decay2, 8),
+            jitter_rate(rng, release, 8),
+        ],
+        [
+            Level::new(peak),
+            jitter_level(rng, level2, 5),
+            jitter_level(rng, sustain, 8),
+            Level::new(0),
+        ])
+}
+
+// Makes the envelope of a modulator operator.
+fn make_modulator_eg<R: Rng + ?Sized>(rng: &mut R, params: &EnvelopeParameters) -> Envelope {
+    // Twang makes the modulation fall off after the attack,
+    // so the tone gets darker over time. Hardness affects the attack
+    // as with carriers, but modulators may also be a little slower.
+    let attack = scale(params.hardness, 30, 99);
+    let decay1 = scale(params.twang, 50, 90) - scale(params.longness, 0, 20);
+    let decay2 = scale(params.twang, 30, 75) - scale(params.longness, 0, 25);
+    let release = scale(params.longness, 80, 30);
+
+    let level2 = scale(params.twang, 95, 60);
+    let sustain = scale(params.twang, 95, 10);
+
+    Envelope::new_rate_level(
+        [
+            jitter_rate(rng, attack, 10),
+            jitter_rate(rng, decay1, 10),
+            jitter_rate(rng, decay2, 10),
+            jitter_rate(rng, release, 10),
+        ],
+        [
+            jitter_level(rng, 99, 5),
+            jitter_level(rng, level2, 8),
+            jitter_level(rng, sustain, 10),
+            Level::new(0),
+        ])
+}
+
+// Makes the keyboard level scaling of an operator. Modulators are
+// tamed towards the high end of the keyboard unless the voice is very bright.
+fn make_kls<R: Rng + ?Sized>(rng: &mut R, params: &TimbreParameters, carrier: bool) -> KeyboardLevelScaling {
+    let breakpoint = Key::new(jitter(rng, Key::DEFAULT, 12, Key::FIRST, Key::LAST));
+
+    if carrier {
+        return KeyboardLevelScaling {
+            breakpoint,
+            left: Scaling { depth: Level::new(0), curve: ScalingCurve::lin_neg() },
+            right: Scaling { depth: jitter_level(rng, 10, 10), curve: ScalingCurve::lin_neg() },
+        };
+    }
+
+    let right_depth = scale(params.brightness, 60, 0);
+    KeyboardLevelScaling {
+        breakpoint,
+        left: Scaling {
+            depth: jitter_level(rng, scale(params.brightness, 0, 20), 10),
+            curve: ScalingCurve::lin_neg()
+        },
+        right: Scaling {
+            depth: jitter_level(rng, right_depth, 10),
+            curve: if rng.random_bool(0.5) { ScalingCurve::exp_neg() } else { ScalingCurve::lin_neg() }
+        },
+    }
+}
+
+// Sets the frequency of the operator based on the atonality.
+// Low atonality gives harmonic integer ratios, high atonality
+// adds fine tuning, detune and even fixed frequencies.
+fn set_frequency<R: Rng + ?Sized>(rng: &mut R, params: &TimbreParameters, carrier: bool, voice: &mut Voice, index: usize) {
+    let atonality = params.atonality.value() as f64 / 99.0;
+    let op = &mut voice.operators[index];
+
+    op.mode = OperatorMode::Ratio;
+    op.coarse = if carrier {
+        Coarse::new(1)
+    } else {
+        // Brighter voices get higher modulator ratios.
+        let max_index = scale(params.brightness, 3, (HARMONIC_RATIOS.len() - 1) as i32) as usize;
+        Coarse::new(HARMONIC_RATIOS[rng.random_range(0..=max_index)])
+    };
+    op.fine = Level::new(0);
+    op.detune = Detune::new(jitter(rng, 0, scale(params.atonality, 1, 7), Detune::FIRST, Detune::LAST));
+
+    if rng.random_bool(atonality * 0.8) {
+        let coarse = jitter(rng, op.coarse.value(), scale(params.atonality, 0, 8), 1, Coarse::LAST);
+        op.coarse = Coarse::new(coarse);
+        op.fine = Level::new(rng.random_range(0..=scale(params.atonality, 0, 99)));
+    }
+
+    if !carrier && rng.random_bool(atonality * atonality * 0.3) {
+        op.mode = OperatorMode::Fixed;
+        op.coarse = Coarse::new(rng.random_range(0..=3));
+    }
+}
+
+/// Makes a new voice based on the randomization parameters.
+pub fn randomize<R: Rng + ?Sized>(params: RandomizationParameters, rng: &mut R) -> Voice {
+
+    let algorithm = select_algorithm(rng, params.timbre.complexity);
+    debug!("Algorithm = {}", algorithm.value());
 
     let mut voice = Voice::new();
     voice.alg = algorithm;
+    voice.name = random_voice_name(rng);
+
+    // Feedback follows the complexity.
+    voice.feedback = jitter_depth(rng, scale(params.timbre.complexity, 0, 6), 1);
+
+    for index in 0..OPERATOR_COUNT {
+        let carrier = is_carrier(algorithm, index + 1);
+
+        set_frequency(rng, &params.timbre, carrier, &mut voice, index);
 
-    // Set operator levels. Carriers should be well audible.
-    let carriers = &carrier_lookup[(algorithm.value() - 1) as usize];
-    for carrier_op in carriers.iter() {
-        voice.operators[carrier_op - 1].output_level = Level::new(rng.random_range(90..=99));
+        let op = &mut voice.operators[index];
+
+        // Set operator levels. Carriers should be well audible,
+        // the level of the modulators depends on the brightness.
+        op.output_level = if carrier {
+            Level::new(rng.random_range(90..=99))
+        } else {
+            jitter_level(rng, scale(params.timbre.brightness, 45, 90), 8)
+        };
+
+        op.eg = if carrier {
+            make_carrier_eg(rng, &params.envelope)
+        } else {
+            make_modulator_eg(rng, &params.envelope)
+        };
+
+        op.kbd_level_scaling = make_kls(rng, &params.timbre, carrier);
+
+        // Longer sounds scale less with the keyboard.
+        op.kbd_rate_scaling = jitter_depth(rng, scale(params.envelope.longness, 4, 0), 1);
+
+        // Velocity affects modulators more than carriers, which
+        // makes harder playing brighter.
+        op.key_vel_sens = if carrier {
+            jitter_depth(rng, scale(params.movement.velocity, 0, 5), 1)
+        } else {
+            jitter_depth(rng, scale(params.movement.velocity, 0, 7), 1)
+        };
+
+        // Wubble is amplitude modulation, applied to the carriers.
+        op.amp_mod_sens = if carrier {
+            Sensitivity::new(scale(params.movement.wubble, 0, 3))
+        } else {
+            Sensitivity::new(0)
+        };
     }
 
-    // ...and so on... (WIP)
+    // The pitch EG stays flat, otherwise the voice would go out of tune.
+    // Only a hard twangy voice gets a little pitch drop at the start.
+    let pitch_start = 50 + scale(params.envelope.twang, 0, 4) * scale(params.envelope.hardness, 0, 1);
+    voice.peg = Envelope::new_rate_level(
+        [Rate::new(99), jitter_rate(rng, 80, 10), Rate::new(99), Rate::new(99)],
+        [Level::new(pitch_start), Level::new(50), Level::new(50), Level::new(50)]);
+
+    // Wobble is pitch modulation, wubble is amplitude modulation.
+    // Both make the LFO a little faster.
+    let movement = cmp::max(params.movement.wobble.value(), params.movement.wubble.value());
+    voice.lfo = Lfo {
+        speed: jitter_level(rng, scale(Level::new(movement), 20, 45), 8),
+        delay: jitter_level(rng, scale(params.envelope.longness, 0, 40), 10),
+        pmd: Level::new(scale(params.movement.wobble, 0, 20)),
+        amd: Level::new(scale(params.movement.wubble, 0, 40)),
+        sync: false,
+        waveform: if rng.random_bool(0.8) { LfoWaveform::Sine } else { LfoWaveform::Triangle },
+    };
+    voice.pitch_mod_sens = Depth::new(scale(params.movement.wobble, 0, 5));
 
     voice
 }
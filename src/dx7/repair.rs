@@ -0,0 +1,394 @@
+//! Recovering voices from damaged or nonstandard DX7 bulk dumps.
+//!
+//! Files found online often have wrong checksums, a missing F7,
+//! stray bytes around the message, wrong byte counts in the header,
+//! or no header at all. The functions here find out what is wrong,
+//! and salvage as much of the voice data as possible.
+
+use std::fmt;
+
+use syxpack::{INITIATOR, TERMINATOR};
+
+use sevenate::Ranged;
+
+use sevenate::dx7::voice::{Voice, VOICE_PACKED_SIZE};
+use sevenate::dx7::cartridge::{Cartridge, VOICE_COUNT};
+use sevenate::dx7::sysex::{MIDIChannel, SystemExclusiveData, checksum};
+
+use crate::dx7::{Document, is_name_character, make_init_voice};
+
+/// The Yamaha manufacturer ID.
+const YAMAHA: u8 = 0x43;
+
+/// Format byte of a single voice bulk dump.
+const VOICE_FORMAT: u8 = 0;
+
+/// Format byte of a cartridge bulk dump.
+const CARTRIDGE_FORMAT: u8 = 9;
+
+/// Something wrong in a bulk dump.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    /// Raw voice or cartridge data without the System Exclusive message around it
+    NoHeader { size: usize },
+    /// Bytes before the start of the message
+    StrayBytesBefore(usize),
+    /// Bytes after the end of the message
+    StrayBytesAfter(usize),
+    /// The message does not end with F7
+    MissingTerminator,
+    /// The format byte is not 0 or 9, or does not match the amount of data
+    WrongFormat { found: u8, expected: u8 },
+    /// The byte count in the header is wrong
+    WrongByteCount { found: usize, expected: usize },
+    /// There is more data than the format has
+    TooMuchData { found: usize, expected: usize },
+    /// There is less data than the format has, and the missing voices were initialized
+    TooLittleData { found: usize, expected: usize, voices: usize },
+    /// The checksum byte is missing
+    MissingChecksum,
+    /// The checksum does not match the data
+    WrongChecksum { found: u8, expected: u8 },
+    /// Data bytes with the high bit set, which is not allowed in System Exclusive data
+    HighBitSet(usize),
+    /// A parameter value out of range in a voice
+    OutOfRange { voice: usize, parameter: String, value: u8, maximum: u8 },
+    /// A character in the name of a voice that is not printable ASCII
+    InvalidCharacter { voice: usize, position: usize, value: u8 },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Problem::NoHeader { size } =>
+                write!(f, "{} bytes of raw data without a System Exclusive header", size),
+            Problem::StrayBytesBefore(count) =>
+                write!(f, "{} stray byte(s) before the message", count),
+            Problem::StrayBytesAfter(count) =>
+                write!(f, "{} stray byte(s) after the message", count),
+            Problem::MissingTerminator =>
+                write!(f, "the message does not end with F7"),
+            Problem::WrongFormat { found, expected } =>
+                write!(f, "format byte is {}, but the data is format {}", found, expected),
+            Problem::WrongByteCount { found, expected } =>
+                write!(f, "byte count in the header is {}, expected {}", found, expected),
+            Problem::TooMuchData { found, expected } =>
+                write!(f, "{} bytes of data, expected {}", found, expected),
+            Problem::TooLittleData { found, expected, voices } =>
+                write!(f, "only {} bytes of data, expected {} ({} complete voices)", found, expected, voices),
+            Problem::MissingChecksum =>
+                write!(f, "the checksum is missing"),
+            Problem::WrongChecksum { found, expected } =>
+                write!(f, "checksum is {:02X}H, expected {:02X}H", found, expected),
+            Problem::HighBitSet(count) =>
+                write!(f, "{} data byte(s) have the high bit set", count),
+            Problem::OutOfRange { voice, parameter, value, maximum } =>
+                write!(f, "voice {}: {} is {}, expected 0...{}", voice, parameter, value, maximum),
+            Problem::InvalidCharacter { voice, position, value } =>
+                write!(f, "voice {}: name character {} is {:02X}H, not printable ASCII", voice, position, value),
+        }
+    }
+}
+
+/// The result of salvaging a bulk dump: the voice or the cartridge,
+/// the MIDI channel from the header, and the problems that were found.
+#[derive(Debug)]
+pub struct Salvaged {
+    pub document: Document,
+    pub channel: MIDIChannel,
+    pub problems: Vec<Problem>,
+}
+
+/// Splits data into parts that each start with F0, for salvaging
+/// one by one. Bytes before the first F0 stay with the first part,
+/// and bytes after an F7 stay with the part they follow, so that
+/// they can be reported. Data without any F0 is a single part.
+pub fn split_parts(data: &[u8]) -> Vec<&[u8]> {
+    let mut starts: Vec<usize> = data.iter()
+        .enumerate()
+        .filter(|(_, &b)| b == INITIATOR)
+        .map(|(offset, _)| offset)
+        .collect();
+
+    if starts.is_empty() {
+        return vec![data];
+    }
+
+    starts[0] = 0;
+    starts.push(data.len());
+    starts.windows(2).map(|w| &data[w[0]..w[1]]).collect()
+}
+
+/// Salvages a single voice or cartridge bulk dump, or raw voice or
+/// cartridge data without a header. Fails only if there is nothing
+/// that could be salvaged.
+pub fn salvage(data: &[u8]) -> Result<Salvaged, String> {
+    let mut problems = Vec::<Problem>::new();
+
+    let Some(start) = data.iter().position(|&b| b == INITIATOR) else {
+        return salvage_raw(data);
+    };
+    if start > 0 {
+        problems.push(Problem::StrayBytesBefore(start));
+    }
+
+    let message = &data[start..];
+    if message.len() < 6 {
+        return Err(format!("Message is too short ({} bytes)", message.len()));
+    }
+    if message[1] != YAMAHA {
+        return Err(format!("Not a Yamaha message (manufacturer ID {:02X}H)", message[1]));
+    }
+
+    let channel = MIDIChannel::new((message[2] & 0x0f) as i32 + 1);
+    let format = message[3];
+    let byte_count = ((message[4] as usize) << 7) | message[5] as usize;
+
+    // The body is the data and the checksum, up to F7 or the end.
+    let body = match message.iter().position(|&b| b == TERMINATOR) {
+        Some(end) => {
+            if end + 1 < message.len() {
+                problems.push(Problem::StrayBytesAfter(message.len() - end - 1));
+            }
+            &message[6..end]
+        },
+        None => {
+            problems.push(Problem::MissingTerminator);
+            &message[6..]
+        }
+    };
+
+    // Trust the amount of data over the format byte, if they disagree
+    // and the amount matches the other format.
+    let expected_format = match format {
+        VOICE_FORMAT if matches_size(body.len(), Cartridge::DATA_SIZE) => CARTRIDGE_FORMAT,
+        CARTRIDGE_FORMAT if matches_size(body.len(), Voice::DATA_SIZE) => VOICE_FORMAT,
+        VOICE_FORMAT | CARTRIDGE_FORMAT => format,
+        _ if body.len() >= Cartridge::DATA_SIZE => CARTRIDGE_FORMAT,
+        _ => VOICE_FORMAT,
+    };
+    if format != expected_format {
+        problems.push(Problem::WrongFormat { found: format, expected: expected_format });
+    }
+
+    let size = if expected_format == CARTRIDGE_FORMAT { Cartridge::DATA_SIZE } else { Voice::DATA_SIZE };
+    if byte_count != size {
+        problems.push(Problem::WrongByteCount { found: byte_count, expected: size });
+    }
+
+    let mut data = body.to_vec();
+    if data.len() > size + 1 {
+        problems.push(Problem::TooMuchData { found: data.len() - 1, expected: size });
+    }
+
+    if data.len() == size {
+        problems.push(Problem::MissingChecksum);
+    } else if data.len() > size {
+        let found = data[size];
+        data.truncate(size);
+        let expected = checksum(&clear_high_bits(&data).0);
+        if found != expected {
+            problems.push(Problem::WrongChecksum { found, expected });
+        }
+    }
+
+    let (data, count) = clear_high_bits(&data);
+    if count > 0 {
+        problems.push(Problem::HighBitSet(count));
+    }
+
+    let document = if expected_format == CARTRIDGE_FORMAT {
+        Document::Cartridge(salvage_cartridge(&data, &mut problems))
+    } else {
+        if data.len() < size {
+            return Err(format!("Only {} bytes of voice data, expected {}", data.len(), size));
+        }
+        Document::Voice(Box::new(salvage_voice(&data, 1, &mut problems)))
+    };
+
+    Ok(Salvaged { document, channel, problems })
+}
+
+// Checks if the amount of data matches a format, with or without the checksum.
+fn matches_size(length: usize, size: usize) -> bool {
+    length == size || length == size + 1
+}
+
+// Salvages raw voice or cartridge data, detected by its size.
+fn salvage_raw(data: &[u8]) -> Result<Salvaged, String> {
+    let mut problems = vec![Problem::NoHeader { size: data.len() }];
+    let channel = MIDIChannel::new(1);
+
+    let (data, count) = clear_high_bits(data);
+    if count > 0 {
+        problems.push(Problem::HighBitSet(count));
+    }
+
+    let document = if data.len() == Cartridge::DATA_SIZE {
+        Document::Cartridge(salvage_cartridge(&data, &mut problems))
+    } else if data.len() == Voice::DATA_SIZE {
+        Document::Voice(Box::new(salvage_voice(&data, 1, &mut problems)))
+    } else if data.len() == VOICE_PACKED_SIZE {
+        let voice_data = Voice::unpack(&data);
+        Document::Voice(Box::new(salvage_voice(&voice_data, 1, &mut problems)))
+    } else {
+        return Err(format!("No System Exclusive message, and {} bytes is not the size of \
+            a voice ({} or {} bytes) or a cartridge ({} bytes)",
+            data.len(), Voice::DATA_SIZE, VOICE_PACKED_SIZE, Cartridge::DATA_SIZE));
+    };
+
+    Ok(Salvaged { document, channel, problems })
+}
+
+// Salvages the packed voices of a cartridge. If there is too little data,
+// the voices that are not complete are initialized.
+fn salvage_cartridge(data: &[u8], problems: &mut Vec<Problem>) -> Cartridge {
+    let complete = (data.len() / VOICE_PACKED_SIZE).min(VOICE_COUNT);
+    if data.len() < Cartridge::DATA_SIZE {
+        problems.push(Problem::TooLittleData {
+            found: data.len(),
+            expected: Cartridge::DATA_SIZE,
+            voices: complete,
+        });
+    }
+
+    let mut voices = Vec::<Voice>::new();
+    for (index, packed) in data.chunks_exact(VOICE_PACKED_SIZE).take(complete).enumerate() {
+        let voice_data = Voice::unpack(packed);
+        voices.push(salvage_voice(&voice_data, index + 1, problems));
+    }
+    while voices.len() < VOICE_COUNT {
+        voices.push(make_init_voice());
+    }
+
+    Cartridge { voices }
+}
+
+// Parses unpacked voice data after bringing every parameter into its range.
+fn salvage_voice(data: &[u8], number: usize, problems: &mut Vec<Problem>) -> Voice {
+    let (data, mut found) = clamp_voice_data(&data[..Voice::DATA_SIZE], number);
+    problems.append(&mut found);
+    Voice::parse(&data).expect("voice data should be valid after clamping")
+}
+
+/// Checks unpacked voice data for values that are not valid in a voice:
+/// parameters out of range, and name characters that are not printable
+/// ASCII. The data must be at least 155 bytes.
+pub fn check_voice_data(data: &[u8], number: usize) -> Vec<Problem> {
+    let (_, problems) = clamp_voice_data(&data[..Voice::DATA_SIZE], number);
+    problems
+}
+
+// Clears the high bit of every byte, and returns the result
+// with the number of bytes that had it set.
+fn clear_high_bits(data: &[u8]) -> (Vec<u8>, usize) {
+    let count = data.iter().filter(|&&b| b > 0x7f).count();
+    (data.iter().map(|b| b & 0x7f).collect(), count)
+}
+
+// The names and maximum values of the 21 parameters of an operator
+// in unpacked voice data.
+const OPERATOR_PARAMETERS: [(&str, u8); 21] = [
+    ("EG rate 1", 99), ("EG rate 2", 99), ("EG rate 3", 99), ("EG rate 4", 99),
+    ("EG level 1", 99), ("EG level 2", 99), ("EG level 3", 99), ("EG level 4", 99),
+    ("breakpoint", 99), ("left depth", 99), ("right depth", 99),
+    ("left curve", 3), ("right curve", 3),
+    ("rate scaling", 7), ("amplitude modulation sensitivity", 3), ("key velocity sensitivity", 7),
+    ("output level", 99), ("mode", 1), ("coarse", 31), ("fine", 99), ("detune", 14),
+];
+
+// The names and maximum values of the voice parameters after
+// the operators, not including the name.
+const VOICE_PARAMETERS: [(&str, u8); 19] = [
+    ("PEG rate 1", 99), ("PEG rate 2", 99), ("PEG rate 3", 99), ("PEG rate 4", 99),
+    ("PEG level 1", 99), ("PEG level 2", 99), ("PEG level 3", 99), ("PEG level 4", 99),
+    ("algorithm", 31), ("feedback", 7), ("oscillator sync", 1),
+    ("LFO speed", 99), ("LFO delay", 99), ("LFO PMD", 99), ("LFO AMD", 99),
+    ("LFO sync", 1), ("LFO waveform", 5), ("pitch modulation sensitivity", 7),
+    ("transpose", 48),
+];
+
+// Offset of the name in unpacked voice data.
+pub(crate) const NAME_OFFSET: usize = 145;
+
+// Gets the names and maximum values of the parameters in unpacked
+// voice data, in order, not including the name.
+pub(crate) fn voice_parameters() -> Vec<(String, u8)> {
+    // The operators are in reverse order, OP6 first.
+    let mut parameters = Vec::<(String, u8)>::new();
+    for op in (1..=6).rev() {
+        for (name, maximum) in OPERATOR_PARAMETERS {
+            parameters.push((format!("OP{} {}", op, name), maximum));
+        }
+    }
+    for (name, maximum) in VOICE_PARAMETERS {
+        parameters.push((name.to_string(), maximum));
+    }
+    parameters
+}
+
+// Brings the parameters of unpacked voice data into range, and
+// replaces characters that are not printable ASCII in the name
+// with spaces. Returns the result and the problems found.
+fn clamp_voice_data(data: &[u8], number: usize) -> (Vec<u8>, Vec<Problem>) {
+    let mut result = data.to_vec();
+    let mut problems = Vec::<Problem>::new();
+    for (value, (parameter, maximum)) in result.iter_mut().zip(voice_parameters()) {
+        if *value > maximum {
+            problems.push(Problem::OutOfRange { voice: number, parameter, value: *value, maximum });
+            *value = maximum;
+        }
+    }
+
+    for (index, c) in result[NAME_OFFSET..].iter_mut().enumerate() {
+        if !is_name_character(*c as char) {
+            problems.push(Problem::InvalidCharacter { voice: number, position: index + 1, value: *c });
+            *c = b' ';
+        }
+    }
+
+    (result, problems)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dx7::{make_voice_message, parse_bulk_dump};
+    use crate::error::Error;
+
+    // Makes a single voice bulk dump of an initialized voice,
+    // with the given character in the first position of the name.
+    fn voice_message_with_name_character(c: u8) -> Vec<u8> {
+        let mut message = make_voice_message(&make_init_voice(), MIDIChannel::new(1)).to_bytes();
+        let name = 6 + NAME_OFFSET;
+        message[name] = c;
+        let last = message.len() - 2;
+        message[last] = checksum(&message[6..last]);
+        message
+    }
+
+    #[test]
+    fn flags_characters_that_are_not_printable() {
+        for c in [0x01, 0x1f, 0x7f] {
+            let mut data = make_init_voice().to_bytes();
+            data[NAME_OFFSET] = c;
+            let problems = check_voice_data(&data, 1);
+            assert_eq!(problems, vec![Problem::InvalidCharacter { voice: 1, position: 1, value: c }]);
+        }
+    }
+
+    #[test]
+    fn loading_and_salvaging_agree_on_names() {
+        for c in [0x01, 0x7f] {
+            let message = voice_message_with_name_character(c);
+            assert!(matches!(parse_bulk_dump(&message), Err(Error::Range(_))));
+
+            let salvaged = salvage(&message).unwrap();
+            assert_eq!(salvaged.problems.len(), 1);
+        }
+
+        let message = voice_message_with_name_character(b'~');
+        assert!(parse_bulk_dump(&message).is_ok());
+        assert!(salvage(&message).unwrap().problems.is_empty());
+    }
+}
@@ -0,0 +1,454 @@
+//! The additional voice data of the DX7II and the TX802, which extends
+//! each DX7 voice with parameters that the DX7 does not have, like
+//! fractional scaling, the pitch EG range, random pitch, unison,
+//! and the pitch bend and aftertouch settings.
+//!
+//! The DX7II sends the supplement of a single voice (ACED, format 5,
+//! 49 bytes) before the voice itself (VCED), and the supplements of
+//! all the 32 voices (AMEM, format 6, 35 bytes per voice) before
+//! the cartridge (VMEM). The supplements are paired with the voices
+//! by their order.
+//!
+//! Bytes 33...48 of the supplement are not used by the parameters here.
+//! They are kept as they are, so that a supplement makes a round trip
+//! through the other formats without changes.
+
+use xml_builder::XMLElement;
+
+use syxpack::{INITIATOR, TERMINATOR};
+
+use sevenate::dx7::cartridge::VOICE_COUNT;
+use sevenate::dx7::voice::Voice;
+use sevenate::dx7::sysex::{MIDIChannel, checksum};
+
+use crate::dx7::{
+    Document,
+    find_messages,
+    parse_bulk_dump,
+    make_voice_message,
+    make_cartridge_message,
+};
+use crate::error::Error;
+use crate::xml::{Element, ToXml, XmlError, XmlErrorKind, parse_document};
+
+/// Size of the supplement data of a single voice (ACED).
+pub const SUPPLEMENT_SIZE: usize = 49;
+
+/// Size of the packed supplement data of one voice in a bank (AMEM).
+pub const SUPPLEMENT_PACKED_SIZE: usize = 35;
+
+/// Bulk dump format of a single voice supplement.
+pub const ACED_FORMAT: u8 = 5;
+
+/// Bulk dump format of the supplements of 32 voices.
+pub const AMEM_FORMAT: u8 = 6;
+
+/// Offset of the bytes that are kept as they are.
+pub const RESERVED_OFFSET: usize = 33;
+
+/// The Yamaha manufacturer ID.
+const YAMAHA: u8 = 0x43;
+
+/// A supplement parameter: its name, the name of its XML attribute,
+/// and its maximum value. All the parameters start from zero.
+#[derive(Debug)]
+pub struct Parameter {
+    pub name: &'static str,
+    pub attribute: &'static str,
+    pub maximum: u8,
+}
+
+const fn parameter(name: &'static str, attribute: &'static str, maximum: u8) -> Parameter {
+    Parameter { name, attribute, maximum }
+}
+
+/// The parameters of the supplement, in the order they are in the ACED data.
+pub const PARAMETERS: [Parameter; RESERVED_OFFSET] = [
+    parameter("OP6 scaling mode", "op6ScalingMode", 1),  // 0 = normal, 1 = fractional
+    parameter("OP5 scaling mode", "op5ScalingMode", 1),
+    parameter("OP4 scaling mode", "op4ScalingMode", 1),
+    parameter("OP3 scaling mode", "op3ScalingMode", 1),
+    parameter("OP2 scaling mode", "op2ScalingMode", 1),
+    parameter("OP1 scaling mode", "op1ScalingMode", 1),
+    parameter("OP6 amplitude modulation sensitivity", "op6AmplitudeModulationSensitivity", 7),
+    parameter("OP5 amplitude modulation sensitivity", "op5AmplitudeModulationSensitivity", 7),
+    parameter("OP4 amplitude modulation sensitivity", "op4AmplitudeModulationSensitivity", 7),
+    parameter("OP3 amplitude modulation sensitivity", "op3AmplitudeModulationSensitivity", 7),
+    parameter("OP2 amplitude modulation sensitivity", "op2AmplitudeModulationSensitivity", 7),
+    parameter("OP1 amplitude modulation sensitivity", "op1AmplitudeModulationSensitivity", 7),
+    parameter("pitch EG range", "pitchEgRange", 3),  // 8 octaves, 2 octaves, 1 octave, 1/2 octave
+    parameter("LFO key trigger", "lfoKeyTrigger", 1),
+    parameter("pitch EG by velocity", "pitchEgVelocity", 1),
+    parameter("poly/mono mode", "polyMonoMode", 3),  // poly, mono, unison poly, unison mono
+    parameter("pitch bend range", "pitchBendRange", 12),
+    parameter("pitch bend step", "pitchBendStep", 12),
+    parameter("pitch bend mode", "pitchBendMode", 3),  // normal, low, high, key on
+    parameter("random pitch", "randomPitch", 7),
+    parameter("portamento mode", "portamentoMode", 1),  // retain or follow, fingered or full time
+    parameter("portamento step", "portamentoStep", 12),
+    parameter("portamento time", "portamentoTime", 99),
+    parameter("modulation wheel range", "modulationWheelRange", 99),
+    parameter("modulation wheel assign", "modulationWheelAssign", 7),  // bits for pitch, amplitude, EG bias
+    parameter("foot controller range", "footControllerRange", 99),
+    parameter("foot controller assign", "footControllerAssign", 7),
+    parameter("breath controller range", "breathControllerRange", 99),
+    parameter("breath controller assign", "breathControllerAssign", 7),
+    parameter("aftertouch range", "aftertouchRange", 99),
+    parameter("aftertouch assign", "aftertouchAssign", 7),
+    parameter("breath controller pitch bias", "breathControllerPitchBias", 100),  // 50 = no bias
+    parameter("breath controller EG bias", "breathControllerEgBias", 99),
+];
+
+/// Where each parameter goes in the packed data: the byte, the first bit,
+/// and the number of bits. The reserved bytes follow unchanged, from the
+/// byte `PACKED_RESERVED_OFFSET`.
+const PACKING: [(usize, u8, u8); RESERVED_OFFSET] = [
+    (0, 0, 1), (0, 1, 1), (0, 2, 1), (0, 3, 1), (0, 4, 1), (0, 5, 1),
+    (1, 0, 3), (1, 3, 3), (2, 0, 3), (2, 3, 3), (3, 0, 3), (3, 3, 3),
+    (4, 0, 2), (4, 2, 1), (4, 3, 1), (4, 4, 2),
+    (5, 0, 7),
+    (6, 0, 7),
+    (7, 0, 2), (7, 2, 3), (7, 5, 1),
+    (8, 0, 7),
+    (9, 0, 7),
+    (10, 0, 7),
+    (11, 0, 3),
+    (12, 0, 7),
+    (11, 3, 3),
+    (13, 0, 7),
+    (14, 0, 3),
+    (15, 0, 7),
+    (14, 3, 3),
+    (16, 0, 7),
+    (17, 0, 7),
+];
+
+const PACKED_RESERVED_OFFSET: usize = 18;
+
+/// The supplement data of one voice, as in the ACED bulk dump.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Supplement {
+    pub data: [u8; SUPPLEMENT_SIZE],
+}
+
+impl Supplement {
+    /// Makes a supplement with the initial values of the DX7II:
+    /// a pitch bend range of 2 and no breath controller pitch bias.
+    pub fn new() -> Self {
+        let mut supplement = Supplement { data: [0; SUPPLEMENT_SIZE] };
+        supplement.data[16] = 2;
+        supplement.data[31] = 50;
+        supplement
+    }
+
+    /// Parses the ACED data of a supplement. The voice number is for
+    /// the error messages. Values out of range are errors.
+    pub fn parse(data: &[u8], number: usize) -> Result<Self, Error> {
+        if data.len() != SUPPLEMENT_SIZE {
+            return Err(Error::Header(format!("Got {} bytes of supplement data, expected {}",
+                data.len(), SUPPLEMENT_SIZE)));
+        }
+
+        for (parameter, &value) in PARAMETERS.iter().zip(data) {
+            if value > parameter.maximum {
+                return Err(Error::Range(format!("voice {}: {} is {}, expected 0...{}",
+                    number, parameter.name, value, parameter.maximum)));
+            }
+        }
+        if let Some(offset) = data[RESERVED_OFFSET..].iter().position(|&b| b > 0x7f) {
+            return Err(Error::Range(format!("voice {}: supplement byte {} is {:02X}H, not a data byte",
+                number, RESERVED_OFFSET + offset, data[RESERVED_OFFSET + offset])));
+        }
+
+        let mut supplement = Supplement { data: [0; SUPPLEMENT_SIZE] };
+        supplement.data.copy_from_slice(data);
+        Ok(supplement)
+    }
+
+    /// Unpacks the AMEM data of a supplement, and parses it.
+    pub fn unpack(packed: &[u8], number: usize) -> Result<Self, Error> {
+        if packed.len() != SUPPLEMENT_PACKED_SIZE {
+            return Err(Error::Header(format!("Got {} bytes of packed supplement data, expected {}",
+                packed.len(), SUPPLEMENT_PACKED_SIZE)));
+        }
+
+        let mut data = [0u8; SUPPLEMENT_SIZE];
+        for (value, &(byte, bit, bits)) in data.iter_mut().zip(&PACKING) {
+            *value = (packed[byte] >> bit) & ((1 << bits) - 1);
+        }
+        data[RESERVED_OFFSET..].copy_from_slice(
+            &packed[PACKED_RESERVED_OFFSET .. PACKED_RESERVED_OFFSET + SUPPLEMENT_SIZE - RESERVED_OFFSET]);
+        Supplement::parse(&data, number)
+    }
+
+    /// Gets the ACED data of the supplement.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.data.to_vec()
+    }
+
+    /// Packs the supplement into the AMEM data.
+    pub fn pack(&self) -> Vec<u8> {
+        let mut packed = vec![0u8; SUPPLEMENT_PACKED_SIZE];
+        for (&value, &(byte, bit, bits)) in self.data.iter().zip(&PACKING) {
+            packed[byte] |= (value & ((1 << bits) - 1)) << bit;
+        }
+        packed[PACKED_RESERVED_OFFSET .. PACKED_RESERVED_OFFSET + SUPPLEMENT_SIZE - RESERVED_OFFSET]
+            .copy_from_slice(&self.data[RESERVED_OFFSET..]);
+        packed
+    }
+}
+
+impl Default for Supplement {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Gets the format of a message if it is a DX7II supplement bulk dump,
+/// either `ACED_FORMAT` or `AMEM_FORMAT`. Does not check the rest.
+pub fn supplement_format(message: &[u8]) -> Option<u8> {
+    if message.len() > 6 && message[1] == YAMAHA && message[2] & 0xf0 == 0
+        && (message[3] == ACED_FORMAT || message[3] == AMEM_FORMAT) {
+        Some(message[3])
+    } else {
+        None
+    }
+}
+
+/// Parses an ACED or AMEM bulk dump, after checking its header, byte count
+/// and checksum. Returns one supplement for ACED, and 32 for AMEM.
+pub fn parse_supplement_dump(message: &[u8]) -> Result<Vec<Supplement>, Error> {
+    if message.len() < 8 || message[0] != INITIATOR || message[message.len() - 1] != TERMINATOR {
+        return Err(Error::SysEx(format!("Not a complete System Exclusive message ({} bytes)", message.len())));
+    }
+
+    let Some(format) = supplement_format(message) else {
+        return Err(Error::Header(String::from("Not a DX7II supplement bulk dump")));
+    };
+
+    let expected_size = if format == ACED_FORMAT {
+        SUPPLEMENT_SIZE
+    } else {
+        VOICE_COUNT * SUPPLEMENT_PACKED_SIZE
+    };
+    let byte_count = ((message[4] as usize) << 7) | message[5] as usize;
+    let data = &message[6..message.len() - 2];
+    if byte_count != data.len() || data.len() != expected_size {
+        return Err(Error::Header(format!("Byte count is {}, got {} bytes, expected {} bytes",
+            byte_count, data.len(), expected_size)));
+    }
+
+    let expected = checksum(data);
+    let found = message[message.len() - 2];
+    if found != expected {
+        return Err(Error::Checksum { found, expected });
+    }
+
+    if format == ACED_FORMAT {
+        Ok(vec![Supplement::parse(data, 1)?])
+    } else {
+        data.chunks_exact(SUPPLEMENT_PACKED_SIZE)
+            .enumerate()
+            .map(|(index, packed)| Supplement::unpack(packed, index + 1))
+            .collect()
+    }
+}
+
+// Makes a supplement bulk dump message with the header and checksum.
+fn make_message(format: u8, data: &[u8], channel: MIDIChannel) -> Vec<u8> {
+    let mut message = vec![
+        INITIATOR,
+        YAMAHA,
+        channel.as_byte(),
+        format,
+        ((data.len() >> 7) & 0x7f) as u8,
+        (data.len() & 0x7f) as u8,
+    ];
+    message.extend(data);
+    message.push(checksum(data));
+    message.push(TERMINATOR);
+    message
+}
+
+/// Makes an ACED bulk dump message with the supplement of a single voice.
+pub fn make_aced_message(supplement: &Supplement, channel: MIDIChannel) -> Vec<u8> {
+    make_message(ACED_FORMAT, &supplement.to_bytes(), channel)
+}
+
+/// Makes an AMEM bulk dump message with the supplements of 32 voices.
+pub fn make_amem_message(supplements: &[Supplement], channel: MIDIChannel) -> Vec<u8> {
+    let data: Vec<u8> = supplements.iter().flat_map(Supplement::pack).collect();
+    make_message(AMEM_FORMAT, &data, channel)
+}
+
+/// A voice or a cartridge, with the DX7II supplements of its voices.
+/// There are no supplements for DX7 data, one for a single voice,
+/// and 32 for a cartridge.
+#[derive(Debug)]
+pub struct SupplementedDocument {
+    pub document: Document,
+    pub supplements: Vec<Supplement>,
+}
+
+impl SupplementedDocument {
+    // Checks that there is a supplement for each voice, if there are any.
+    fn check(self) -> Result<Self, Error> {
+        let voice_count = match &self.document {
+            Document::Voice(_) => 1,
+            Document::Cartridge(cartridge) => cartridge.voices.len(),
+        };
+        if !self.supplements.is_empty() && self.supplements.len() != voice_count {
+            return Err(Error::Header(format!("Got supplements for {} voices, expected {}",
+                self.supplements.len(), voice_count)));
+        }
+        Ok(self)
+    }
+}
+
+/// Parses data with a voice or cartridge bulk dump, and optionally
+/// the matching ACED or AMEM bulk dump, in either order.
+pub fn parse_supplemented(data: &[u8]) -> Result<SupplementedDocument, Error> {
+    let messages = find_messages(data);
+    let (supplement_messages, voice_messages): (Vec<_>, Vec<_>) = messages.iter()
+        .map(|(_, message)| *message)
+        .partition(|message| supplement_format(message).is_some());
+
+    let [voice_message] = voice_messages.as_slice() else {
+        return Err(Error::SysEx(format!("Expected one voice or cartridge bulk dump, found {}", voice_messages.len())));
+    };
+    let document = parse_bulk_dump(voice_message)?;
+
+    let supplements = match supplement_messages.as_slice() {
+        [] => Vec::new(),
+        [message] => parse_supplement_dump(message)?,
+        _ => return Err(Error::SysEx(format!("Expected at most one supplement bulk dump, found {}", supplement_messages.len()))),
+    };
+
+    SupplementedDocument { document, supplements }.check()
+}
+
+/// Makes the bulk dumps of a voice or a cartridge with its supplements,
+/// the supplements first like the DX7II sends them. Without supplements,
+/// only the voice or cartridge bulk dump is made.
+pub fn supplemented_to_syx(document: &SupplementedDocument, channel: MIDIChannel) -> Vec<u8> {
+    let mut data = match (&document.document, document.supplements.as_slice()) {
+        (_, []) => Vec::new(),
+        (Document::Voice(_), [supplement]) => make_aced_message(supplement, channel),
+        (_, supplements) => make_amem_message(supplements, channel),
+    };
+    let message = match &document.document {
+        Document::Voice(voice) => make_voice_message(voice, channel),
+        Document::Cartridge(cartridge) => make_cartridge_message(cartridge, channel),
+    };
+    data.extend(message.to_bytes());
+    data
+}
+
+//
+// XML
+//
+
+impl ToXml for Supplement {
+    fn to_xml(&self) -> XMLElement {
+        self.to_xml_named("supplement")
+    }
+
+    fn to_xml_named(&self, name: &str) -> XMLElement {
+        let mut e = XMLElement::new(name);
+        for (parameter, value) in PARAMETERS.iter().zip(self.data) {
+            e.add_attribute(parameter.attribute, &value.to_string());
+        }
+        let reserved: Vec<String> = self.data[RESERVED_OFFSET..].iter().map(|b| b.to_string()).collect();
+        e.add_attribute("reserved", &reserved.join(" "));
+        e
+    }
+}
+
+/// Converts a voice or a cartridge into an XML element, with the supplement
+/// of each voice as the `supplement` child element of the `voice` element.
+pub fn supplemented_to_xml(document: &SupplementedDocument) -> XMLElement {
+    let voice_element = |voice: &Voice, supplement: Option<&Supplement>| {
+        let mut e = voice.to_xml();
+        if let Some(supplement) = supplement {
+            let _ = e.add_child(supplement.to_xml());
+        }
+        e
+    };
+
+    match &document.document {
+        Document::Voice(voice) => voice_element(voice, document.supplements.first()),
+        Document::Cartridge(cartridge) => {
+            let mut voices_element = XMLElement::new("voices");
+            for (index, voice) in cartridge.voices.iter().enumerate() {
+                let _ = voices_element.add_child(voice_element(voice, document.supplements.get(index)));
+            }
+            let mut e = XMLElement::new("cartridge");
+            let _ = e.add_child(voices_element);
+            e
+        }
+    }
+}
+
+/// Converts a `supplement` element into a supplement.
+pub fn parse_supplement(element: &Element) -> Result<Supplement, XmlError> {
+    let mut attributes: Vec<&str> = PARAMETERS.iter().map(|p| p.attribute).collect();
+    attributes.push("reserved");
+    element.check_attributes(&attributes)?;
+    element.check_children(&[], false)?;
+
+    let mut supplement = Supplement::new();
+    for (value, parameter) in supplement.data.iter_mut().zip(&PARAMETERS) {
+        *value = element.integer_attribute(parameter.attribute, 0, parameter.maximum as i32)? as u8;
+    }
+
+    let reserved = element.required_attribute("reserved")?;
+    let values: Vec<&str> = reserved.split_whitespace().collect();
+    if values.len() != SUPPLEMENT_SIZE - RESERVED_OFFSET {
+        return Err(element.error(XmlErrorKind::InvalidValue {
+            name: String::from("reserved"),
+            value: reserved.to_string(),
+            expected: format!("{} integers separated by spaces", SUPPLEMENT_SIZE - RESERVED_OFFSET),
+        }));
+    }
+    for (byte, value) in supplement.data[RESERVED_OFFSET..].iter_mut().zip(values) {
+        *byte = value.parse::<u8>().ok().filter(|b| *b <= 0x7f).ok_or_else(|| element.error(XmlErrorKind::InvalidValue {
+            name: String::from("reserved"),
+            value: value.to_string(),
+            expected: String::from("an integer 0...127"),
+        }))?;
+    }
+
+    Ok(supplement)
+}
+
+/// Converts the root element of a document into a voice or a cartridge,
+/// with the supplements of its voices. If any voice of a cartridge has
+/// a supplement, the voices without one get an initialized supplement.
+pub fn parse_supplemented_document(root: &Element) -> Result<SupplementedDocument, XmlError> {
+    let document = parse_document(root)?;
+
+    let voice_elements: Vec<&Element> = match &document {
+        Document::Voice(_) => vec![root],
+        Document::Cartridge(_) => root.child("voices")?.children_named("voice"),
+    };
+
+    let mut supplements = Vec::<Supplement>::new();
+    let mut found = false;
+    for voice_element in voice_elements {
+        match voice_element.children_named("supplement").as_slice() {
+            [] => supplements.push(Supplement::new()),
+            [element] => {
+                supplements.push(parse_supplement(element)?);
+                found = true;
+            },
+            [_, duplicate, ..] => {
+                return Err(duplicate.error(XmlErrorKind::DuplicateElement(String::from("supplement"))));
+            }
+        }
+    }
+
+    if !found {
+        supplements.clear();
+    }
+    Ok(SupplementedDocument { document, supplements })
+}
@@ -0,0 +1,110 @@
+//! Errors of the commands, and the exit codes for them.
+
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::xml::XmlError;
+
+/// Exit code when a file fails verification.
+pub const EXIT_VERIFY: i32 = 1;
+/// Exit code when the compared files are different.
+pub const EXIT_DIFFERENT: i32 = 1;
+/// Exit code for file and directory errors.
+pub const EXIT_IO: i32 = 3;
+/// Exit code for data that is not a valid System Exclusive message.
+pub const EXIT_SYSEX: i32 = 4;
+/// Exit code for a bulk dump header that does not match the data.
+pub const EXIT_HEADER: i32 = 5;
+/// Exit code for a wrong checksum.
+pub const EXIT_CHECKSUM: i32 = 6;
+/// Exit code for a parameter or argument out of range.
+pub const EXIT_RANGE: i32 = 7;
+/// Exit code for errors in XML documents.
+pub const EXIT_XML: i32 = 8;
+/// Exit code for errors in JSON and TOML documents.
+pub const EXIT_DOCUMENT: i32 = 9;
+/// Exit code for MIDI errors.
+pub const EXIT_MIDI: i32 = 10;
+
+/// An error that stops a command.
+#[derive(Debug)]
+pub enum Error {
+    /// Reading or writing a file or a directory failed
+    Io { path: PathBuf, source: io::Error },
+    /// The data is not a complete Yamaha System Exclusive message
+    SysEx(String),
+    /// The header of a bulk dump is unknown or does not match the data
+    Header(String),
+    /// The checksum does not match the data
+    Checksum { found: u8, expected: u8 },
+    /// A voice parameter or a command argument is out of range
+    Range(String),
+    /// Errors in an XML document
+    Xml { path: PathBuf, errors: Vec<XmlError> },
+    /// An error in a JSON or TOML document
+    Document { path: PathBuf, message: String },
+    /// Sending or receiving over MIDI failed
+    Midi(String),
+    /// Some of the files failed verification
+    Verify { failed: usize, total: usize },
+    /// The compared files are different
+    Different { count: usize },
+}
+
+impl Error {
+    /// Makes an I/O error for a path.
+    pub fn io(path: &Path, source: io::Error) -> Self {
+        Error::Io { path: path.to_path_buf(), source }
+    }
+
+    /// Gets the exit code of the program for this error.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Io { .. } => EXIT_IO,
+            Error::SysEx(_) => EXIT_SYSEX,
+            Error::Header(_) => EXIT_HEADER,
+            Error::Checksum { .. } => EXIT_CHECKSUM,
+            Error::Range(_) => EXIT_RANGE,
+            Error::Xml { .. } => EXIT_XML,
+            Error::Document { .. } => EXIT_DOCUMENT,
+            Error::Midi(_) => EXIT_MIDI,
+            Error::Verify { .. } => EXIT_VERIFY,
+            Error::Different { .. } => EXIT_DIFFERENT,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io { path, source } =>
+                write!(f, "{}: {}", path.display(), source),
+            Error::SysEx(message) | Error::Header(message) | Error::Range(message) | Error::Midi(message) =>
+                write!(f, "{}", message),
+            Error::Checksum { found, expected } =>
+                write!(f, "Checksum is {:02X}H, expected {:02X}H", found, expected),
+            Error::Xml { path, errors } => {
+                for e in errors {
+                    writeln!(f, "{}: {}", path.display(), e)?;
+                }
+                write!(f, "{} error(s) in {}", errors.len(), path.display())
+            },
+            Error::Document { path, message } =>
+                write!(f, "{}: {}", path.display(), message),
+            Error::Verify { failed, total } =>
+                write!(f, "{} of {} file(s) failed verification", failed, total),
+            Error::Different { count } =>
+                write!(f, "The files are different, {} difference(s)", count),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
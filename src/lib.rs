@@ -0,0 +1,552 @@
+//! Sevenator is a toolkit for Yamaha DX7 voices and cartridges,
+//! with the DX7II supplements, TX802 performances, and micro-tuning tables.
+//!
+//! The functions here are the stable API of the library: loading and
+//! saving System Exclusive bulk dumps, converting them to and from XML,
+//! extracting the voices of a cartridge into files, packing voices into
+//! a cartridge, and generating new voices.
+//! They return values and errors instead of printing anything.
+//! The modules have the details, and may change more often.
+//!
+//! ```no_run
+//! use std::path::Path;
+//! use sevenator::{load_syx, save_xml};
+//!
+//! let document = load_syx(Path::new("ROM1A.SYX"))?;
+//! save_xml(&document, Path::new("rom1a.xml"))?;
+//! # Ok::<(), sevenator::Error>(())
+//! ```
+
+use std::fmt;
+use std::fs::{self, File};
+use std::str::FromStr;
+use std::io::BufReader;
+use std::path::Path;
+
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+use xml_builder::{XMLBuilder, XMLElement, XMLVersion};
+
+use sevenate::Ranged;
+use sevenate::dx7::voice::Voice;
+use sevenate::dx7::cartridge::{Cartridge, VOICE_COUNT};
+use sevenate::dx7::sysex::{Format, MIDIChannel};
+
+pub mod dx7;
+pub mod error;
+pub mod midi;
+pub mod patch;
+pub mod xml;
+pub mod tuning;
+pub mod tx802;
+pub mod universal;
+
+pub use crate::dx7::Document;
+pub use crate::error::Error;
+
+use crate::dx7::{
+    check_bulk_dump,
+    parse_bulk_dump,
+    make_voice_message,
+    make_cartridge_message,
+    make_init_voice,
+    make_init_cartridge,
+    make_random_voice,
+    make_random_cartridge,
+};
+use crate::dx7::randomizer::{randomize, RandomizationParameters};
+use crate::dx7::supplement::{
+    SupplementedDocument,
+    parse_supplemented,
+    supplemented_to_syx,
+    supplemented_to_xml,
+    parse_supplemented_document,
+};
+use crate::xml::{ToXml, Element, read_document, resolve_includes, parse_document};
+use crate::xml::schema::validate;
+use crate::patch::{to_json, to_toml};
+use crate::tuning::{
+    KeyTuning,
+    TuningKind,
+    TuningTable,
+    FINE_STEPS,
+    OCTAVE_FIRST_KEY,
+    parse_tuning,
+    make_tuning_message,
+};
+use crate::tuning::scala::{Scale, KeyboardMapping, parse_scl, parse_kbm, keyboard_cents};
+use crate::tx802::{
+    PerformanceMemory,
+    parse_pmem,
+    make_pmem_message,
+    performances_to_xml,
+    parse_performances,
+};
+
+//
+// System Exclusive
+//
+
+/// Reads a single voice or cartridge bulk dump from a System Exclusive file.
+/// The file must contain exactly one complete and valid bulk dump.
+pub fn load_syx(path: &Path) -> Result<Document, Error> {
+    let data = fs::read(path).map_err(|e| Error::io(path, e))?;
+    parse_bulk_dump(&data)
+}
+
+/// Writes a voice or a cartridge into a System Exclusive file
+/// as a bulk dump on MIDI channel 1.
+pub fn save_syx(document: &Document, path: &Path) -> Result<(), Error> {
+    fs::write(path, to_syx(document, MIDIChannel::new(1))).map_err(|e| Error::io(path, e))
+}
+
+/// Makes the bytes of a single voice or cartridge bulk dump,
+/// with the header and checksum.
+pub fn to_syx(document: &Document, channel: MIDIChannel) -> Vec<u8> {
+    let message = match document {
+        Document::Voice(voice) => make_voice_message(voice, channel),
+        Document::Cartridge(cartridge) => make_cartridge_message(cartridge, channel),
+    };
+    message.to_bytes()
+}
+
+/// File formats for voices and cartridges.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileFormat {
+    /// System Exclusive bulk dump
+    Syx,
+    /// XML document in the dx7-xml format
+    Xml,
+    /// JSON document
+    Json,
+    /// TOML document
+    Toml,
+}
+
+impl FileFormat {
+    /// Gets the file name extension for the format, without the dot.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            FileFormat::Syx => "syx",
+            FileFormat::Xml => "xml",
+            FileFormat::Json => "json",
+            FileFormat::Toml => "toml",
+        }
+    }
+}
+
+impl fmt::Display for FileFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.extension())
+    }
+}
+
+impl FromStr for FileFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "syx" => Ok(FileFormat::Syx),
+            "xml" => Ok(FileFormat::Xml),
+            "json" => Ok(FileFormat::Json),
+            "toml" => Ok(FileFormat::Toml),
+            _ => Err(format!("unknown format '{}', expected syx, xml, json or toml", s)),
+        }
+    }
+}
+
+/// Converts a voice or a cartridge into the bytes of a file in the given format.
+/// The MIDI channel is only used for System Exclusive.
+pub fn to_format(document: &Document, format: FileFormat, channel: MIDIChannel) -> Vec<u8> {
+    match format {
+        FileFormat::Syx => to_syx(document, channel),
+        FileFormat::Xml => to_xml(document).into_bytes(),
+        FileFormat::Json => to_json(document).into_bytes(),
+        FileFormat::Toml => to_toml(document).into_bytes(),
+    }
+}
+
+/// A voice extracted from a cartridge, converted into a file format.
+#[derive(Debug, Clone)]
+pub struct ExtractedVoice {
+    /// Number of the voice in the cartridge, 1...32
+    pub number: usize,
+    /// Name of the voice, with trailing spaces removed
+    pub name: String,
+    /// Contents of the file
+    pub data: Vec<u8>,
+}
+
+/// Extracts each voice of a cartridge bulk dump into the given format.
+/// Single voice bulk dumps are on the same MIDI channel as the cartridge.
+/// Fails if the message is not a valid cartridge bulk dump.
+pub fn extract_voices(message: &[u8], format: FileFormat) -> Result<Vec<ExtractedVoice>, Error> {
+    let header = check_bulk_dump(message)?;
+    let Document::Cartridge(cartridge) = parse_bulk_dump(message)? else {
+        return Err(Error::Header(format!("Expected a {} bulk dump, got {}", Format::Cartridge, header.format)));
+    };
+
+    Ok(cartridge.voices.into_iter()
+        .enumerate()
+        .map(|(index, voice)| ExtractedVoice {
+            number: index + 1,
+            name: voice.name.value().trim_end().to_string(),
+            data: to_format(&Document::Voice(Box::new(voice)), format, header.channel),
+        })
+        .collect())
+}
+
+/// The default template for the names of extracted voice files.
+pub const DEFAULT_NAME_TEMPLATE: &str = "{stem}-{number}";
+
+/// Makes the name of a file for an extracted voice from a template,
+/// and adds the extension of the format. In the template, `{stem}` is
+/// the name of the cartridge file without the extension, `{number}` is the
+/// voice number with two digits, and `{name}` is the voice name with
+/// the characters that are not safe in file names replaced by underscores.
+pub fn voice_file_name(template: &str, stem: &str, voice: &ExtractedVoice, format: FileFormat) -> Result<String, Error> {
+    let mut name = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        name.push_str(&rest[..start]);
+        let Some(end) = rest[start..].find('}') else {
+            return Err(Error::Range(format!("Unclosed placeholder in name template '{}'", template)));
+        };
+        match &rest[start + 1 .. start + end] {
+            "stem" => name.push_str(stem),
+            "number" => name.push_str(&format!("{:02}", voice.number)),
+            "name" => name.push_str(&sanitize_file_name(&voice.name)),
+            other => {
+                return Err(Error::Range(format!("Unknown placeholder '{{{}}}' in name template, expected {{stem}}, {{number}} or {{name}}", other)));
+            }
+        }
+        rest = &rest[start + end + 1 ..];
+    }
+    name.push_str(rest);
+
+    if name.is_empty() || name.contains(['/', '\\']) {
+        return Err(Error::Range(format!("Name template '{}' does not make a valid file name", template)));
+    }
+
+    Ok(format!("{}.{}", name, format.extension()))
+}
+
+// Replaces the characters of a voice name that are not letters, digits,
+// hyphens or underscores with underscores, like "E.PIANO 1" to "E_PIANO_1".
+fn sanitize_file_name(name: &str) -> String {
+    let sanitized: String = name.trim()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .collect();
+    if sanitized.is_empty() {
+        String::from("voice")
+    } else {
+        sanitized
+    }
+}
+
+/// Packs up to 32 voices into a cartridge, in order.
+/// The rest of the cartridge is filled with initialized voices.
+pub fn pack_voices(voices: Vec<Voice>) -> Result<Cartridge, Error> {
+    if voices.len() > VOICE_COUNT {
+        return Err(Error::Range(format!("A cartridge has at most {} voices, got {}", VOICE_COUNT, voices.len())));
+    }
+
+    let mut voices = voices;
+    voices.resize_with(VOICE_COUNT, make_init_voice);
+    Ok(Cartridge { voices })
+}
+
+//
+// XML
+//
+
+/// Reads an XML document, resolves any XInclude elements in it,
+/// and validates it against the dx7-xml schema, with the extension
+/// for TX802 performances.
+/// Returns the root element, or all the errors found in the document.
+pub fn read_xml(path: &Path) -> Result<Element, Error> {
+    let file = File::open(path).map_err(|e| Error::io(path, e))?;
+
+    let root = read_document(BufReader::new(file))
+        .and_then(|root| resolve_includes(root, path))
+        .map_err(|e| Error::Xml { path: path.to_path_buf(), errors: vec![e] })?;
+
+    let errors = validate(&root);
+    if !errors.is_empty() {
+        return Err(Error::Xml { path: path.to_path_buf(), errors });
+    }
+
+    Ok(root)
+}
+
+/// Reads a voice or a cartridge from an XML document, after validating it.
+pub fn load_xml(path: &Path) -> Result<Document, Error> {
+    let root = read_xml(path)?;
+    parse_document(&root).map_err(|e| Error::Xml { path: path.to_path_buf(), errors: vec![e] })
+}
+
+/// Converts a voice or a cartridge into an XML document.
+/// The root element is `voice` or `cartridge` respectively.
+pub fn to_xml(document: &Document) -> String {
+    let root: XMLElement = match document {
+        Document::Voice(voice) => voice.to_xml(),
+        Document::Cartridge(cartridge) => cartridge.to_xml(),
+    };
+    xml_string(root)
+}
+
+// Makes an XML document with the given root element.
+fn xml_string(root: XMLElement) -> String {
+    let mut xml = XMLBuilder::new()
+        .version(XMLVersion::XML1_1)
+        .encoding("UTF-8".into())
+        .build();
+    xml.set_root_element(root);
+
+    let mut writer: Vec<u8> = Vec::new();
+    xml.generate(&mut writer).expect("XML should generate into memory");
+    String::from_utf8_lossy(&writer).into_owned()
+}
+
+/// Writes a voice or a cartridge into an XML document.
+pub fn save_xml(document: &Document, path: &Path) -> Result<(), Error> {
+    fs::write(path, to_xml(document)).map_err(|e| Error::io(path, e))
+}
+
+//
+// DX7II supplements
+//
+
+/// Reads a voice or a cartridge from a System Exclusive file, with
+/// the DX7II supplements of its voices if the file has an ACED or AMEM
+/// bulk dump besides the voice or cartridge bulk dump.
+pub fn load_supplemented(path: &Path) -> Result<SupplementedDocument, Error> {
+    let data = fs::read(path).map_err(|e| Error::io(path, e))?;
+    parse_supplemented(&data)
+}
+
+/// Writes a voice or a cartridge into a System Exclusive file on MIDI
+/// channel 1, after the bulk dump with its supplements if it has any.
+pub fn save_supplemented(document: &SupplementedDocument, path: &Path) -> Result<(), Error> {
+    fs::write(path, supplemented_to_syx(document, MIDIChannel::new(1))).map_err(|e| Error::io(path, e))
+}
+
+/// Converts a voice or a cartridge into an XML document, with
+/// the supplement of each voice in the `voice` element.
+pub fn supplemented_xml(document: &SupplementedDocument) -> String {
+    xml_string(supplemented_to_xml(document))
+}
+
+/// Reads a voice or a cartridge from an XML document, with the supplements
+/// of its voices, after validating it.
+pub fn load_supplemented_xml(path: &Path) -> Result<SupplementedDocument, Error> {
+    let root = read_xml(path)?;
+    parse_supplemented_document(&root).map_err(|e| Error::Xml { path: path.to_path_buf(), errors: vec![e] })
+}
+
+//
+// TX802 performances
+//
+
+/// Reads the TX802 performance memory from a System Exclusive file.
+/// The file must contain exactly one complete and valid PMEM bulk dump.
+pub fn load_pmem(path: &Path) -> Result<PerformanceMemory, Error> {
+    let data = fs::read(path).map_err(|e| Error::io(path, e))?;
+    parse_pmem(&data)
+}
+
+/// Writes the TX802 performance memory into a System Exclusive file
+/// as a PMEM bulk dump on MIDI channel 1.
+pub fn save_pmem(memory: &PerformanceMemory, path: &Path) -> Result<(), Error> {
+    fs::write(path, make_pmem_message(memory, MIDIChannel::new(1))).map_err(|e| Error::io(path, e))
+}
+
+/// Converts the TX802 performance memory into an XML document
+/// with the root element `performances`.
+pub fn pmem_to_xml(memory: &PerformanceMemory) -> String {
+    xml_string(performances_to_xml(memory))
+}
+
+/// Reads the TX802 performance memory from an XML document, after validating it.
+pub fn load_pmem_xml(path: &Path) -> Result<PerformanceMemory, Error> {
+    let root = read_xml(path)?;
+    parse_performances(&root).map_err(|e| Error::Xml { path: path.to_path_buf(), errors: vec![e] })
+}
+
+/// The TX802 has 64 internal voices, so a setup has at most two cartridges.
+pub const SETUP_CARTRIDGE_COUNT: usize = 2;
+
+/// Makes the bytes of a complete TX802 setup: a cartridge bulk dump
+/// for each bank of internal voices, followed by the performance memory
+/// bulk dump, all on the same MIDI channel. There can be one or two
+/// cartridges.
+pub fn setup_to_syx(cartridges: &[Cartridge], memory: &PerformanceMemory, channel: MIDIChannel) -> Result<Vec<u8>, Error> {
+    if cartridges.is_empty() || cartridges.len() > SETUP_CARTRIDGE_COUNT {
+        return Err(Error::Range(format!("A TX802 setup has 1...{} cartridges, got {}",
+            SETUP_CARTRIDGE_COUNT, cartridges.len())));
+    }
+
+    let mut data = Vec::<u8>::new();
+    for cartridge in cartridges {
+        data.extend(make_cartridge_message(cartridge, channel).to_bytes());
+    }
+    data.extend(make_pmem_message(memory, channel));
+    Ok(data)
+}
+
+//
+// Micro-tuning
+//
+
+/// Reads a Scala scale file, and a keyboard mapping file if there is one.
+/// Without a mapping file, the mapping is linear with degree 0 on middle C.
+pub fn load_scala(scl_path: &Path, kbm_path: Option<&Path>) -> Result<(Scale, KeyboardMapping), Error> {
+    let read = |path: &Path| fs::read_to_string(path).map_err(|e| Error::io(path, e));
+    let document_error = |path: &Path, message: String| Error::Document { path: path.to_path_buf(), message };
+
+    let scale = parse_scl(&read(scl_path)?).map_err(|e| document_error(scl_path, e))?;
+    let mapping = match kbm_path {
+        Some(path) => parse_kbm(&read(path)?).map_err(|e| document_error(path, e))?,
+        None => KeyboardMapping::default(),
+    };
+    Ok((scale, mapping))
+}
+
+/// Makes a micro-tuning table from a Scala scale and keyboard mapping.
+/// An octave table is made from the keys C3...B3, and the tuning
+/// must repeat every octave. In a full keyboard table, keys with pitches
+/// outside the range of the table get its lowest or highest pitch.
+pub fn tuning_from_scala(scale: &Scale, mapping: &KeyboardMapping, kind: TuningKind) -> Result<TuningTable, Error> {
+    let cents = keyboard_cents(scale, mapping).map_err(Error::Range)?;
+    match kind {
+        TuningKind::Keyboard => {
+            let highest = KeyTuning { note: 127, fine: FINE_STEPS - 1 }.cents();
+            let clamped: Vec<f64> = cents.iter().map(|c| c.clamp(0.0, highest)).collect();
+            TuningTable::from_cents(kind, &clamped)
+        },
+        TuningKind::Octave => {
+            let first = OCTAVE_FIRST_KEY as usize;
+            let octave = &cents[first .. first + 12];
+            let next_octave = &cents[first + 12 .. first + 24];
+            if octave.iter().zip(next_octave).any(|(a, b)| (b - a - 1200.0).abs() > 0.01) {
+                return Err(Error::Range(String::from("The tuning does not repeat every octave, use a full keyboard table")));
+            }
+            TuningTable::from_cents(kind, octave)
+        }
+    }
+}
+
+/// Reads a micro-tuning table from a System Exclusive file.
+/// The file must contain exactly one complete and valid tuning table bulk dump.
+pub fn load_tuning(path: &Path) -> Result<TuningTable, Error> {
+    let data = fs::read(path).map_err(|e| Error::io(path, e))?;
+    parse_tuning(&data)
+}
+
+/// Writes a micro-tuning table into a System Exclusive file as a bulk dump
+/// on MIDI channel 1.
+pub fn save_tuning(table: &TuningTable, path: &Path) -> Result<(), Error> {
+    fs::write(path, make_tuning_message(table, MIDIChannel::new(1))).map_err(|e| Error::io(path, e))
+}
+
+//
+// Generating voices
+//
+
+/// How to fill in generated voices.
+#[derive(Debug, Clone, Copy)]
+pub enum Generator {
+    /// Initialized voices with the DX7 defaults
+    Init,
+    /// Completely random voices
+    Random,
+    /// Random voices shaped by the randomizer parameters
+    Randomizer(RandomizationParameters),
+}
+
+/// Generates a voice. The same seed always makes the same voice.
+pub fn generate_voice(generator: Generator, seed: u64) -> Voice {
+    let mut rng = ChaCha8Rng::seed_from_u64(seed);
+    match generator {
+        Generator::Init => make_init_voice(),
+        Generator::Random => make_random_voice(&mut rng),
+        Generator::Randomizer(params) => randomize(params, &mut rng),
+    }
+}
+
+/// Generates a cartridge of 32 voices. The same seed always makes
+/// the same cartridge.
+pub fn generate_cartridge(generator: Generator, seed: u64) -> Cartridge {
+    let mut rng = ChaCha8Rng::seed_from_u64(seed);
+    match generator {
+        Generator::Init => make_init_cartridge(),
+        Generator::Random => make_random_cartridge(&mut rng),
+        Generator::Randomizer(params) => Cartridge {
+            voices: (0..VOICE_COUNT).map(|_| randomize(params, &mut rng)).collect()
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sevenate::dx7::sysex::SystemExclusiveData;
+    use crate::dx7::repair::check_voice_data;
+
+    fn generators() -> [Generator; 3] {
+        [Generator::Init, Generator::Random, Generator::Randomizer(RandomizationParameters::default())]
+    }
+
+    #[test]
+    fn same_seed_makes_same_cartridge() {
+        for generator in generators() {
+            let first = generate_cartridge(generator, 42).to_bytes();
+            let second = generate_cartridge(generator, 42).to_bytes();
+            assert_eq!(first, second, "{:?}", generator);
+        }
+    }
+
+    #[test]
+    fn different_seed_makes_different_cartridge() {
+        for generator in [Generator::Random, Generator::Randomizer(RandomizationParameters::default())] {
+            let first = generate_cartridge(generator, 42).to_bytes();
+            let second = generate_cartridge(generator, 43).to_bytes();
+            assert_ne!(first, second, "{:?}", generator);
+        }
+    }
+
+    #[test]
+    fn init_cartridge_does_not_depend_on_seed() {
+        assert_eq!(generate_cartridge(Generator::Init, 1).to_bytes(),
+            generate_cartridge(Generator::Init, 2).to_bytes());
+    }
+
+    #[test]
+    fn generated_voices_are_valid() {
+        for generator in generators() {
+            for seed in 0..8 {
+                for (index, voice) in generate_cartridge(generator, seed).voices.iter().enumerate() {
+                    let problems = check_voice_data(&voice.to_bytes(), index + 1);
+                    assert!(problems.is_empty(), "{:?} seed {}: {:?}", generator, seed, problems);
+                }
+                let problems = check_voice_data(&generate_voice(generator, seed).to_bytes(), 1);
+                assert!(problems.is_empty(), "{:?} seed {}: {:?}", generator, seed, problems);
+            }
+        }
+    }
+
+    #[test]
+    fn different_seed_makes_different_voice() {
+        for generator in [Generator::Random, Generator::Randomizer(RandomizationParameters::default())] {
+            assert_ne!(generate_voice(generator, 1).to_bytes(), generate_voice(generator, 2).to_bytes(), "{:?}", generator);
+        }
+    }
+
+    #[test]
+    fn same_seed_makes_same_voice() {
+        for generator in generators() {
+            assert_eq!(generate_voice(generator, 7).to_bytes(), generate_voice(generator, 7).to_bytes(), "{:?}", generator);
+        }
+    }
+}
@@ -5,17 +5,57 @@ use clap::{
     Subcommand
 };
 
-pub mod cmd;
-pub mod dx7;
-pub mod tx802;
+mod cmd;
+
+use sevenator::{FileFormat, DEFAULT_NAME_TEMPLATE};
+use sevenator::dx7::edit::SortKey;
+use sevenator::tuning::TuningKind;
+use sevenator::tx802::INIT_NAME;
 
 use crate::cmd::{
     run_list,
+    run_split,
+    run_join,
+    run_diff,
+    run_repair,
+    run_verify,
+    run_pack,
+    run_move,
+    run_swap,
+    run_replace,
+    run_copy,
+    run_sort,
+    run_perf_new,
+    run_perf_show,
+    run_perf_assign,
+    run_perf_set,
+    run_perf_limits,
+    run_perf_split,
+    run_perf_layer,
+    run_export_setup,
+    run_tuning_new,
+    run_tuning_scala,
+    run_tuning_show,
     run_extract,
     run_dump,
     run_make_xml,
     run_make_syx,
+    run_validate_xml,
+    run_make_json,
+    run_from_json,
+    run_make_toml,
+    run_from_toml,
+    run_generate,
+    run_send,
+    run_set_parameter,
+    run_receive,
     run_repl,
+    GenerateKind,
+    GenerateMode,
+    RandomizerArgs,
+    DEFAULT_SEND_DELAY,
+    DumpKind,
+    DEFAULT_RECEIVE_TIMEOUT,
 };
 
 #[derive(Parser)]
@@ -27,16 +67,364 @@ struct Cli {
 
 #[derive(Subcommand)]
 enum Commands {
-    /// List the voices in a cartridge file
+    /// List the voices in a file with one or more voice or cartridge dumps
     List {
         #[arg(short, long)]
         file: PathBuf,
     },
 
+    /// Split a file with many voice or cartridge dumps into one file per dump
+    Split {
+        #[arg(short, long)]
+        input_file: PathBuf,
+
+        /// Directory for the output files, by default that of the input file
+        #[arg(short = 'd', long)]
+        output_dir: Option<PathBuf>,
+    },
+
+    /// Join voice and cartridge dumps from files or directories into one file
+    Join {
+        #[arg(short, long)]
+        output_file: PathBuf,
+
+        /// Files or directories of .syx files to join, in order
+        #[arg(required = true)]
+        input_files: Vec<PathBuf>,
+    },
+
+    /// Verify the structure, checksums and parameter ranges of System Exclusive files
+    Verify {
+        /// Files or directories of .syx files to verify
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+    },
+
+    /// Compare two voices or two cartridges parameter by parameter
+    Diff {
+        first_file: PathBuf,
+
+        second_file: PathBuf,
+
+        /// Print the differences as JSON
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Check a damaged or nonstandard System Exclusive file, and salvage its voices
+    Repair {
+        #[arg(short, long)]
+        input_file: PathBuf,
+
+        /// Write the salvaged bulk dumps into this file
+        #[arg(short, long)]
+        output_file: Option<PathBuf>,
+    },
+
+    /// Pack single voice files into a cartridge, filling the rest with initialized voices
+    Pack {
+        #[arg(short, long)]
+        output_file: PathBuf,
+
+        /// File with the paths of the voice files, one per line
+        #[arg(short, long)]
+        list: Option<PathBuf>,
+
+        /// Voice files or directories of .syx files, in order
+        #[arg(required_unless_present = "list")]
+        input_files: Vec<PathBuf>,
+    },
+
+    /// Move a voice of a cartridge to another slot, moving the voices in between
+    Move {
+        #[arg(short, long)]
+        input_file: PathBuf,
+
+        /// Write the result into this file instead of the input file
+        #[arg(short, long)]
+        output_file: Option<PathBuf>,
+
+        /// Voice number to move (1...32)
+        from: usize,
+
+        /// Voice number to move it to (1...32)
+        to: usize,
+    },
+
+    /// Swap two voices of a cartridge
+    Swap {
+        #[arg(short, long)]
+        input_file: PathBuf,
+
+        /// Write the result into this file instead of the input file
+        #[arg(short, long)]
+        output_file: Option<PathBuf>,
+
+        /// Voice number (1...32)
+        a: usize,
+
+        /// Voice number (1...32)
+        b: usize,
+    },
+
+    /// Replace a voice of a cartridge with the voice in a single voice file
+    Replace {
+        #[arg(short, long)]
+        input_file: PathBuf,
+
+        /// Write the result into this file instead of the input file
+        #[arg(short, long)]
+        output_file: Option<PathBuf>,
+
+        /// Voice number to replace (1...32)
+        number: usize,
+
+        /// Single voice file with the new voice
+        voice_file: PathBuf,
+    },
+
+    /// Copy a range of voices from one cartridge to another
+    Copy {
+        /// Cartridge to copy the voices from
+        #[arg(short, long)]
+        input_file: PathBuf,
+
+        /// Cartridge to copy the voices into
+        #[arg(short, long)]
+        target_file: PathBuf,
+
+        /// Write the result into this file instead of the target file
+        #[arg(short, long)]
+        output_file: Option<PathBuf>,
+
+        /// First voice number to copy (1...32)
+        first: usize,
+
+        /// Last voice number to copy (1...32)
+        last: usize,
+
+        /// Voice number in the target cartridge for the first voice (1...32)
+        to: usize,
+    },
+
+    /// Sort the voices of a cartridge by name or by algorithm
+    Sort {
+        #[arg(short, long)]
+        input_file: PathBuf,
+
+        /// Write the result into this file instead of the input file
+        #[arg(short, long)]
+        output_file: Option<PathBuf>,
+
+        /// Sort by name or algorithm
+        #[arg(short, long, default_value_t = SortKey::Name)]
+        by: SortKey,
+    },
+
+    /// Initialize a performance of a TX802 performance memory file
+    PerfNew {
+        /// Performance memory to edit, or none to start from initialized performances
+        #[arg(short, long)]
+        input_file: Option<PathBuf>,
+
+        /// Write the result into this file instead of the input file
+        #[arg(short, long, required_unless_present = "input_file")]
+        output_file: Option<PathBuf>,
+
+        /// Performance number (1...64)
+        #[arg(short, long, default_value_t = 1)]
+        number: usize,
+
+        /// Name of the performance, at most 20 characters
+        #[arg(long, default_value = INIT_NAME)]
+        name: String,
+    },
+
+    /// Show the tone generator settings of a TX802 performance
+    PerfShow {
+        #[arg(short, long)]
+        input_file: PathBuf,
+
+        /// Performance number (1...64)
+        #[arg(short, long)]
+        number: usize,
+    },
+
+    /// Assign a voice to a tone generator of a TX802 performance
+    PerfAssign {
+        #[arg(short, long)]
+        input_file: PathBuf,
+
+        /// Write the result into this file instead of the input file
+        #[arg(short, long)]
+        output_file: Option<PathBuf>,
+
+        /// Performance number (1...64)
+        #[arg(short, long)]
+        number: usize,
+
+        /// Tone generator number (1...8)
+        tg: usize,
+
+        /// Voice, I01...I64 for internal or C01...C64 for cartridge voices
+        voice: String,
+    },
+
+    /// Set a parameter of a tone generator of a TX802 performance
+    PerfSet {
+        #[arg(short, long)]
+        input_file: PathBuf,
+
+        /// Write the result into this file instead of the input file
+        #[arg(short, long)]
+        output_file: Option<PathBuf>,
+
+        /// Performance number (1...64)
+        #[arg(short, long)]
+        number: usize,
+
+        /// Tone generator number (1...8)
+        tg: usize,
+
+        /// Parameter name, like outputVolume or receiveChannel
+        parameter: String,
+
+        /// Raw value of the parameter, as in the bulk dump
+        value: u8,
+    },
+
+    /// Set the key range of a tone generator of a TX802 performance
+    PerfLimits {
+        #[arg(short, long)]
+        input_file: PathBuf,
+
+        /// Write the result into this file instead of the input file
+        #[arg(short, long)]
+        output_file: Option<PathBuf>,
+
+        /// Performance number (1...64)
+        #[arg(short, long)]
+        number: usize,
+
+        /// Tone generator number (1...8)
+        tg: usize,
+
+        /// Lowest note, as a note number or a name like C-2
+        low: String,
+
+        /// Highest note, as a note number or a name like G8
+        high: String,
+    },
+
+    /// Make a TX802 performance into a key split of two voices
+    PerfSplit {
+        #[arg(short, long)]
+        input_file: PathBuf,
+
+        /// Write the result into this file instead of the input file
+        #[arg(short, long)]
+        output_file: Option<PathBuf>,
+
+        /// Performance number (1...64)
+        #[arg(short, long)]
+        number: usize,
+
+        /// Lowest note of the upper voice, as a note number or a name like C3
+        split: String,
+
+        /// Voice below the split note, like I01
+        lower: String,
+
+        /// Voice from the split note up, like I02
+        upper: String,
+    },
+
+    /// Make a TX802 performance into a layer of one to eight voices
+    PerfLayer {
+        #[arg(short, long)]
+        input_file: PathBuf,
+
+        /// Write the result into this file instead of the input file
+        #[arg(short, long)]
+        output_file: Option<PathBuf>,
+
+        /// Performance number (1...64)
+        #[arg(short, long)]
+        number: usize,
+
+        /// Voices to layer, like I01 C05
+        #[arg(required = true)]
+        voices: Vec<String>,
+    },
+
+    /// Make a micro-tuning table in equal temperament
+    TuningNew {
+        /// Tune one octave or the full keyboard
+        #[arg(short, long, default_value_t = TuningKind::Keyboard)]
+        kind: TuningKind,
+
+        #[arg(short, long)]
+        output_file: PathBuf,
+    },
+
+    /// Make a micro-tuning table from a Scala scale and keyboard mapping
+    TuningScala {
+        /// Scala scale file (.scl)
+        #[arg(long)]
+        scl: PathBuf,
+
+        /// Scala keyboard mapping file (.kbm), by default degree 0 on middle C
+        #[arg(long)]
+        kbm: Option<PathBuf>,
+
+        /// Tune one octave or the full keyboard
+        #[arg(short, long, default_value_t = TuningKind::Keyboard)]
+        kind: TuningKind,
+
+        #[arg(short, long)]
+        output_file: PathBuf,
+    },
+
+    /// Show a micro-tuning table as the pitch of each key in cents
+    TuningShow {
+        #[arg(short, long)]
+        file: PathBuf,
+    },
+
+    /// Write the internal voices and the performances of a TX802 into one file
+    ExportSetup {
+        /// Performance memory file
+        #[arg(short, long)]
+        performances: PathBuf,
+
+        /// Cartridge file for internal voices 1...32, and another for 33...64
+        #[arg(short, long, required = true)]
+        cartridge: Vec<PathBuf>,
+
+        #[arg(short, long)]
+        output_file: PathBuf,
+    },
+
     /// Extract the voices in a cartridge file to separate voice files
     Extract {
         #[arg(short, long)]
         file: PathBuf,
+
+        /// Directory for the voice files, by default that of the cartridge file
+        #[arg(short = 'd', long)]
+        output_dir: Option<PathBuf>,
+
+        /// Template for the file names, with {stem}, {number} and {name}
+        #[arg(long, default_value = DEFAULT_NAME_TEMPLATE)]
+        name: String,
+
+        /// Format of the voice files: syx, xml, json or toml
+        #[arg(long, default_value_t = FileFormat::Syx)]
+        format: FileFormat,
+
+        /// Overwrite existing files
+        #[arg(long)]
+        force: bool,
     },
 
     /// Dump a System Exclusive file
@@ -66,6 +454,128 @@ enum Commands {
         output_file: PathBuf,
     },
 
+    /// Make JSON from System Exclusive file
+    MakeJson {
+        #[arg(short, long)]
+        input_file: PathBuf,
+
+        #[arg(short, long)]
+        output_file: PathBuf,
+    },
+
+    /// Make System Exclusive file from JSON
+    FromJson {
+        #[arg(short, long)]
+        input_file: PathBuf,
+
+        #[arg(short, long)]
+        output_file: PathBuf,
+    },
+
+    /// Make TOML from System Exclusive file
+    MakeToml {
+        #[arg(short, long)]
+        input_file: PathBuf,
+
+        #[arg(short, long)]
+        output_file: PathBuf,
+    },
+
+    /// Make System Exclusive file from TOML
+    FromToml {
+        #[arg(short, long)]
+        input_file: PathBuf,
+
+        #[arg(short, long)]
+        output_file: PathBuf,
+    },
+
+    /// Validate an XML file against the dx7-xml schema
+    ValidateXml {
+        #[arg(short, long)]
+        input_file: PathBuf,
+    },
+
+    /// Generate a single voice or a cartridge as System Exclusive
+    Generate {
+        #[arg(short, long, value_enum, default_value_t = GenerateKind::Cartridge)]
+        kind: GenerateKind,
+
+        #[arg(short, long, value_enum, default_value_t = GenerateMode::Init)]
+        mode: GenerateMode,
+
+        #[arg(short, long)]
+        output: PathBuf,
+
+        /// Seed for the random number generator, to make the same voices again
+        #[arg(short, long)]
+        seed: Option<u64>,
+
+        #[command(flatten)]
+        randomizer: RandomizerArgs,
+    },
+
+    /// Send a System Exclusive file to a MIDI output port
+    Send {
+        #[arg(short, long)]
+        file: PathBuf,
+
+        /// MIDI output port index or part of its name
+        #[arg(short, long)]
+        port: String,
+
+        /// Voice number (1...32) to send from a cartridge as a single voice
+        #[arg(short, long)]
+        number: Option<u8>,
+
+        /// Delay after each message in milliseconds
+        #[arg(short, long, default_value_t = DEFAULT_SEND_DELAY)]
+        delay: u64,
+    },
+
+    /// Set a parameter of the voice in the edit buffer of the synth
+    SetParameter {
+        /// MIDI output port index or part of its name
+        #[arg(short, long)]
+        port: String,
+
+        /// MIDI channel (1...16) of the synth
+        #[arg(short, long, default_value_t = 1, value_parser = clap::value_parser!(u8).range(1..=16))]
+        channel: u8,
+
+        /// Field like op1.output_level, name like "OP1 output level", number 0...155, or "name"
+        parameter: String,
+
+        /// Raw value, or the voice name for the "name" parameter
+        value: String,
+    },
+
+    /// Receive a bulk dump from a MIDI input port into a System Exclusive file
+    Receive {
+        #[arg(short, long)]
+        output: PathBuf,
+
+        /// MIDI input port index or part of its name
+        #[arg(short, long)]
+        port: String,
+
+        /// Send a dump request for a voice or a cartridge first
+        #[arg(short, long, value_enum)]
+        request: Option<DumpKind>,
+
+        /// MIDI output port for the dump request, if different from the input port name
+        #[arg(long)]
+        request_port: Option<String>,
+
+        /// MIDI channel (1...16) of the synth for the dump request
+        #[arg(short, long, default_value_t = 1, value_parser = clap::value_parser!(u8).range(1..=16))]
+        channel: u8,
+
+        /// Time to wait for the dump in seconds
+        #[arg(short, long, default_value_t = DEFAULT_RECEIVE_TIMEOUT)]
+        timeout: u64,
+    },
+
     /// Start a REPL for commands
     Repl,
 }
@@ -75,31 +585,145 @@ fn main() {
 
     let cli = Cli::parse();
 
-    match &cli.command {
+    let result = match &cli.command {
         Commands::List { file } => {
             let path = PathBuf::from(file);
-            run_list(&path);
+            run_list(&path)
+        },
+        Commands::Split { input_file, output_dir } => {
+            let input_path = PathBuf::from(input_file);
+            run_split(&input_path, output_dir)
+        },
+        Commands::Join { output_file, input_files } => {
+            let output_path = PathBuf::from(output_file);
+            run_join(input_files, &output_path)
+        },
+        Commands::Verify { files } => {
+            run_verify(files)
+        },
+        Commands::Diff { first_file, second_file, json } => {
+            run_diff(first_file, second_file, *json)
+        },
+        Commands::Repair { input_file, output_file } => {
+            let input_path = PathBuf::from(input_file);
+            run_repair(&input_path, output_file)
+        },
+        Commands::Pack { output_file, list, input_files } => {
+            let output_path = PathBuf::from(output_file);
+            run_pack(input_files, list, &output_path)
+        },
+        Commands::PerfNew { input_file, output_file, number, name } => {
+            run_perf_new(input_file, *number, name, output_file)
+        },
+        Commands::PerfShow { input_file, number } => {
+            run_perf_show(input_file, *number)
         },
-        Commands::Extract { file } => {
+        Commands::PerfAssign { input_file, output_file, number, tg, voice } => {
+            run_perf_assign(input_file, *number, *tg, voice, output_file)
+        },
+        Commands::PerfSet { input_file, output_file, number, tg, parameter, value } => {
+            run_perf_set(input_file, *number, *tg, parameter, *value, output_file)
+        },
+        Commands::PerfLimits { input_file, output_file, number, tg, low, high } => {
+            run_perf_limits(input_file, *number, *tg, low, high, output_file)
+        },
+        Commands::PerfSplit { input_file, output_file, number, split, lower, upper } => {
+            run_perf_split(input_file, *number, split, lower, upper, output_file)
+        },
+        Commands::PerfLayer { input_file, output_file, number, voices } => {
+            run_perf_layer(input_file, *number, voices, output_file)
+        },
+        Commands::TuningNew { kind, output_file } => {
+            run_tuning_new(*kind, output_file)
+        },
+        Commands::TuningScala { scl, kbm, kind, output_file } => {
+            run_tuning_scala(scl, kbm, *kind, output_file)
+        },
+        Commands::TuningShow { file } => {
+            run_tuning_show(file)
+        },
+        Commands::ExportSetup { performances, cartridge, output_file } => {
+            run_export_setup(performances, cartridge, output_file)
+        },
+        Commands::Move { input_file, output_file, from, to } => {
+            run_move(input_file, *from, *to, output_file)
+        },
+        Commands::Swap { input_file, output_file, a, b } => {
+            run_swap(input_file, *a, *b, output_file)
+        },
+        Commands::Replace { input_file, output_file, number, voice_file } => {
+            run_replace(input_file, *number, voice_file, output_file)
+        },
+        Commands::Copy { input_file, target_file, output_file, first, last, to } => {
+            run_copy(input_file, *first, *last, target_file, *to, output_file)
+        },
+        Commands::Sort { input_file, output_file, by } => {
+            run_sort(input_file, *by, output_file)
+        },
+        Commands::Extract { file, output_dir, name, format, force } => {
             let path = PathBuf::from(file);
-            run_extract(&path);
+            run_extract(&path, output_dir, name, *format, *force)
         },
         Commands::Dump { file, number } => {
             let path = PathBuf::from(file);
-            run_dump(&path, number);
+            run_dump(&path, number)
         },
         Commands::MakeXml { input_file, output_file } => {
             let input_path = PathBuf::from(input_file);
             let output_path = PathBuf::from(output_file);
-            run_make_xml(&input_path, &output_path);
+            run_make_xml(&input_path, &output_path)
         },
         Commands::MakeSyx { input_file, output_file } => {
             let input_path = PathBuf::from(input_file);
             let output_path = PathBuf::from(output_file);
-            run_make_syx(&input_path, &output_path);
+            run_make_syx(&input_path, &output_path)
+        },
+        Commands::MakeJson { input_file, output_file } => {
+            let input_path = PathBuf::from(input_file);
+            let output_path = PathBuf::from(output_file);
+            run_make_json(&input_path, &output_path)
+        },
+        Commands::FromJson { input_file, output_file } => {
+            let input_path = PathBuf::from(input_file);
+            let output_path = PathBuf::from(output_file);
+            run_from_json(&input_path, &output_path)
+        },
+        Commands::MakeToml { input_file, output_file } => {
+            let input_path = PathBuf::from(input_file);
+            let output_path = PathBuf::from(output_file);
+            run_make_toml(&input_path, &output_path)
+        },
+        Commands::FromToml { input_file, output_file } => {
+            let input_path = PathBuf::from(input_file);
+            let output_path = PathBuf::from(output_file);
+            run_from_toml(&input_path, &output_path)
+        },
+        Commands::ValidateXml { input_file } => {
+            let input_path = PathBuf::from(input_file);
+            run_validate_xml(&input_path)
+        },
+        Commands::Generate { kind, mode, output, seed, randomizer } => {
+            let output_path = PathBuf::from(output);
+            run_generate(*kind, *mode, (*randomizer).into(), *seed, &output_path)
+        },
+        Commands::Send { file, port, number, delay } => {
+            let path = PathBuf::from(file);
+            run_send(&path, port, number, *delay)
+        },
+        Commands::SetParameter { port, channel, parameter, value } => {
+            run_set_parameter(port, parameter, value, *channel)
+        },
+        Commands::Receive { output, port, request, request_port, channel, timeout } => {
+            let output_path = PathBuf::from(output);
+            run_receive(&output_path, port, *request, request_port, *channel, *timeout)
         },
         Commands::Repl => {
-            run_repl().unwrap();
+            run_repl()
         },
+    };
+
+    if let Err(e) = result {
+        eprintln!("Error: {}", e);
+        std::process::exit(e.exit_code());
     }
 }
@@ -0,0 +1,178 @@
+//! Transferring System Exclusive messages to and from a synth over MIDI.
+
+use std::error::Error;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{debug, info};
+use midir::{Ignore, MidiInput, MidiInputPort, MidiOutput, MidiOutputConnection, MidiOutputPort};
+use syxpack::{INITIATOR, TERMINATOR};
+
+/// Finds a MIDI output port by index, or by a case-insensitive
+/// match of part of its name.
+pub fn find_output_port(midi_out: &MidiOutput, port: &str) -> Option<MidiOutputPort> {
+    let ports = midi_out.ports();
+
+    if let Ok(index) = port.parse::<usize>() {
+        return ports.get(index).cloned();
+    }
+
+    let wanted = port.to_lowercase();
+    ports.into_iter().find(|p| {
+        midi_out.port_name(p)
+            .map(|name| name.to_lowercase().contains(&wanted))
+            .unwrap_or(false)
+    })
+}
+
+/// Sends System Exclusive messages to a MIDI output port,
+/// waiting for `delay` after each message so that the receiving
+/// device has time to process it.
+pub fn send_messages(port: &str, messages: &[Vec<u8>], delay: Duration) -> Result<(), Box<dyn Error>> {
+    let midi_out = MidiOutput::new("sevenator output")?;
+
+    let Some(output_port) = find_output_port(&midi_out, port) else {
+        return Err(format!("MIDI output port '{}' not found", port).into());
+    };
+    let port_name = midi_out.port_name(&output_port)?;
+
+    let mut connection = midi_out.connect(&output_port, "sevenator-send")?;
+
+    for (index, message) in messages.iter().enumerate() {
+        debug!("Sending message {} ({} bytes) to '{}'", index + 1, message.len(), port_name);
+        connection.send(message)?;
+        thread::sleep(delay);
+    }
+
+    connection.close();
+    Ok(())
+}
+
+/// A connection to a MIDI output port that stays open, for sending
+/// messages one at a time as they are made, like parameter changes.
+pub struct OutputConnection {
+    port: String,
+    connection: MidiOutputConnection,
+}
+
+impl OutputConnection {
+    /// Opens a connection to a MIDI output port, found like in `find_output_port`.
+    pub fn open(port: &str) -> Result<Self, Box<dyn Error>> {
+        let midi_out = MidiOutput::new("sevenator output")?;
+
+        let Some(output_port) = find_output_port(&midi_out, port) else {
+            return Err(format!("MIDI output port '{}' not found", port).into());
+        };
+        debug!("Opening '{}'", midi_out.port_name(&output_port)?);
+
+        let connection = midi_out.connect(&output_port, "sevenator-live")?;
+        Ok(OutputConnection { port: port.to_string(), connection })
+    }
+
+    /// Gets the port as it was given when the connection was opened.
+    pub fn port(&self) -> &str {
+        &self.port
+    }
+
+    /// Sends a message right away.
+    pub fn send(&mut self, message: &[u8]) -> Result<(), Box<dyn Error>> {
+        debug!("Sending message ({} bytes) to '{}'", message.len(), self.port);
+        self.connection.send(message)?;
+        Ok(())
+    }
+}
+
+/// Finds a MIDI input port by index, or by a case-insensitive
+/// match of part of its name.
+pub fn find_input_port(midi_in: &MidiInput, port: &str) -> Option<MidiInputPort> {
+    let ports = midi_in.ports();
+
+    if let Ok(index) = port.parse::<usize>() {
+        return ports.get(index).cloned();
+    }
+
+    let wanted = port.to_lowercase();
+    ports.into_iter().find(|p| {
+        midi_in.port_name(p)
+            .map(|name| name.to_lowercase().contains(&wanted))
+            .unwrap_or(false)
+    })
+}
+
+/// A request to send to the synth after the input port is open,
+/// typically a dump request.
+pub struct Request<'a> {
+    pub port: &'a str,
+    pub message: Vec<u8>,
+}
+
+/// Receives System Exclusive messages from a MIDI input port until
+/// one is accepted by the `accept` function, and returns it.
+/// If a request is given, it is sent after the input port is open.
+/// Fails if no acceptable message arrives before the timeout.
+pub fn receive_message<F>(port: &str, request: Option<Request>, timeout: Duration, accept: F)
+        -> Result<Vec<u8>, Box<dyn Error>>
+        where F: Fn(&[u8]) -> bool {
+    let mut midi_in = MidiInput::new("sevenator input")?;
+    midi_in.ignore(Ignore::None);
+
+    let Some(input_port) = find_input_port(&midi_in, port) else {
+        return Err(format!("MIDI input port '{}' not found", port).into());
+    };
+    let port_name = midi_in.port_name(&input_port)?;
+
+    // The callback collects the bytes of each System Exclusive message,
+    // which may arrive in several parts, and passes complete messages on.
+    let (sender, receiver) = mpsc::channel::<Vec<u8>>();
+    let connection = midi_in.connect(
+        &input_port,
+        "sevenator-receive",
+        move |_timestamp, bytes, buffer: &mut Vec<u8>| {
+            for &b in bytes {
+                if b == INITIATOR {
+                    buffer.clear();
+                    buffer.push(b);
+                } else if b >= 0xf8 {
+                    // System real-time messages can appear anywhere, skip them
+                } else if !buffer.is_empty() {
+                    buffer.push(b);
+                    if b == TERMINATOR {
+                        let _ = sender.send(std::mem::take(buffer));
+                    }
+                }
+            }
+        },
+        Vec::new())?;
+
+    info!("Listening on '{}'", port_name);
+
+    if let Some(request) = request {
+        send_messages(request.port, &[request.message], Duration::from_millis(0))?;
+    }
+
+    let deadline = Instant::now() + timeout;
+    let result = loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        match receiver.recv_timeout(remaining) {
+            Ok(message) => {
+                debug!("Received System Exclusive message, {} bytes", message.len());
+                if accept(&message) {
+                    break Ok(message);
+                }
+                info!("Ignoring a System Exclusive message of {} bytes", message.len());
+            },
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                break Err(format!(
+                    "No System Exclusive message received from '{}' in {} seconds",
+                    port_name, timeout.as_secs()).into());
+            },
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                break Err("MIDI input was disconnected".into());
+            }
+        }
+    };
+
+    connection.close();
+    result
+}
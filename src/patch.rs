@@ -0,0 +1,519 @@
+//! Serializable representation of voices and cartridges, used for
+//! the JSON and TOML text formats. The field names and the names of the
+//! enumerated values are the same as in the XML documents.
+
+use serde::{Deserialize, Serialize};
+
+use crate::dx7::{Document, is_valid_voice_name};
+
+use sevenate::Ranged;
+use sevenate::dx7::{Algorithm, Transpose, Depth, Level, Coarse, Detune, Sensitivity};
+use sevenate::dx7::voice::{Voice, VoiceName, OPERATOR_COUNT};
+use sevenate::dx7::cartridge::{Cartridge, VOICE_COUNT};
+use sevenate::dx7::operator::{
+    CurveSign,
+    CurveStyle,
+    Key,
+    KeyboardLevelScaling,
+    Operator,
+    OperatorMode,
+    Scaling,
+    ScalingCurve
+};
+use sevenate::dx7::envelope::{Envelope, Rate};
+use sevenate::dx7::lfo::{Lfo, LfoWaveform};
+
+/// A cartridge of 32 voices.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CartridgePatch {
+    pub voices: Vec<VoicePatch>,
+}
+
+/// A single voice.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct VoicePatch {
+    pub name: String,
+    pub algorithm: i32,
+    pub transpose: i32,
+    pub feedback: i32,
+    pub oscillator_sync: bool,
+    pub pitch_modulation_sensitivity: i32,
+    pub peg: EnvelopePatch,
+    pub lfo: LfoPatch,
+    pub operators: Vec<OperatorPatch>,
+}
+
+/// Envelope rates and levels.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EnvelopePatch {
+    pub rates: [i32; 4],
+    pub levels: [i32; 4],
+}
+
+/// LFO waveform, with the same names as in XML.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum WavePatch {
+    Triangle,
+    SawDown,
+    SawUp,
+    Square,
+    Sine,
+    SampleAndHold,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LfoPatch {
+    pub speed: i32,
+    pub delay: i32,
+    pub pmd: i32,
+    pub amd: i32,
+    pub sync: bool,
+    pub wave: WavePatch,
+}
+
+/// Operator mode.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ModePatch {
+    Ratio,
+    Fixed,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct OperatorPatch {
+    pub level: i32,
+    pub mode: ModePatch,
+    pub coarse: i32,
+    pub fine: i32,
+    pub detune: i32,
+    pub amplitude_modulation_sensitivity: i32,
+    pub key_velocity_sensitivity: i32,
+    pub keyboard_rate_scaling: i32,
+    pub eg: EnvelopePatch,
+    pub keyboard_level_scaling: ScalingPatch,
+}
+
+/// Keyboard level scaling curve.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum CurvePatch {
+    #[serde(rename = "+LIN")]
+    LinPos,
+    #[serde(rename = "-LIN")]
+    LinNeg,
+    #[serde(rename = "+EXP")]
+    ExpPos,
+    #[serde(rename = "-EXP")]
+    ExpNeg,
+}
+
+/// A pair of left and right values.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LeftRight<T> {
+    pub left: T,
+    pub right: T,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScalingPatch {
+    pub breakpoint: i32,
+    pub depth: LeftRight<i32>,
+    pub curve: LeftRight<CurvePatch>,
+}
+
+//
+// Making patches from voices and cartridges
+//
+
+impl From<&Cartridge> for CartridgePatch {
+    fn from(cartridge: &Cartridge) -> Self {
+        CartridgePatch {
+            voices: cartridge.voices.iter().map(VoicePatch::from).collect(),
+        }
+    }
+}
+
+impl From<&Voice> for VoicePatch {
+    fn from(voice: &Voice) -> Self {
+        VoicePatch {
+            name: voice.name.value(),
+            algorithm: voice.alg.value(),
+            transpose: voice.transpose.value(),
+            feedback: voice.feedback.value(),
+            oscillator_sync: voice.osc_sync,
+            pitch_modulation_sensitivity: voice.pitch_mod_sens.value(),
+            peg: EnvelopePatch::from(&voice.peg),
+            lfo: LfoPatch::from(&voice.lfo),
+            operators: voice.operators.iter().map(OperatorPatch::from).collect(),
+        }
+    }
+}
+
+impl From<&Envelope> for EnvelopePatch {
+    fn from(envelope: &Envelope) -> Self {
+        EnvelopePatch {
+            rates: envelope.rates.map(|r| r.value()),
+            levels: envelope.levels.map(|l| l.value()),
+        }
+    }
+}
+
+impl From<&Lfo> for LfoPatch {
+    fn from(lfo: &Lfo) -> Self {
+        LfoPatch {
+            speed: lfo.speed.value(),
+            delay: lfo.delay.value(),
+            pmd: lfo.pmd.value(),
+            amd: lfo.amd.value(),
+            sync: lfo.sync,
+            wave: match lfo.waveform {
+                LfoWaveform::Triangle => WavePatch::Triangle,
+                LfoWaveform::SawDown => WavePatch::SawDown,
+                LfoWaveform::SawUp => WavePatch::SawUp,
+                LfoWaveform::Square => WavePatch::Square,
+                LfoWaveform::Sine => WavePatch::Sine,
+                LfoWaveform::SampleAndHold => WavePatch::SampleAndHold,
+            },
+        }
+    }
+}
+
+impl From<&Operator> for OperatorPatch {
+    fn from(op: &Operator) -> Self {
+        OperatorPatch {
+            level: op.output_level.value(),
+            mode: match op.mode {
+                OperatorMode::Ratio => ModePatch::Ratio,
+                OperatorMode::Fixed => ModePatch::Fixed,
+            },
+            coarse: op.coarse.value(),
+            fine: op.fine.value(),
+            detune: op.detune.value(),
+            amplitude_modulation_sensitivity: op.amp_mod_sens.value(),
+            key_velocity_sensitivity: op.key_vel_sens.value(),
+            keyboard_rate_scaling: op.kbd_rate_scaling.value(),
+            eg: EnvelopePatch::from(&op.eg),
+            keyboard_level_scaling: ScalingPatch::from(&op.kbd_level_scaling),
+        }
+    }
+}
+
+impl From<ScalingCurve> for CurvePatch {
+    fn from(curve: ScalingCurve) -> Self {
+        match (curve.sign, curve.style) {
+            (CurveSign::Positive, CurveStyle::Linear) => CurvePatch::LinPos,
+            (CurveSign::Negative, CurveStyle::Linear) => CurvePatch::LinNeg,
+            (CurveSign::Positive, CurveStyle::Exponential) => CurvePatch::ExpPos,
+            (CurveSign::Negative, CurveStyle::Exponential) => CurvePatch::ExpNeg,
+        }
+    }
+}
+
+impl From<&KeyboardLevelScaling> for ScalingPatch {
+    fn from(kls: &KeyboardLevelScaling) -> Self {
+        ScalingPatch {
+            breakpoint: kls.breakpoint.value(),
+            depth: LeftRight {
+                left: kls.left.depth.value(),
+                right: kls.right.depth.value(),
+            },
+            curve: LeftRight {
+                left: CurvePatch::from(kls.left.curve),
+                right: CurvePatch::from(kls.right.curve),
+            },
+        }
+    }
+}
+
+//
+// Making voices and cartridges from patches
+//
+// The errors are strings that start with the path of the value
+// in the patch, like "voices[3].operators[2].level".
+//
+
+// Converts an integer into a ranged value, rejecting values out of range.
+fn ranged<T: Ranged>(value: i32, name: &str) -> Result<T, String> {
+    if T::contains(value) {
+        Ok(T::new(value))
+    } else {
+        Err(format!("{}: value {} is out of range {}...{}", name, value, T::FIRST, T::LAST))
+    }
+}
+
+// Converts four integers into ranged values.
+fn ranged_four<T: Ranged + Copy>(values: &[i32; 4], name: &str) -> Result<[T; 4], String> {
+    let mut result = [T::new(T::DEFAULT); 4];
+    for (index, value) in values.iter().enumerate() {
+        result[index] = ranged(*value, &format!("{}[{}]", name, index + 1))?;
+    }
+    Ok(result)
+}
+
+// Prefixes the path in an error message with the path of the parent.
+fn within(parent: String) -> impl Fn(String) -> String {
+    move |e| format!("{}.{}", parent, e)
+}
+
+impl TryFrom<&CartridgePatch> for Cartridge {
+    type Error = String;
+
+    fn try_from(patch: &CartridgePatch) -> Result<Self, Self::Error> {
+        if patch.voices.len() != VOICE_COUNT {
+            return Err(format!("voices: found {} voices, expected {}",
+                patch.voices.len(), VOICE_COUNT));
+        }
+
+        let mut voices = Vec::<Voice>::new();
+        for (index, voice) in patch.voices.iter().enumerate() {
+            voices.push(Voice::try_from(voice)
+                .map_err(within(format!("voices[{}]", index + 1)))?);
+        }
+        Ok(Cartridge { voices })
+    }
+}
+
+impl TryFrom<&VoicePatch> for Voice {
+    type Error = String;
+
+    fn try_from(patch: &VoicePatch) -> Result<Self, Self::Error> {
+        let name = &patch.name;
+        if !is_valid_voice_name(name) {
+            return Err(format!("name: invalid value '{}', expected at most 10 printable ASCII characters", name));
+        }
+
+        if patch.operators.len() != OPERATOR_COUNT {
+            return Err(format!("operators: found {} operators, expected {}",
+                patch.operators.len(), OPERATOR_COUNT));
+        }
+
+        let mut voice = Voice::new();
+        for (index, op) in patch.operators.iter().enumerate() {
+            voice.operators[index] = Operator::try_from(op)
+                .map_err(within(format!("operators[{}]", index + 1)))?;
+        }
+
+        voice.name = VoiceName::new(name);
+        voice.alg = ranged::<Algorithm>(patch.algorithm, "algorithm")?;
+        voice.transpose = ranged::<Transpose>(patch.transpose, "transpose")?;
+        voice.feedback = ranged::<Depth>(patch.feedback, "feedback")?;
+        voice.osc_sync = patch.oscillator_sync;
+        voice.pitch_mod_sens = ranged::<Depth>(patch.pitch_modulation_sensitivity, "pitchModulationSensitivity")?;
+        voice.peg = Envelope::try_from(&patch.peg).map_err(within(String::from("peg")))?;
+        voice.lfo = Lfo::try_from(&patch.lfo).map_err(within(String::from("lfo")))?;
+
+        Ok(voice)
+    }
+}
+
+impl TryFrom<&EnvelopePatch> for Envelope {
+    type Error = String;
+
+    fn try_from(patch: &EnvelopePatch) -> Result<Self, Self::Error> {
+        Ok(Envelope::new_rate_level(
+            ranged_four::<Rate>(&patch.rates, "rates")?,
+            ranged_four::<Level>(&patch.levels, "levels")?))
+    }
+}
+
+impl TryFrom<&LfoPatch> for Lfo {
+    type Error = String;
+
+    fn try_from(patch: &LfoPatch) -> Result<Self, Self::Error> {
+        Ok(Lfo {
+            speed: ranged::<Level>(patch.speed, "speed")?,
+            delay: ranged::<Level>(patch.delay, "delay")?,
+            pmd: ranged::<Level>(patch.pmd, "pmd")?,
+            amd: ranged::<Level>(patch.amd, "amd")?,
+            sync: patch.sync,
+            waveform: match patch.wave {
+                WavePatch::Triangle => LfoWaveform::Triangle,
+                WavePatch::SawDown => LfoWaveform::SawDown,
+                WavePatch::SawUp => LfoWaveform::SawUp,
+                WavePatch::Square => LfoWaveform::Square,
+                WavePatch::Sine => LfoWaveform::Sine,
+                WavePatch::SampleAndHold => LfoWaveform::SampleAndHold,
+            },
+        })
+    }
+}
+
+impl TryFrom<&OperatorPatch> for Operator {
+    type Error = String;
+
+    fn try_from(patch: &OperatorPatch) -> Result<Self, Self::Error> {
+        Ok(Operator {
+            eg: Envelope::try_from(&patch.eg).map_err(within(String::from("eg")))?,
+            kbd_level_scaling: KeyboardLevelScaling::try_from(&patch.keyboard_level_scaling)
+                .map_err(within(String::from("keyboardLevelScaling")))?,
+            kbd_rate_scaling: ranged::<Depth>(patch.keyboard_rate_scaling, "keyboardRateScaling")?,
+            amp_mod_sens: ranged::<Sensitivity>(patch.amplitude_modulation_sensitivity, "amplitudeModulationSensitivity")?,
+            key_vel_sens: ranged::<Depth>(patch.key_velocity_sensitivity, "keyVelocitySensitivity")?,
+            output_level: ranged::<Level>(patch.level, "level")?,
+            mode: match patch.mode {
+                ModePatch::Ratio => OperatorMode::Ratio,
+                ModePatch::Fixed => OperatorMode::Fixed,
+            },
+            coarse: ranged::<Coarse>(patch.coarse, "coarse")?,
+            fine: ranged::<Level>(patch.fine, "fine")?,
+            detune: ranged::<Detune>(patch.detune, "detune")?,
+        })
+    }
+}
+
+impl From<CurvePatch> for ScalingCurve {
+    fn from(curve: CurvePatch) -> Self {
+        match curve {
+            CurvePatch::LinPos => ScalingCurve::lin_pos(),
+            CurvePatch::LinNeg => ScalingCurve::lin_neg(),
+            CurvePatch::ExpPos => ScalingCurve::exp_pos(),
+            CurvePatch::ExpNeg => ScalingCurve::exp_neg(),
+        }
+    }
+}
+
+impl TryFrom<&ScalingPatch> for KeyboardLevelScaling {
+    type Error = String;
+
+    fn try_from(patch: &ScalingPatch) -> Result<Self, Self::Error> {
+        Ok(KeyboardLevelScaling {
+            breakpoint: ranged::<Key>(patch.breakpoint, "breakpoint")?,
+            left: Scaling {
+                depth: ranged::<Level>(patch.depth.left, "depth.left")?,
+                curve: ScalingCurve::from(patch.curve.left),
+            },
+            right: Scaling {
+                depth: ranged::<Level>(patch.depth.right, "depth.right")?,
+                curve: ScalingCurve::from(patch.curve.right),
+            },
+        })
+    }
+}
+
+//
+// JSON
+//
+
+/// Converts a voice or a cartridge into a JSON document.
+pub fn to_json(document: &Document) -> String {
+    let result = match document {
+        Document::Voice(voice) => serde_json::to_string_pretty(&VoicePatch::from(voice.as_ref())),
+        Document::Cartridge(cartridge) => serde_json::to_string_pretty(&CartridgePatch::from(cartridge)),
+    };
+    let mut json = result.expect("patch should serialize into JSON");
+    json.push('\n');
+    json
+}
+
+/// Reads a voice or a cartridge from a JSON document.
+/// The document is a cartridge if it has a `voices` field.
+pub fn from_json(text: &str) -> Result<Document, String> {
+    let value: serde_json::Value = serde_json::from_str(text).map_err(|e| e.to_string())?;
+
+    // Deserialize again from the text, so that errors have line numbers.
+    if value.get("voices").is_some() {
+        let patch: CartridgePatch = serde_json::from_str(text).map_err(|e| e.to_string())?;
+        Ok(Document::Cartridge(Cartridge::try_from(&patch)?))
+    } else {
+        let patch: VoicePatch = serde_json::from_str(text).map_err(|e| e.to_string())?;
+        Ok(Document::Voice(Box::new(Voice::try_from(&patch)?)))
+    }
+}
+
+//
+// TOML
+//
+
+/// Converts a voice or a cartridge into a TOML document.
+/// The operators of a voice become an array of tables.
+pub fn to_toml(document: &Document) -> String {
+    let result = match document {
+        Document::Voice(voice) => toml::to_string(&VoicePatch::from(voice.as_ref())),
+        Document::Cartridge(cartridge) => toml::to_string(&CartridgePatch::from(cartridge)),
+    };
+    result.expect("patch should serialize into TOML")
+}
+
+/// Reads a voice or a cartridge from a TOML document.
+/// The document is a cartridge if it has a `voices` array of tables.
+pub fn from_toml(text: &str) -> Result<Document, String> {
+    let table: toml::Table = text.parse().map_err(|e: toml::de::Error| e.to_string())?;
+
+    if table.contains_key("voices") {
+        let patch: CartridgePatch = toml::from_str(text).map_err(|e| e.to_string())?;
+        Ok(Document::Cartridge(Cartridge::try_from(&patch)?))
+    } else {
+        let patch: VoicePatch = toml::from_str(text).map_err(|e| e.to_string())?;
+        Ok(Document::Voice(Box::new(Voice::try_from(&patch)?)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sevenate::dx7::sysex::SystemExclusiveData;
+
+    // The packed data of the ROM1A cartridge.
+    const ROM1A: [u8; 4096] = include!("rom1asyx.in");
+
+    fn rom1a() -> Cartridge {
+        Cartridge::parse(&ROM1A).expect("ROM1A should parse")
+    }
+
+    // Makes a voice with the given bytes as its name.
+    fn voice_named(name: &[u8; 10]) -> Voice {
+        let mut data = rom1a().voices[0].to_bytes();
+        data[145..155].copy_from_slice(name);
+        Voice::parse(&data).expect("voice should parse")
+    }
+
+    fn cartridge_bytes(document: &Document) -> Vec<u8> {
+        match document {
+            Document::Cartridge(cartridge) => cartridge.to_bytes(),
+            Document::Voice(_) => panic!("expected a cartridge"),
+        }
+    }
+
+    fn voice_bytes(document: &Document) -> Vec<u8> {
+        match document {
+            Document::Voice(voice) => voice.to_bytes(),
+            Document::Cartridge(_) => panic!("expected a voice"),
+        }
+    }
+
+    #[test]
+    fn json_round_trip_is_lossless() {
+        let document = Document::Cartridge(rom1a());
+        let result = from_json(&to_json(&document)).unwrap();
+        assert_eq!(cartridge_bytes(&result), ROM1A);
+    }
+
+    #[test]
+    fn toml_round_trip_is_lossless() {
+        let document = Document::Cartridge(rom1a());
+        let result = from_toml(&to_toml(&document)).unwrap();
+        assert_eq!(cartridge_bytes(&result), ROM1A);
+    }
+
+    #[test]
+    fn special_name_characters_round_trip() {
+        let voice = voice_named(b"A~\\^{|}`\"'");
+        let document = Document::Voice(Box::new(voice.clone()));
+        assert_eq!(voice_bytes(&from_json(&to_json(&document)).unwrap()), voice.to_bytes());
+        assert_eq!(voice_bytes(&from_toml(&to_toml(&document)).unwrap()), voice.to_bytes());
+    }
+
+    #[test]
+    fn rejects_names_that_are_not_printable() {
+        let document = Document::Voice(Box::new(voice_named(b"A\x7f\x01 TEST  ")));
+        assert!(from_json(&to_json(&document)).is_err());
+        assert!(from_toml(&to_toml(&document)).is_err());
+    }
+}
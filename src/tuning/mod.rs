@@ -0,0 +1,284 @@
+//! Micro-tuning tables of the DX7II and the TX802.
+//!
+//! A table tunes each key to a note number and a fine offset
+//! in steps of 1/85 semitone (about 1.18 cents). A full keyboard table
+//! tunes all the 128 keys separately, and an octave table tunes the keys
+//! C3...B3, repeating the same tuning in every octave.
+//!
+//! The tables are universal bulk dumps (see `crate::universal`) with
+//! the classification name "LM  MCRTE0" for an octave table and
+//! "LM  MCRTE1" for a full keyboard table, and two bytes for each key:
+//! the note number and the fine offset.
+
+pub mod scala;
+
+use std::fmt;
+use std::str::FromStr;
+
+use sevenate::dx7::sysex::MIDIChannel;
+
+use crate::error::Error;
+use crate::tx802::edit::note_name;
+use crate::universal::{CLASSIFICATION_LENGTH, classification, universal_data, make_universal_message};
+
+/// Classification name of an octave tuning table.
+pub const OCTAVE_CLASSIFICATION: &[u8; CLASSIFICATION_LENGTH] = b"LM  MCRTE0";
+
+/// Classification name of a full keyboard tuning table.
+pub const KEYBOARD_CLASSIFICATION: &[u8; CLASSIFICATION_LENGTH] = b"LM  MCRTE1";
+
+/// Number of fine steps in a semitone.
+pub const FINE_STEPS: u8 = 85;
+
+/// The first key of an octave table, C3.
+pub const OCTAVE_FIRST_KEY: u8 = 60;
+
+/// The extent of a tuning table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TuningKind {
+    /// The twelve keys of an octave, repeated over the keyboard
+    Octave,
+    /// All the 128 keys separately
+    Keyboard,
+}
+
+impl TuningKind {
+    /// Gets the number of keys in a table of this kind.
+    pub fn key_count(&self) -> usize {
+        match self {
+            TuningKind::Octave => 12,
+            TuningKind::Keyboard => 128,
+        }
+    }
+
+    /// Gets the first key of a table of this kind.
+    pub fn first_key(&self) -> u8 {
+        match self {
+            TuningKind::Octave => OCTAVE_FIRST_KEY,
+            TuningKind::Keyboard => 0,
+        }
+    }
+
+    fn classification(&self) -> &'static [u8; CLASSIFICATION_LENGTH] {
+        match self {
+            TuningKind::Octave => OCTAVE_CLASSIFICATION,
+            TuningKind::Keyboard => KEYBOARD_CLASSIFICATION,
+        }
+    }
+}
+
+impl fmt::Display for TuningKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TuningKind::Octave => write!(f, "octave"),
+            TuningKind::Keyboard => write!(f, "keyboard"),
+        }
+    }
+}
+
+impl FromStr for TuningKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "octave" => Ok(TuningKind::Octave),
+            "keyboard" => Ok(TuningKind::Keyboard),
+            _ => Err(format!("unknown tuning kind '{}', expected octave or keyboard", s)),
+        }
+    }
+}
+
+/// The tuning of one key: a note number, and a fine offset
+/// above it in steps of 1/85 semitone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyTuning {
+    pub note: u8,
+    pub fine: u8,
+}
+
+impl KeyTuning {
+    /// Makes the tuning nearest to a pitch in cents above MIDI note 0 (C-2).
+    /// Pitches outside the range of the MIDI notes are errors.
+    pub fn from_cents(cents: f64) -> Result<Self, Error> {
+        let steps = (cents / 100.0 * FINE_STEPS as f64).round();
+        let note = (steps / FINE_STEPS as f64).floor();
+        if !(0.0..=127.0).contains(&note) {
+            return Err(Error::Range(format!("Pitch of {:.1} cents is outside the MIDI notes", cents)));
+        }
+        Ok(KeyTuning {
+            note: note as u8,
+            fine: (steps - note * FINE_STEPS as f64) as u8,
+        })
+    }
+
+    /// Gets the pitch in cents above MIDI note 0 (C-2).
+    pub fn cents(&self) -> f64 {
+        self.note as f64 * 100.0 + self.fine as f64 * 100.0 / FINE_STEPS as f64
+    }
+}
+
+/// A micro-tuning table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TuningTable {
+    pub kind: TuningKind,
+    pub keys: Vec<KeyTuning>,
+}
+
+impl TuningTable {
+    /// Makes a table in equal temperament, where each key plays its own note.
+    pub fn equal_temperament(kind: TuningKind) -> Self {
+        TuningTable {
+            kind,
+            keys: (0..kind.key_count())
+                .map(|index| KeyTuning { note: kind.first_key() + index as u8, fine: 0 })
+                .collect(),
+        }
+    }
+
+    /// Makes a table from the pitch of each key in cents above MIDI note 0.
+    /// There must be a pitch for each key of the table.
+    pub fn from_cents(kind: TuningKind, cents: &[f64]) -> Result<Self, Error> {
+        if cents.len() != kind.key_count() {
+            return Err(Error::Range(format!("Tables of the {} kind have {} keys, got {}",
+                kind, kind.key_count(), cents.len())));
+        }
+        let keys = cents.iter()
+            .map(|&c| KeyTuning::from_cents(c))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(TuningTable { kind, keys })
+    }
+
+    /// Parses the data of a table, two bytes for each key.
+    /// Values out of range are errors.
+    pub fn parse(kind: TuningKind, data: &[u8]) -> Result<Self, Error> {
+        if data.len() != kind.key_count() * 2 {
+            return Err(Error::Header(format!("Got {} bytes of tuning data, expected {}",
+                data.len(), kind.key_count() * 2)));
+        }
+
+        let mut keys = Vec::<KeyTuning>::new();
+        for (index, pair) in data.chunks_exact(2).enumerate() {
+            let key = kind.first_key() + index as u8;
+            if pair[0] > 127 {
+                return Err(Error::Range(format!("key {}: note is {}, expected 0...127", note_name(key), pair[0])));
+            }
+            if pair[1] >= FINE_STEPS {
+                return Err(Error::Range(format!("key {}: fine is {}, expected 0...{}",
+                    note_name(key), pair[1], FINE_STEPS - 1)));
+            }
+            keys.push(KeyTuning { note: pair[0], fine: pair[1] });
+        }
+        Ok(TuningTable { kind, keys })
+    }
+
+    /// Makes the data of the table, two bytes for each key.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.keys.iter().flat_map(|key| [key.note, key.fine]).collect()
+    }
+}
+
+impl fmt::Display for TuningTable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{} tuning table", if self.kind == TuningKind::Octave { "Octave" } else { "Full keyboard" })?;
+        writeln!(f, "KEY        NOTE FINE     CENTS  OFFSET")?;
+        for (index, tuning) in self.keys.iter().enumerate() {
+            let key = self.kind.first_key() + index as u8;
+            writeln!(f, "{:<4} {:3}  {:<4} {:4} {:9.1} {:+7.1}",
+                note_name(key), key,
+                note_name(tuning.note), tuning.fine,
+                tuning.cents(),
+                tuning.cents() - key as f64 * 100.0)?;
+        }
+        Ok(())
+    }
+}
+
+/// Gets the kind of a message if it is a micro-tuning table bulk dump,
+/// by its format and classification name. Does not check the rest.
+pub fn tuning_kind(message: &[u8]) -> Option<TuningKind> {
+    match classification(message) {
+        Some(name) if name == OCTAVE_CLASSIFICATION => Some(TuningKind::Octave),
+        Some(name) if name == KEYBOARD_CLASSIFICATION => Some(TuningKind::Keyboard),
+        _ => None,
+    }
+}
+
+/// Parses a micro-tuning table bulk dump, after checking its header,
+/// byte count and checksum.
+pub fn parse_tuning(message: &[u8]) -> Result<TuningTable, Error> {
+    let Some(kind) = tuning_kind(message) else {
+        return Err(Error::Header(String::from("Not a micro-tuning table bulk dump")));
+    };
+    let data = universal_data(message, kind.classification(), kind.key_count() * 2, "micro-tuning table")?;
+    TuningTable::parse(kind, data)
+}
+
+/// Makes a micro-tuning table bulk dump with the header and checksum.
+pub fn make_tuning_message(table: &TuningTable, channel: MIDIChannel) -> Vec<u8> {
+    make_universal_message(table.kind.classification(), &table.to_bytes(), channel)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sevenate::Ranged;
+    use crate::tuning::scala::{parse_scl, keyboard_cents, KeyboardMapping};
+
+    // Makes a table where each key is tuned a little differently.
+    fn uneven_table(kind: TuningKind) -> TuningTable {
+        TuningTable {
+            kind,
+            keys: (0..kind.key_count())
+                .map(|index| KeyTuning { note: (127 - index) as u8, fine: (index * 7 % FINE_STEPS as usize) as u8 })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn table_round_trip_is_lossless() {
+        for kind in [TuningKind::Octave, TuningKind::Keyboard] {
+            let table = uneven_table(kind);
+            assert_eq!(TuningTable::parse(kind, &table.to_bytes()).unwrap(), table);
+
+            let message = make_tuning_message(&table, MIDIChannel::new(1));
+            assert_eq!(tuning_kind(&message), Some(kind));
+            assert_eq!(parse_tuning(&message).unwrap(), table);
+        }
+    }
+
+    #[test]
+    fn messages_have_the_classification_names() {
+        let octave = make_tuning_message(&TuningTable::equal_temperament(TuningKind::Octave), MIDIChannel::new(1));
+        assert_eq!(&octave[6..16], b"LM  MCRTE0");
+        assert_eq!(octave.len(), 6 + 10 + 24 + 2);
+        let keyboard = make_tuning_message(&TuningTable::equal_temperament(TuningKind::Keyboard), MIDIChannel::new(1));
+        assert_eq!(&keyboard[6..16], b"LM  MCRTE1");
+        assert_eq!(keyboard.len(), 6 + 10 + 256 + 2);
+    }
+
+    #[test]
+    fn rejects_values_out_of_range() {
+        let mut data = TuningTable::equal_temperament(TuningKind::Octave).to_bytes();
+        data[1] = FINE_STEPS;
+        assert!(matches!(TuningTable::parse(TuningKind::Octave, &data), Err(Error::Range(_))));
+        data[1] = 0;
+        data[0] = 128;
+        assert!(matches!(TuningTable::parse(TuningKind::Octave, &data), Err(Error::Range(_))));
+    }
+
+    #[test]
+    fn equal_scale_makes_equal_temperament() {
+        let text = "12-TET\n12\n100.\n200.\n300.\n400.\n500.\n600.\n700.\n800.\n900.\n1000.\n1100.\n2/1\n";
+        let cents = keyboard_cents(&parse_scl(text).unwrap(), &KeyboardMapping::default()).unwrap();
+        let table = TuningTable::from_cents(TuningKind::Keyboard, &cents).unwrap();
+        assert_eq!(table, TuningTable::equal_temperament(TuningKind::Keyboard));
+    }
+
+    #[test]
+    fn rounds_cents_to_fine_steps() {
+        assert_eq!(KeyTuning::from_cents(6000.0).unwrap(), KeyTuning { note: 60, fine: 0 });
+        assert_eq!(KeyTuning::from_cents(6050.0).unwrap(), KeyTuning { note: 60, fine: 43 });
+        assert_eq!(KeyTuning::from_cents(6099.9).unwrap(), KeyTuning { note: 61, fine: 0 });
+        assert!(KeyTuning::from_cents(-10.0).is_err());
+    }
+}
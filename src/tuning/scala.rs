@@ -0,0 +1,305 @@
+//! Scala scale (`.scl`) and keyboard mapping (`.kbm`) files,
+//! see https://www.huygens-fokker.org/scala/scl_format.html
+//! and https://www.huygens-fokker.org/scala/help.htm#mappings.
+
+/// Frequency of MIDI note 0 (C-2) in equal temperament at A = 440 Hz.
+const NOTE_0_FREQUENCY: f64 = 8.175798915643707;
+
+/// A scale: the pitches of the degrees above the first one, in cents.
+/// The last degree is the period of the scale, usually the octave.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Scale {
+    pub description: String,
+    pub cents: Vec<f64>,
+}
+
+impl Scale {
+    /// Gets the pitch of a scale degree in cents above degree 0.
+    /// Degrees below zero and above the period repeat the scale.
+    pub fn degree_cents(&self, degree: i32) -> f64 {
+        let count = self.cents.len() as i32;
+        let period = self.cents[self.cents.len() - 1];
+        let index = degree.rem_euclid(count);
+        let step = if index == 0 { 0.0 } else { self.cents[index as usize - 1] };
+        degree.div_euclid(count) as f64 * period + step
+    }
+}
+
+/// A keyboard mapping, which maps the keys to scale degrees
+/// and sets the frequency of one key.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyboardMapping {
+    /// Size of the repeating pattern of keys, or 0 for a linear mapping
+    pub size: usize,
+    pub first_note: u8,
+    pub last_note: u8,
+    /// Key where the scale degree 0 is mapped
+    pub middle_note: u8,
+    /// Key that has the reference frequency
+    pub reference_note: u8,
+    pub reference_frequency: f64,
+    /// Scale degree that is the period of the mapping, or 0 for the period of the scale
+    pub octave_degree: usize,
+    /// Scale degree for each key of the pattern, or none for keys that are not mapped
+    pub mapping: Vec<Option<i32>>,
+}
+
+impl Default for KeyboardMapping {
+    /// The linear mapping that Scala uses without a mapping file:
+    /// degree 0 on middle C (60), which has its frequency in equal temperament.
+    fn default() -> Self {
+        KeyboardMapping {
+            size: 0,
+            first_note: 0,
+            last_note: 127,
+            middle_note: 60,
+            reference_note: 60,
+            reference_frequency: 261.6255653005986,
+            octave_degree: 0,
+            mapping: Vec::new(),
+        }
+    }
+}
+
+impl KeyboardMapping {
+    /// Gets the scale degree of a key, if it is mapped.
+    pub fn degree(&self, key: u8, scale: &Scale) -> Option<i32> {
+        if key < self.first_note || key > self.last_note {
+            return None;
+        }
+
+        let offset = key as i32 - self.middle_note as i32;
+        if self.size == 0 {
+            return Some(offset);
+        }
+
+        let size = self.size as i32;
+        let octave_degree = if self.octave_degree == 0 { scale.cents.len() } else { self.octave_degree } as i32;
+        let degree = (*self.mapping.get(offset.rem_euclid(size) as usize)?)?;
+        Some(offset.div_euclid(size) * octave_degree + degree)
+    }
+}
+
+// Gets the lines of a Scala file that are not comments.
+fn data_lines(text: &str) -> impl Iterator<Item = &str> {
+    text.lines().filter(|line| !line.starts_with('!'))
+}
+
+// Parses a pitch line of a scale: cents if there is a period,
+// otherwise a ratio like "3/2" or an integer like "2".
+fn parse_pitch(line: &str) -> Result<f64, String> {
+    let value = line.split_whitespace().next().unwrap_or("");
+    if value.contains('.') {
+        return value.parse::<f64>().map_err(|_| format!("invalid cents value '{}'", value));
+    }
+
+    let (numerator, denominator) = value.split_once('/').unwrap_or((value, "1"));
+    match (numerator.parse::<u64>(), denominator.parse::<u64>()) {
+        (Ok(n), Ok(d)) if n > 0 && d > 0 => Ok(1200.0 * (n as f64 / d as f64).log2()),
+        _ => Err(format!("invalid ratio '{}'", value)),
+    }
+}
+
+/// Parses the text of a scale file.
+pub fn parse_scl(text: &str) -> Result<Scale, String> {
+    let mut lines = data_lines(text);
+    let description = lines.next().ok_or("missing description")?.trim().to_string();
+    let count_line = lines.next().ok_or("missing number of notes")?;
+    let count = count_line.split_whitespace().next().unwrap_or("")
+        .parse::<usize>()
+        .map_err(|_| format!("invalid number of notes '{}'", count_line.trim()))?;
+    if count == 0 {
+        return Err(String::from("the scale has no notes"));
+    }
+
+    let cents = lines.take(count)
+        .map(parse_pitch)
+        .collect::<Result<Vec<_>, _>>()?;
+    if cents.len() != count {
+        return Err(format!("expected {} notes, got {}", count, cents.len()));
+    }
+
+    Ok(Scale { description, cents })
+}
+
+/// Parses the text of a keyboard mapping file.
+pub fn parse_kbm(text: &str) -> Result<KeyboardMapping, String> {
+    let mut values = data_lines(text).map(|line| line.split_whitespace().next().unwrap_or(""));
+    let mut next = |name: &str| values.next().ok_or(format!("missing {}", name));
+
+    let integer = |value: &str, name: &str, max: usize| value.parse::<usize>().ok()
+        .filter(|n| *n <= max)
+        .ok_or(format!("invalid {} '{}'", name, value));
+
+    let size = integer(next("map size")?, "map size", 127)?;
+    let first_note = integer(next("first note")?, "first note", 127)? as u8;
+    let last_note = integer(next("last note")?, "last note", 127)? as u8;
+    let middle_note = integer(next("middle note")?, "middle note", 127)? as u8;
+    let reference_note = integer(next("reference note")?, "reference note", 127)? as u8;
+    let frequency = next("reference frequency")?;
+    let reference_frequency = frequency.parse::<f64>().ok()
+        .filter(|f| *f > 0.0)
+        .ok_or(format!("invalid reference frequency '{}'", frequency))?;
+    let octave_degree = integer(next("octave degree")?, "octave degree", usize::MAX)?;
+
+    // Keys after the last mapping entry are not mapped.
+    let mut mapping = Vec::<Option<i32>>::new();
+    for _ in 0..size {
+        match values.next() {
+            Some("x") | None => mapping.push(None),
+            Some(value) => mapping.push(Some(value.parse::<i32>().map_err(|_| format!("invalid mapping entry '{}'", value))?)),
+        }
+    }
+
+    Ok(KeyboardMapping {
+        size,
+        first_note,
+        last_note,
+        middle_note,
+        reference_note,
+        reference_frequency,
+        octave_degree,
+        mapping,
+    })
+}
+
+/// Gets the pitch of each of the 128 keys in cents above MIDI note 0 (C-2).
+/// Keys that are not mapped keep their pitch in equal temperament.
+pub fn keyboard_cents(scale: &Scale, mapping: &KeyboardMapping) -> Result<Vec<f64>, String> {
+    let Some(reference_degree) = mapping.degree(mapping.reference_note, scale) else {
+        return Err(format!("the reference note {} is not mapped", mapping.reference_note));
+    };
+    let reference_cents = 1200.0 * (mapping.reference_frequency / NOTE_0_FREQUENCY).log2();
+
+    Ok((0..=127u8)
+        .map(|key| match mapping.degree(key, scale) {
+            Some(degree) => reference_cents + scale.degree_cents(degree) - scale.degree_cents(reference_degree),
+            None => key as f64 * 100.0,
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EQUAL: &str = "! 12-tet.scl
+!
+12 tone equal temperament
+ 12
+!
+ 100.0
+ 200.
+ 300.0
+ 400.0
+ 500.0
+ 600.0
+ 700.0
+ 800.0
+ 900.0
+ 1000.0
+ 1100.0
+ 2/1
+";
+
+    const JUST: &str = "! just.scl
+Just major scale
+7
+9/8
+5/4
+4/3
+3/2 perfect fifth
+5/3
+15/8
+2
+";
+
+    // The white keys of each octave play the scale from C, the black keys are not mapped.
+    const WHITE_KEYS: &str = "! white.kbm
+12
+0
+127
+60
+69
+440.0
+7
+! Mapping
+0
+x
+1
+x
+2
+3
+x
+4
+x
+5
+x
+6
+";
+
+    fn assert_near(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-6, "{} is not {}", a, b);
+    }
+
+    #[test]
+    fn parses_cents_and_comments() {
+        let scale = parse_scl(EQUAL).unwrap();
+        assert_eq!(scale.description, "12 tone equal temperament");
+        assert_eq!(scale.cents.len(), 12);
+        assert_near(scale.cents[1], 200.0);
+        assert_near(scale.cents[11], 1200.0);
+    }
+
+    #[test]
+    fn equal_temperament_keeps_the_notes() {
+        let cents = keyboard_cents(&parse_scl(EQUAL).unwrap(), &KeyboardMapping::default()).unwrap();
+        for (key, c) in cents.iter().enumerate() {
+            assert_near(*c, key as f64 * 100.0);
+        }
+    }
+
+    #[test]
+    fn parses_ratios() {
+        let scale = parse_scl(JUST).unwrap();
+        assert_eq!(scale.cents.len(), 7);
+        assert_near(scale.cents[0], 1200.0 * (9.0f64 / 8.0).log2());
+        assert_near(scale.cents[3], 1200.0 * 1.5f64.log2());
+        assert_near(scale.cents[6], 1200.0);
+        assert_near(scale.degree_cents(-1), 1200.0 * (15.0f64 / 8.0).log2() - 1200.0);
+    }
+
+    #[test]
+    fn rejects_invalid_scales() {
+        assert!(parse_scl("Too short\n3\n100.0\n").is_err());
+        assert!(parse_scl("Bad ratio\n1\n3/0\n").is_err());
+        assert!(parse_scl("No notes\n0\n").is_err());
+    }
+
+    #[test]
+    fn maps_keys_with_unmapped_ones() {
+        let scale = parse_scl(JUST).unwrap();
+        let mapping = parse_kbm(WHITE_KEYS).unwrap();
+        assert_eq!(mapping.size, 12);
+        assert_eq!(mapping.reference_note, 69);
+        assert_eq!(mapping.octave_degree, 7);
+        assert_eq!(mapping.degree(60, &scale), Some(0));
+        assert_eq!(mapping.degree(61, &scale), None);
+        assert_eq!(mapping.degree(69, &scale), Some(5));
+        assert_eq!(mapping.degree(72, &scale), Some(7));
+        assert_eq!(mapping.degree(59, &scale), Some(-1));
+
+        let cents = keyboard_cents(&scale, &mapping).unwrap();
+        assert_near(cents[69], 6900.0);  // A3 is 440 Hz
+        assert_near(cents[60], 6900.0 - 1200.0 * (5.0f64 / 3.0).log2());  // C3 is a major sixth below
+        assert_near(cents[61], 6100.0);  // unmapped keys stay in equal temperament
+        assert_near(cents[72] - cents[60], 1200.0);
+    }
+
+    #[test]
+    fn rejects_unmapped_reference_note() {
+        let scale = parse_scl(JUST).unwrap();
+        let mapping = KeyboardMapping { reference_note: 61, ..parse_kbm(WHITE_KEYS).unwrap() };
+        assert!(keyboard_cents(&scale, &mapping).is_err());
+    }
+}
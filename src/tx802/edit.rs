@@ -0,0 +1,332 @@
+//! Editing TX802 performances: assigning voices to the tone generators,
+//! setting their parameters, and making key splits and layers.
+//! Performance numbers are 1...64 and tone generator numbers 1...8
+//! like on the TX802.
+
+use crate::error::Error;
+use crate::tx802::{
+    Performance,
+    PerformanceMemory,
+    ToneGenerator,
+    PARAMETERS,
+    PERFORMANCE_COUNT,
+    TONE_GENERATOR_COUNT,
+    NAME_LENGTH,
+};
+
+// Checks that a performance number is 1...64, and returns the index of the performance.
+fn performance_index(number: usize) -> Result<usize, Error> {
+    if (1..=PERFORMANCE_COUNT).contains(&number) {
+        Ok(number - 1)
+    } else {
+        Err(Error::Range(format!("Performance number must be 1...{}, got {}", PERFORMANCE_COUNT, number)))
+    }
+}
+
+// Checks that a tone generator number is 1...8, and returns the index of the tone generator.
+fn tone_generator_index(number: usize) -> Result<usize, Error> {
+    if (1..=TONE_GENERATOR_COUNT).contains(&number) {
+        Ok(number - 1)
+    } else {
+        Err(Error::Range(format!("Tone generator number must be 1...{}, got {}", TONE_GENERATOR_COUNT, number)))
+    }
+}
+
+/// Gets a performance of the performance memory for editing.
+pub fn performance_mut(memory: &mut PerformanceMemory, number: usize) -> Result<&mut Performance, Error> {
+    let index = performance_index(number)?;
+    Ok(&mut memory.performances[index])
+}
+
+/// Gets a performance of the performance memory.
+pub fn performance(memory: &PerformanceMemory, number: usize) -> Result<&Performance, Error> {
+    let index = performance_index(number)?;
+    Ok(&memory.performances[index])
+}
+
+/// Parses a voice number like it is shown on the TX802, "I01"..."I64"
+/// for the internal voices and "C01"..."C64" for the cartridge voices,
+/// into the value of the voice number parameter.
+pub fn parse_voice_number(s: &str) -> Result<u8, Error> {
+    let invalid = || Error::Range(format!("Voice must be I01...I64 or C01...C64, got '{}'", s));
+
+    let (bank, number) = s.split_at_checked(1).ok_or_else(invalid)?;
+    let number = number.parse::<u8>().map_err(|_| invalid())?;
+    if !(1..=64).contains(&number) {
+        return Err(invalid());
+    }
+
+    match bank {
+        "I" | "i" => Ok(number - 1),
+        "C" | "c" => Ok(number + 63),
+        _ => Err(invalid()),
+    }
+}
+
+/// Describes the value of the voice number parameter like on the TX802,
+/// "I01"..."I64" for the internal voices and "C01"..."C64" for the cartridge.
+pub fn voice_number_name(value: u8) -> String {
+    if value < 64 {
+        format!("I{:02}", value + 1)
+    } else {
+        format!("C{:02}", value - 63)
+    }
+}
+
+const NOTE_NAMES: [&str; 12] = ["C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B"];
+
+/// Parses a MIDI note number 0...127, or a note name like "C3" or "F#-1"
+/// in the Yamaha convention where middle C (60) is C3 and the range
+/// is C-2...G8.
+pub fn parse_note(s: &str) -> Result<u8, Error> {
+    let invalid = || Error::Range(format!("Note must be 0...127 or C-2...G8, got '{}'", s));
+
+    if let Ok(number) = s.parse::<u8>() {
+        return if number <= 127 { Ok(number) } else { Err(invalid()) };
+    }
+
+    let upper = s.to_uppercase();
+    let name_length = if upper.get(1..2) == Some("#") { 2 } else { 1 };
+    let (name, octave) = upper.split_at_checked(name_length).ok_or_else(invalid)?;
+    let name = NOTE_NAMES.iter().position(|n| *n == name).ok_or_else(invalid)?;
+    let octave = octave.parse::<i32>().map_err(|_| invalid())?;
+
+    let number = (octave + 2) * 12 + name as i32;
+    if (0..=127).contains(&number) {
+        Ok(number as u8)
+    } else {
+        Err(invalid())
+    }
+}
+
+/// Makes the name of a MIDI note number, like "C3" for 60.
+pub fn note_name(number: u8) -> String {
+    format!("{}{}", NOTE_NAMES[number as usize % 12], number as i32 / 12 - 2)
+}
+
+/// Checks that a performance name fits and has only printable ASCII characters.
+pub fn check_name(name: &str) -> Result<(), Error> {
+    if name.chars().count() > NAME_LENGTH || !name.chars().all(|c| (' '..='~').contains(&c)) {
+        return Err(Error::Range(format!("Performance name must be at most {} ASCII characters, got '{}'",
+            NAME_LENGTH, name)));
+    }
+    Ok(())
+}
+
+/// Replaces a performance with an initialized one that has the given name.
+pub fn init_performance(memory: &mut PerformanceMemory, number: usize, name: &str) -> Result<(), Error> {
+    check_name(name)?;
+    let performance = performance_mut(memory, number)?;
+    *performance = Performance { name: name.to_string(), ..Performance::new() };
+    Ok(())
+}
+
+/// Assigns a voice to a tone generator. The voice is the value
+/// of the voice number parameter, see `parse_voice_number`.
+pub fn assign_voice(performance: &mut Performance, tg: usize, voice: u8) -> Result<(), Error> {
+    set_parameter(performance, tg, "voiceNumber", voice)
+}
+
+// Finds a tone generator parameter by its XML attribute name or its name,
+// ignoring case, spaces, hyphens and underscores. Returns its index.
+fn find_parameter(name: &str) -> Result<usize, Error> {
+    let simplify = |s: &str| s.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .collect::<String>()
+        .to_lowercase();
+
+    let wanted = simplify(name);
+    PARAMETERS.iter()
+        .position(|p| simplify(p.attribute) == wanted || simplify(p.name) == wanted)
+        .ok_or_else(|| Error::Range(format!("Unknown tone generator parameter '{}', expected one of {}",
+            name, PARAMETERS.iter().map(|p| p.attribute).collect::<Vec<_>>().join(", "))))
+}
+
+/// Sets a parameter of a tone generator, given by its XML attribute name
+/// like "outputVolume" or by its name like "output-volume".
+/// The value is the raw value in the bulk dump.
+pub fn set_parameter(performance: &mut Performance, tg: usize, name: &str, value: u8) -> Result<(), Error> {
+    let tg_index = tone_generator_index(tg)?;
+    let index = find_parameter(name)?;
+    let parameter = &PARAMETERS[index];
+    if value > parameter.maximum {
+        return Err(Error::Range(format!("TG{} {} must be 0...{}, got {}",
+            tg, parameter.name, parameter.maximum, value)));
+    }
+
+    let tone_generator = &mut performance.tone_generators[tg_index];
+    let mut values = tone_generator.values();
+    values[index] = value;
+    *tone_generator = ToneGenerator::from_values(values);
+    Ok(())
+}
+
+/// Sets the key range of a tone generator. The low limit must not be
+/// above the high limit.
+pub fn set_note_limits(performance: &mut Performance, tg: usize, low: u8, high: u8) -> Result<(), Error> {
+    if low > high {
+        return Err(Error::Range(format!("Low note limit {} is above the high note limit {}",
+            note_name(low), note_name(high))));
+    }
+    set_parameter(performance, tg, "noteLimitLow", low)?;
+    set_parameter(performance, tg, "noteLimitHigh", high)
+}
+
+/// Makes a key split: tone generators 1...4 play the lower voice below
+/// the split note, and tone generators 5...8 play the upper voice from
+/// the split note up. Each half is one voice channel, so it has the
+/// polyphony of four tone generators. All the tone generators receive
+/// on the channel of tone generator 1.
+pub fn make_split(performance: &mut Performance, split: u8, lower: u8, upper: u8) -> Result<(), Error> {
+    if split == 0 || split > 127 {
+        return Err(Error::Range(format!("Split note must be 1...127, got {}", split)));
+    }
+    make_groups(performance, &[(lower, 0, split - 1), (upper, split, 127)])
+}
+
+/// Makes a layer of one to eight voices over the whole keyboard.
+/// The tone generators are shared between the voices as evenly as possible,
+/// with each voice on its own voice channel. All the tone generators
+/// receive on the channel of tone generator 1.
+pub fn make_layer(performance: &mut Performance, voices: &[u8]) -> Result<(), Error> {
+    if voices.is_empty() || voices.len() > TONE_GENERATOR_COUNT {
+        return Err(Error::Range(format!("A layer must have 1...{} voices, got {}",
+            TONE_GENERATOR_COUNT, voices.len())));
+    }
+    let groups: Vec<(u8, u8, u8)> = voices.iter().map(|&voice| (voice, 0, 127)).collect();
+    make_groups(performance, &groups)
+}
+
+// Shares the tone generators between the groups of (voice, low note, high note)
+// in order, giving each group its own voice channel offset.
+fn make_groups(performance: &mut Performance, groups: &[(u8, u8, u8)]) -> Result<(), Error> {
+    let receive_channel = performance.tone_generators[0].receive_channel;
+    for tg in 1..=TONE_GENERATOR_COUNT {
+        let group = (tg - 1) * groups.len() / TONE_GENERATOR_COUNT;
+        let (voice, low, high) = groups[group];
+        assign_voice(performance, tg, voice)?;
+        set_note_limits(performance, tg, low, high)?;
+        set_parameter(performance, tg, "voiceChannelOffset", group as u8)?;
+        set_parameter(performance, tg, "receiveChannel", receive_channel)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Gets (voice number, low note, high note, voice channel offset) of each tone generator.
+    fn assignments(performance: &Performance) -> Vec<(u8, u8, u8, u8)> {
+        performance.tone_generators.iter()
+            .map(|tg| (tg.voice_number, tg.note_limit_low, tg.note_limit_high, tg.voice_channel_offset))
+            .collect()
+    }
+
+    #[test]
+    fn parses_voice_numbers() {
+        assert_eq!(parse_voice_number("I01").unwrap(), 0);
+        assert_eq!(parse_voice_number("I64").unwrap(), 63);
+        assert_eq!(parse_voice_number("C01").unwrap(), 64);
+        assert_eq!(parse_voice_number("c64").unwrap(), 127);
+        for s in ["I00", "I65", "C00", "C65", "X01", "I", "", "I-1"] {
+            assert!(matches!(parse_voice_number(s), Err(Error::Range(_))), "{}", s);
+        }
+    }
+
+    #[test]
+    fn names_voice_numbers() {
+        for s in ["I01", "I64", "C01", "C64"] {
+            assert_eq!(voice_number_name(parse_voice_number(s).unwrap()), s);
+        }
+    }
+
+    #[test]
+    fn parses_notes() {
+        assert_eq!(parse_note("C3").unwrap(), 60);
+        assert_eq!(parse_note("C-2").unwrap(), 0);
+        assert_eq!(parse_note("G8").unwrap(), 127);
+        assert_eq!(parse_note("f#-1").unwrap(), 18);
+        assert_eq!(parse_note("64").unwrap(), 64);
+        for s in ["G#8", "B-3", "128", "H3", "C", ""] {
+            assert!(matches!(parse_note(s), Err(Error::Range(_))), "{}", s);
+        }
+    }
+
+    #[test]
+    fn names_notes() {
+        assert_eq!(note_name(60), "C3");
+        assert_eq!(note_name(0), "C-2");
+        assert_eq!(note_name(127), "G8");
+        for number in 0..=127 {
+            assert_eq!(parse_note(&note_name(number)).unwrap(), number);
+        }
+    }
+
+    #[test]
+    fn sets_note_limits() {
+        let mut performance = Performance::new();
+        set_note_limits(&mut performance, 2, 36, 72).unwrap();
+        let tg = &performance.tone_generators[1];
+        assert_eq!((tg.note_limit_low, tg.note_limit_high), (36, 72));
+
+        set_note_limits(&mut performance, 2, 60, 60).unwrap();
+        let tg = &performance.tone_generators[1];
+        assert_eq!((tg.note_limit_low, tg.note_limit_high), (60, 60));
+    }
+
+    #[test]
+    fn rejects_reversed_note_limits() {
+        let mut performance = Performance::new();
+        let before = assignments(&performance);
+        assert!(matches!(set_note_limits(&mut performance, 1, 72, 36), Err(Error::Range(_))));
+        assert!(matches!(set_note_limits(&mut performance, 9, 36, 72), Err(Error::Range(_))));
+        assert_eq!(assignments(&performance), before);
+    }
+
+    #[test]
+    fn makes_split() {
+        let mut performance = Performance::new();
+        make_split(&mut performance, 60, 10, 20).unwrap();
+        let lower = (10, 0, 59, 0);
+        let upper = (20, 60, 127, 1);
+        assert_eq!(assignments(&performance), [[lower; 4], [upper; 4]].concat());
+    }
+
+    #[test]
+    fn makes_split_at_keyboard_edges() {
+        let mut performance = Performance::new();
+        make_split(&mut performance, 1, 10, 20).unwrap();
+        assert_eq!(assignments(&performance), [[(10, 0, 0, 0); 4], [(20, 1, 127, 1); 4]].concat());
+
+        make_split(&mut performance, 127, 10, 20).unwrap();
+        assert_eq!(assignments(&performance), [[(10, 0, 126, 0); 4], [(20, 127, 127, 1); 4]].concat());
+
+        assert!(matches!(make_split(&mut performance, 0, 10, 20), Err(Error::Range(_))));
+        assert!(matches!(make_split(&mut performance, 128, 10, 20), Err(Error::Range(_))));
+    }
+
+    #[test]
+    fn makes_layer() {
+        let mut performance = Performance::new();
+        make_layer(&mut performance, &[1, 2, 3]).unwrap();
+        let voices: Vec<(u8, u8)> = performance.tone_generators.iter()
+            .map(|tg| (tg.voice_number, tg.voice_channel_offset))
+            .collect();
+        assert_eq!(voices, vec![(1, 0), (1, 0), (1, 0), (2, 1), (2, 1), (2, 1), (3, 2), (3, 2)]);
+        assert!(performance.tone_generators.iter().all(|tg| (tg.note_limit_low, tg.note_limit_high) == (0, 127)));
+
+        assert!(matches!(make_layer(&mut performance, &[]), Err(Error::Range(_))));
+        assert!(matches!(make_layer(&mut performance, &[0; 9]), Err(Error::Range(_))));
+    }
+
+    #[test]
+    fn groups_share_receive_channel_of_first_tone_generator() {
+        let mut performance = Performance::new();
+        set_parameter(&mut performance, 1, "receiveChannel", 5).unwrap();
+        make_groups(&mut performance, &[(1, 0, 127); 8]).unwrap();
+        assert!(performance.tone_generators.iter().all(|tg| tg.receive_channel == 5));
+        let offsets: Vec<u8> = performance.tone_generators.iter().map(|tg| tg.voice_channel_offset).collect();
+        assert_eq!(offsets, (0..8).collect::<Vec<u8>>());
+    }
+}
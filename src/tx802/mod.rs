@@ -1,17 +1,482 @@
-pub struct Performance {
+//! Yamaha TX802 performances, and the performance memory bulk dump
+//! (PMEM) with all the 64 performances of the TX802.
+//!
+//! The performance memory is a universal bulk dump (see `crate::universal`)
+//! with the classification name "LM  8952PM".
+//!
+//! Each performance has the parameters of the eight tone generators,
+//! one row of eight bytes for each parameter in the order of `PARAMETERS`,
+//! followed by the name in 20 ASCII characters, 116 bytes in all.
+//! The dump has the 64 performances back to back, unpacked.
+//!
+//! The parameters, their order and their ranges are the tone generator
+//! parameters of a performance in the TX802 owner's manual. The byte layout
+//! of the dump has not been checked against a dump from a TX802. The packed
+//! layout, where the TX802 packs the performances like the AMEM supplements
+//! (see `crate::dx7::supplement`), is not implemented: its byte count does
+//! not match, and `parse_pmem` rejects the dump instead of misreading it.
+//! `make_pmem_message` always makes the unpacked layout.
+
+pub mod edit;
+
+use std::fmt;
+
+use xml_builder::XMLElement;
+
+use sevenate::dx7::sysex::MIDIChannel;
+
+use crate::error::Error;
+use crate::universal::{CLASSIFICATION_LENGTH, classification, universal_data, make_universal_message};
+use crate::tx802::edit::{note_name, voice_number_name};
+use crate::xml::{Element, XmlError, XmlErrorKind};
+
+/// Number of tone generators in the TX802.
+pub const TONE_GENERATOR_COUNT: usize = 8;
+
+/// Number of performances in the performance memory.
+pub const PERFORMANCE_COUNT: usize = 64;
+
+/// Length of a performance name.
+pub const NAME_LENGTH: usize = 20;
+
+/// Size of the data of one performance.
+pub const PERFORMANCE_SIZE: usize = PARAMETERS.len() * TONE_GENERATOR_COUNT + NAME_LENGTH;
+
+/// Size of the data of the performance memory.
+pub const PERFORMANCE_MEMORY_SIZE: usize = PERFORMANCE_COUNT * PERFORMANCE_SIZE;
+
+/// Name of an initialized performance.
+pub const INIT_NAME: &str = "INIT PERFORMANCE";
+
+/// Classification name of a performance memory bulk dump.
+pub const PMEM_CLASSIFICATION: &[u8; CLASSIFICATION_LENGTH] = b"LM  8952PM";
+
+/// A tone generator parameter: its name, the name of its XML attribute,
+/// and its maximum value. All the parameters start from zero.
+#[derive(Debug)]
+pub struct Parameter {
+    pub name: &'static str,
+    pub attribute: &'static str,
+    pub maximum: u8,
+}
+
+const fn parameter(name: &'static str, attribute: &'static str, maximum: u8) -> Parameter {
+    Parameter { name, attribute, maximum }
+}
+
+/// The parameters of a tone generator, in the order they are in the data.
+pub const PARAMETERS: [Parameter; 12] = [
+    parameter("voice channel offset", "voiceChannelOffset", 7),
+    parameter("receive channel", "receiveChannel", 16),  // 16 = omni
+    parameter("voice number", "voiceNumber", 127),  // 0...63 internal, 64...127 cartridge
+    parameter("detune", "detune", 14),  // 7 = no detune
+    parameter("output volume", "outputVolume", 99),
+    parameter("output assign", "outputAssign", 3),  // off, I, II, I+II
+    parameter("note limit low", "noteLimitLow", 127),
+    parameter("note limit high", "noteLimitHigh", 127),
+    parameter("note shift", "noteShift", 48),  // 24 = no shift
+    parameter("EG forced damp", "egForcedDamp", 1),
+    parameter("key assign group", "keyAssignGroup", 1),
+    parameter("micro tuning table number", "microTuningTableNumber", 76),
+];
+
+/// The settings of one tone generator in a performance.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ToneGenerator {
     pub voice_channel_offset: u8,
     pub receive_channel: u8,
     pub voice_number: u8,
-    pub micro_tuning_table_number: u8,
+    pub detune: u8,
     pub output_volume: u8,
     pub output_assign: u8,
     pub note_limit_low: u8,
     pub note_limit_high: u8,
     pub note_shift: u8,
     pub eg_forced_damp: u8,
+    pub key_assign_group: u8,
+    pub micro_tuning_table_number: u8,
+}
+
+impl ToneGenerator {
+    /// Makes a tone generator with the initial settings, playing
+    /// the internal voice with the same number.
+    pub fn new(number: usize) -> Self {
+        ToneGenerator {
+            voice_channel_offset: 0,
+            receive_channel: 0,
+            voice_number: number as u8,
+            detune: 7,
+            output_volume: 90,
+            output_assign: 3,
+            note_limit_low: 0,
+            note_limit_high: 127,
+            note_shift: 24,
+            eg_forced_damp: 0,
+            key_assign_group: 0,
+            micro_tuning_table_number: 0,
+        }
+    }
+
+    /// Gets the parameter values, in the order of `PARAMETERS`.
+    pub fn values(&self) -> [u8; 12] {
+        [
+            self.voice_channel_offset,
+            self.receive_channel,
+            self.voice_number,
+            self.detune,
+            self.output_volume,
+            self.output_assign,
+            self.note_limit_low,
+            self.note_limit_high,
+            self.note_shift,
+            self.eg_forced_damp,
+            self.key_assign_group,
+            self.micro_tuning_table_number,
+        ]
+    }
+
+    /// Makes a tone generator from parameter values in the order of `PARAMETERS`.
+    /// The values must be in range.
+    pub fn from_values(values: [u8; 12]) -> Self {
+        let [voice_channel_offset, receive_channel, voice_number, detune, output_volume,
+            output_assign, note_limit_low, note_limit_high, note_shift, eg_forced_damp,
+            key_assign_group, micro_tuning_table_number] = values;
+        ToneGenerator {
+            voice_channel_offset,
+            receive_channel,
+            voice_number,
+            detune,
+            output_volume,
+            output_assign,
+            note_limit_low,
+            note_limit_high,
+            note_shift,
+            eg_forced_damp,
+            key_assign_group,
+            micro_tuning_table_number,
+        }
+    }
+}
+
+/// A TX802 performance: the settings of the eight tone generators, and a name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Performance {
+    pub tone_generators: Vec<ToneGenerator>,
     pub name: String,
 }
 
+impl Performance {
+    /// Makes an initialized performance, with the tone generators
+    /// playing the first eight internal voices.
+    pub fn new() -> Self {
+        Performance {
+            tone_generators: (0..TONE_GENERATOR_COUNT).map(ToneGenerator::new).collect(),
+            name: String::from(INIT_NAME),
+        }
+    }
+
+    /// Parses the data of a performance. The performance number is
+    /// for the error messages. Values out of range and name characters
+    /// that are not printable ASCII are errors.
+    pub fn parse(data: &[u8], number: usize) -> Result<Self, Error> {
+        if data.len() != PERFORMANCE_SIZE {
+            return Err(Error::Header(format!("Got {} bytes of performance data, expected {}",
+                data.len(), PERFORMANCE_SIZE)));
+        }
+
+        let mut tone_generators = Vec::<ToneGenerator>::new();
+        for tg in 0..TONE_GENERATOR_COUNT {
+            let mut values = [0u8; 12];
+            for (row, parameter) in PARAMETERS.iter().enumerate() {
+                let value = data[row * TONE_GENERATOR_COUNT + tg];
+                if value > parameter.maximum {
+                    return Err(Error::Range(format!("performance {}: TG{} {} is {}, expected 0...{}",
+                        number, tg + 1, parameter.name, value, parameter.maximum)));
+                }
+                values[row] = value;
+            }
+            tone_generators.push(ToneGenerator::from_values(values));
+        }
+
+        let name_data = &data[PERFORMANCE_SIZE - NAME_LENGTH..];
+        if let Some(position) = name_data.iter().position(|c| !(0x20..=0x7e).contains(c)) {
+            return Err(Error::Range(format!("performance {}: name character {} is {:02X}H, not printable ASCII",
+                number, position + 1, name_data[position])));
+        }
+        let name = String::from_utf8_lossy(name_data).trim_end().to_string();
+
+        Ok(Performance { tone_generators, name })
+    }
+
+    /// Makes the data of the performance. The name is padded
+    /// with spaces, and characters that are not printable ASCII
+    /// are replaced with spaces.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = vec![0u8; PERFORMANCE_SIZE];
+        for (tg, tone_generator) in self.tone_generators.iter().take(TONE_GENERATOR_COUNT).enumerate() {
+            for (row, value) in tone_generator.values().iter().enumerate() {
+                data[row * TONE_GENERATOR_COUNT + tg] = *value;
+            }
+        }
+
+        let name = &mut data[PERFORMANCE_SIZE - NAME_LENGTH..];
+        name.fill(b' ');
+        for (c, b) in self.name.bytes().take(NAME_LENGTH).zip(name.iter_mut()) {
+            *b = if (0x20..=0x7e).contains(&c) { c } else { b' ' };
+        }
+        data
+    }
+}
+
+impl Default for Performance {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Display for Performance {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}", self.name)?;
+        writeln!(f, "TG  OFS  RCH VNUM DET VOL  OUT  NLL  NLH SFT DMP KAG MTT")?;
+        for (tg, t) in self.tone_generators.iter().enumerate() {
+            writeln!(f, "{:2}  {:3} {:>4} {:>4} {:+3} {:3} {:>4} {:>4} {:>4} {:+3} {:3} {:3} {:3}",
+                tg + 1,
+                t.voice_channel_offset,
+                if t.receive_channel == 16 { String::from("omni") } else { (t.receive_channel + 1).to_string() },
+                voice_number_name(t.voice_number),
+                t.detune as i32 - 7,
+                t.output_volume,
+                ["off", "I", "II", "I+II"][t.output_assign as usize & 3],
+                note_name(t.note_limit_low),
+                note_name(t.note_limit_high),
+                t.note_shift as i32 - 24,
+                t.eg_forced_damp,
+                t.key_assign_group,
+                t.micro_tuning_table_number)?;
+        }
+        Ok(())
+    }
+}
+
+/// All the 64 performances of the TX802.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct PerformanceMemory {
-    pub performances: [Performance; 64],
+    pub performances: Vec<Performance>,
+}
+
+impl PerformanceMemory {
+    /// Makes a performance memory with initialized performances.
+    pub fn new() -> Self {
+        PerformanceMemory {
+            performances: (0..PERFORMANCE_COUNT).map(|_| Performance::new()).collect(),
+        }
+    }
+}
+
+impl Default for PerformanceMemory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Checks if a message is a TX802 performance memory bulk dump,
+/// by its format and classification name. Does not check the rest.
+pub fn is_pmem(message: &[u8]) -> bool {
+    classification(message) == Some(PMEM_CLASSIFICATION.as_slice())
+}
+
+/// Parses a TX802 performance memory bulk dump, after checking
+/// its header, byte count and checksum.
+pub fn parse_pmem(message: &[u8]) -> Result<PerformanceMemory, Error> {
+    let data = universal_data(message, PMEM_CLASSIFICATION, PERFORMANCE_MEMORY_SIZE, "TX802 performance memory")
+        .map_err(|e| match e {
+            // The classification is right, so this is the size.
+            Error::Header(text) if is_pmem(message) => Error::Header(format!("{}. Only the unpacked layout \
+                is supported, a packed TX802 performance memory cannot be read yet", text)),
+            e => e,
+        })?;
+    let performances = data.chunks_exact(PERFORMANCE_SIZE)
+        .enumerate()
+        .map(|(index, chunk)| Performance::parse(chunk, index + 1))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(PerformanceMemory { performances })
+}
+
+/// Makes a TX802 performance memory bulk dump with the header and checksum.
+/// There must be 64 performances.
+pub fn make_pmem_message(memory: &PerformanceMemory, channel: MIDIChannel) -> Vec<u8> {
+    let data: Vec<u8> = memory.performances.iter().flat_map(Performance::to_bytes).collect();
+    make_universal_message(PMEM_CLASSIFICATION, &data, channel)
+}
+
+//
+// XML
+//
+
+/// Converts the performance memory into a `performances` element.
+pub fn performances_to_xml(memory: &PerformanceMemory) -> XMLElement {
+    let mut root = XMLElement::new("performances");
+    for performance in &memory.performances {
+        let mut e = XMLElement::new("performance");
+        e.add_attribute("name", &performance.name);
+        for tone_generator in &performance.tone_generators {
+            let mut tg = XMLElement::new("toneGenerator");
+            for (parameter, value) in PARAMETERS.iter().zip(tone_generator.values()) {
+                tg.add_attribute(parameter.attribute, &value.to_string());
+            }
+            let _ = e.add_child(tg);
+        }
+        let _ = root.add_child(e);
+    }
+    root
+}
+
+/// Converts a `performances` element into the performance memory.
+/// It must contain exactly 64 performances.
+pub fn parse_performances(root: &Element) -> Result<PerformanceMemory, XmlError> {
+    if root.name != "performances" {
+        return Err(root.error(XmlErrorKind::UnexpectedElement(root.name.clone())));
+    }
+    root.check_attributes(&[])?;
+    root.check_children(&["performance"], false)?;
+
+    let count = root.children.len();
+    if count != PERFORMANCE_COUNT {
+        return Err(root.error(XmlErrorKind::WrongCount {
+            name: String::from("performance"),
+            count,
+            min: PERFORMANCE_COUNT,
+            max: PERFORMANCE_COUNT,
+        }));
+    }
+
+    let performances = root.children.iter()
+        .map(parse_performance)
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(PerformanceMemory { performances })
+}
+
+/// Converts a `performance` element into a performance.
+/// It must contain exactly eight tone generators.
+pub fn parse_performance(element: &Element) -> Result<Performance, XmlError> {
+    element.check_attributes(&["name"])?;
+    element.check_children(&["toneGenerator"], false)?;
+
+    let name = element.required_attribute("name")?;
+    if name.chars().count() > NAME_LENGTH || !name.chars().all(|c| (' '..='~').contains(&c)) {
+        return Err(element.error(XmlErrorKind::InvalidValue {
+            name: String::from("name"),
+            value: name.to_string(),
+            expected: format!("at most {} ASCII characters", NAME_LENGTH),
+        }));
+    }
+
+    let count = element.children.len();
+    if count != TONE_GENERATOR_COUNT {
+        return Err(element.error(XmlErrorKind::WrongCount {
+            name: String::from("toneGenerator"),
+            count,
+            min: TONE_GENERATOR_COUNT,
+            max: TONE_GENERATOR_COUNT,
+        }));
+    }
+
+    let mut tone_generators = Vec::<ToneGenerator>::new();
+    for tg in &element.children {
+        let attributes: Vec<&str> = PARAMETERS.iter().map(|p| p.attribute).collect();
+        tg.check_attributes(&attributes)?;
+        tg.check_children(&[], false)?;
+
+        let mut values = [0u8; 12];
+        for (value, parameter) in values.iter_mut().zip(&PARAMETERS) {
+            *value = tg.integer_attribute(parameter.attribute, 0, parameter.maximum as i32)? as u8;
+        }
+        tone_generators.push(ToneGenerator::from_values(values));
+    }
+
+    Ok(Performance { tone_generators, name: name.to_string() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sevenate::Ranged;
+
+    // Makes a performance memory bulk dump by hand from the data,
+    // without the functions under test.
+    fn make_message(data: &[u8]) -> Vec<u8> {
+        let mut body = PMEM_CLASSIFICATION.to_vec();
+        body.extend(data);
+        let sum = body.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+        let mut message = vec![0xf0, 0x43, 0x00, 0x7e, (body.len() >> 7) as u8, (body.len() & 0x7f) as u8];
+        message.extend(&body);
+        message.push(sum.wrapping_neg() & 0x7f);
+        message.push(0xf7);
+        message
+    }
+
+    // Makes the data of a performance memory where every performance
+    // has valid values and a blank name.
+    fn blank_data() -> Vec<u8> {
+        let mut performance = [0u8; PERFORMANCE_SIZE];
+        performance[PERFORMANCE_SIZE - NAME_LENGTH..].fill(b' ');
+        performance.repeat(PERFORMANCE_COUNT)
+    }
+
+    #[test]
+    fn parses_rows_of_parameters() {
+        let mut data = blank_data();
+        let second = PERFORMANCE_SIZE;
+        data[second + 4 * TONE_GENERATOR_COUNT + 2] = 55;  // TG3 output volume
+        data[second + 2 * TONE_GENERATOR_COUNT + 7] = 70;  // TG8 voice number
+        data[second + 11 * TONE_GENERATOR_COUNT] = 76;  // TG1 micro tuning table number
+        data[second + 96 .. second + 101].copy_from_slice(b"BRASS");
+
+        let memory = parse_pmem(&make_message(&data)).unwrap();
+        assert_eq!(memory.performances.len(), PERFORMANCE_COUNT);
+        let performance = &memory.performances[1];
+        assert_eq!(performance.name, "BRASS");
+        assert_eq!(performance.tone_generators[2].output_volume, 55);
+        assert_eq!(performance.tone_generators[7].voice_number, 70);
+        assert_eq!(performance.tone_generators[0].micro_tuning_table_number, 76);
+        assert_eq!(memory.performances[0].tone_generators[2].output_volume, 0);
+    }
+
+    #[test]
+    fn round_trip_is_lossless() {
+        let mut memory = PerformanceMemory::new();
+        memory.performances[63].name = String::from("LAST ONE");
+        memory.performances[63].tone_generators[5].note_shift = 36;
+        let message = make_pmem_message(&memory, MIDIChannel::new(1));
+        assert_eq!(message, make_message(&memory.performances.iter().flat_map(Performance::to_bytes).collect::<Vec<_>>()));
+        assert_eq!(parse_pmem(&message).unwrap(), memory);
+    }
+
+    #[test]
+    fn rejects_values_out_of_range() {
+        let mut data = blank_data();
+        data[PERFORMANCE_SIZE * 2 + TONE_GENERATOR_COUNT + 3] = 17;  // performance 3, TG4 receive channel
+        match parse_pmem(&make_message(&data)) {
+            Err(Error::Range(message)) => assert!(message.contains("performance 3: TG4 receive channel"), "{}", message),
+            other => panic!("expected a range error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rejects_other_sizes() {
+        let data = vec![0u8; PERFORMANCE_MEMORY_SIZE - PERFORMANCE_COUNT];
+        match parse_pmem(&make_message(&data)) {
+            Err(Error::Header(message)) => assert!(message.contains("packed"), "{}", message),
+            other => panic!("expected a header error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rejects_wrong_checksum() {
+        let mut message = make_message(&blank_data());
+        let last = message.len() - 2;
+        message[last] ^= 1;
+        assert!(matches!(parse_pmem(&message), Err(Error::Checksum { .. })));
+    }
 }
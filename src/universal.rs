@@ -0,0 +1,84 @@
+//! The universal bulk dump format of the DX7II series and the TX802:
+//! `F0 43 0n 7E (count MSB) (count LSB) (classification) (data) (checksum) F7`.
+//! The classification is a ten-character name like "LM  8952PM".
+//! The byte count is in two 7-bit bytes, and both the byte count and
+//! the checksum cover the classification name and the data.
+
+use syxpack::{INITIATOR, TERMINATOR};
+
+use sevenate::dx7::sysex::{MIDIChannel, checksum};
+
+use crate::error::Error;
+
+/// Length of a classification name.
+pub const CLASSIFICATION_LENGTH: usize = 10;
+
+/// The Yamaha manufacturer ID.
+const YAMAHA: u8 = 0x43;
+
+/// Format byte of universal bulk dumps.
+const UNIVERSAL_FORMAT: u8 = 0x7e;
+
+// Offset of the classification name in the message.
+const CLASSIFICATION_OFFSET: usize = 6;
+
+/// Gets the classification name of a universal bulk dump message.
+/// Does not check the rest.
+pub fn classification(message: &[u8]) -> Option<&[u8]> {
+    if message.len() > CLASSIFICATION_OFFSET + CLASSIFICATION_LENGTH
+        && message[1] == YAMAHA
+        && message[3] == UNIVERSAL_FORMAT {
+        Some(&message[CLASSIFICATION_OFFSET .. CLASSIFICATION_OFFSET + CLASSIFICATION_LENGTH])
+    } else {
+        None
+    }
+}
+
+/// Checks the header, byte count and checksum of a universal bulk dump
+/// with the given classification name and size of data, and returns the data.
+/// The description is for the error messages, like "TX802 performance memory".
+pub fn universal_data<'a>(message: &'a [u8], name: &[u8; CLASSIFICATION_LENGTH], size: usize,
+        description: &str) -> Result<&'a [u8], Error> {
+    if message.len() < CLASSIFICATION_OFFSET + CLASSIFICATION_LENGTH + 2
+        || message[0] != INITIATOR || message[message.len() - 1] != TERMINATOR {
+        return Err(Error::SysEx(format!("Not a complete System Exclusive message ({} bytes)", message.len())));
+    }
+
+    if classification(message) != Some(name.as_slice()) {
+        return Err(Error::Header(format!("Not a {} bulk dump", description)));
+    }
+
+    let byte_count = ((message[4] as usize) << 7) | message[5] as usize;
+    let body = &message[CLASSIFICATION_OFFSET .. message.len() - 2];  // classification name and data
+    if byte_count != body.len() || body.len() != CLASSIFICATION_LENGTH + size {
+        return Err(Error::Header(format!("Byte count is {}, got {} bytes, expected {} bytes",
+            byte_count, body.len(), CLASSIFICATION_LENGTH + size)));
+    }
+
+    let expected = checksum(body);
+    let found = message[message.len() - 2];
+    if found != expected {
+        return Err(Error::Checksum { found, expected });
+    }
+
+    Ok(&body[CLASSIFICATION_LENGTH..])
+}
+
+/// Makes a universal bulk dump message with the header and checksum.
+pub fn make_universal_message(name: &[u8; CLASSIFICATION_LENGTH], data: &[u8], channel: MIDIChannel) -> Vec<u8> {
+    let mut body = name.to_vec();
+    body.extend(data);
+
+    let mut message = vec![
+        INITIATOR,
+        YAMAHA,
+        channel.as_byte(),
+        UNIVERSAL_FORMAT,
+        ((body.len() >> 7) & 0x7f) as u8,
+        (body.len() & 0x7f) as u8,
+    ];
+    message.extend(&body);
+    message.push(checksum(&body));
+    message.push(TERMINATOR);
+    message
+}
@@ -0,0 +1,867 @@
+//! Converting voices and cartridges to and from XML documents
+//! in the format described in https://github.com/coniferprod/dx7-xml.
+
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use xml_builder::XMLElement;
+use xml::common::Position;
+use xml::reader::{ParserConfig, XmlEvent};
+
+use sevenate::Ranged;
+use sevenate::dx7::{Algorithm, Transpose, Depth, Level, Coarse, Detune, Sensitivity};
+use sevenate::dx7::voice::{Voice, VoiceName, OPERATOR_COUNT};
+use sevenate::dx7::cartridge::{Cartridge, VOICE_COUNT};
+use sevenate::dx7::operator::{
+    Key,
+    KeyboardLevelScaling,
+    Operator,
+    OperatorMode,
+    Scaling,
+    ScalingCurve
+};
+use sevenate::dx7::envelope::{Envelope, Rate, Rates, Levels};
+use sevenate::dx7::lfo::{Lfo, LfoWaveform};
+
+pub use crate::dx7::Document;
+use crate::dx7::is_valid_voice_name;
+
+pub mod schema;
+
+//
+// Writing voices and cartridges as XML
+//
+
+/// Converts a value into an XML element.
+pub trait ToXml {
+    fn to_xml(&self) -> XMLElement;
+    fn to_xml_named(&self, name: &str) -> XMLElement;
+}
+
+impl ToXml for Cartridge {
+    fn to_xml(&self) -> XMLElement {
+        self.to_xml_named("cartridge")
+    }
+
+    fn to_xml_named(&self, name: &str) -> XMLElement {
+        let mut e = XMLElement::new(name);
+
+        let mut voices_element = XMLElement::new("voices");
+
+        for voice in &self.voices {
+            voices_element.add_child(voice.to_xml()).unwrap();
+        }
+
+        let _ = e.add_child(voices_element);
+        e
+    }
+}
+
+impl ToXml for Voice {
+    fn to_xml(&self) -> XMLElement {
+        self.to_xml_named("voice")
+    }
+
+    fn to_xml_named(&self, name: &str) -> XMLElement {
+        let mut e = XMLElement::new(name);
+
+        e.add_attribute("name", &self.name.value());
+        e.add_attribute("algorithm", &self.alg.value().to_string());
+        e.add_attribute("transpose", &self.transpose.value().to_string());
+        e.add_attribute("feedback", &self.feedback.value().to_string());
+        e.add_attribute("oscillatorSync", &self.osc_sync.to_string());
+        e.add_attribute("pitchModulationSensitivity", &self.pitch_mod_sens.value().to_string());
+
+        e.add_child(self.peg.to_xml_named("peg")).unwrap();
+        e.add_child(self.lfo.to_xml()).unwrap();
+
+        let mut op_e = XMLElement::new("operators");
+        for op in self.operators {
+            op_e.add_child(op.to_xml()).unwrap();
+        }
+        let _ = e.add_child(op_e);
+
+        e
+    }
+}
+
+impl ToXml for Lfo {
+    fn to_xml(&self) -> XMLElement {
+        self.to_xml_named("lfo")
+    }
+
+    fn to_xml_named(&self, name: &str) -> XMLElement {
+        let mut e = XMLElement::new(name);
+    
+        e.add_attribute("speed", &self.speed.value().to_string());
+        e.add_attribute("delay", &self.delay.value().to_string());
+        e.add_attribute("pmd", &self.pmd.value().to_string());
+        e.add_attribute("amd", &self.amd.value().to_string());
+        e.add_attribute("sync", &self.sync.to_string());
+        e.add_attribute("wave", &self.waveform.to_string());
+        //e.add_attribute("pms", &lfo.pms.value().to_string());
+    
+        e
+    }    
+}
+
+impl ToXml for Envelope {
+    fn to_xml(&self) -> XMLElement {
+        self.to_xml_named("envelope")
+    }
+
+    fn to_xml_named(&self, name: &str) -> XMLElement {
+        let mut e = XMLElement::new(name);
+
+        let mut rates_element = XMLElement::new("rates");
+        let mut rates_string = String::new();
+        let mut count = 0;
+        for r in self.rates.iter() {
+            rates_string.push_str(&r.to_string());
+            count += 1;
+            if count < 4 {
+                rates_string.push(' ');
+            }
+        }
+
+        rates_element.add_text(rates_string).unwrap();
+        let _ = e.add_child(rates_element);
+
+        let mut levels_element = XMLElement::new("levels");
+        let mut levels_string = String::new();
+        let mut count = 0;
+        for level in self.levels.iter() {
+            levels_string.push_str(&level.to_string());
+            count += 1;
+            if count < 4 {
+                levels_string.push(' ');
+            }
+        }
+        levels_element.add_text(levels_string).unwrap();
+        let _ = e.add_child(levels_element);
+
+        e
+    }
+}
+
+impl ToXml for Operator {
+    fn to_xml(&self) -> XMLElement {
+        self.to_xml_named("operator")
+    }
+
+    fn to_xml_named(&self, name: &str) -> XMLElement {
+        let mut e = XMLElement::new(name);
+
+        e.add_attribute("level", &self.output_level.value().to_string());
+        e.add_attribute("mode", &self.mode.to_string());
+        e.add_attribute("coarse", &self.coarse.value().to_string());
+        e.add_attribute("fine", &self.fine.value().to_string());
+        e.add_attribute("detune", &self.detune.value().to_string());
+        e.add_attribute("amplitudeModulationSensitivity", &self.amp_mod_sens.value().to_string());
+        e.add_attribute("keyVelocitySensitivity", &self.key_vel_sens.value().to_string());
+        e.add_attribute("keyboardRateScaling", &self.kbd_rate_scaling.value().to_string());
+
+        e.add_child(self.eg.to_xml_named("eg")).unwrap();
+        e.add_child(self.kbd_level_scaling.to_xml()).unwrap();
+
+        e
+    }
+}
+
+impl ToXml for KeyboardLevelScaling {
+    fn to_xml(&self) -> XMLElement {
+        self.to_xml_named("keyboardLevelScaling")
+    }
+
+    fn to_xml_named(&self, name: &str) -> XMLElement {
+        let mut elem = XMLElement::new(name);
+
+        elem.add_attribute("breakpoint", &self.breakpoint.value().to_string());
+
+        let mut depth_element = XMLElement::new("depth");
+        depth_element.add_attribute("left", &self.left.depth.value().to_string());
+        depth_element.add_attribute("right", &self.right.depth.value().to_string());
+        let _ = elem.add_child(depth_element);
+
+        let mut curve_element = XMLElement::new("curve");
+        curve_element.add_attribute("left", &self.left.curve.to_string());
+        curve_element.add_attribute("right", &self.right.curve.to_string());
+        let _ = elem.add_child(curve_element);
+
+        elem
+    }
+}
+
+
+//
+// Reading voices and cartridges from XML
+//
+
+/// Location of an element in an XML document.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Location {
+    pub file: Option<Arc<PathBuf>>,  // set for elements from included documents
+    pub line: u64,  // 1-based
+    pub column: u64,  // 1-based
+    pub path: String,  // like "/cartridge/voices/voice[3]/operators/operator[2]"
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{}, ", file.display())?;
+        }
+        write!(f, "line {}, column {}, {}", self.line, self.column, self.path)
+    }
+}
+
+/// The kinds of errors in XML documents.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum XmlErrorKind {
+    Syntax(String),  // the document is not well-formed
+    UnexpectedElement(String),
+    MissingElement(String),
+    DuplicateElement(String),
+    MissingAttribute(String),
+    UnknownAttribute(String),
+    InvalidValue { name: String, value: String, expected: String },
+    OutOfRange { name: String, value: i32, first: i32, last: i32 },
+    WrongCount { name: String, count: usize, min: usize, max: usize },
+    UnexpectedText(String),
+    Include { href: String, reason: String },
+}
+
+impl fmt::Display for XmlErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            XmlErrorKind::Syntax(message) =>
+                write!(f, "{}", message),
+            XmlErrorKind::UnexpectedElement(name) =>
+                write!(f, "unexpected element <{}>", name),
+            XmlErrorKind::MissingElement(name) =>
+                write!(f, "missing element <{}>", name),
+            XmlErrorKind::DuplicateElement(name) =>
+                write!(f, "element <{}> appears more than once", name),
+            XmlErrorKind::MissingAttribute(name) =>
+                write!(f, "missing attribute '{}'", name),
+            XmlErrorKind::UnknownAttribute(name) =>
+                write!(f, "unknown attribute '{}'", name),
+            XmlErrorKind::InvalidValue { name, value, expected } =>
+                write!(f, "invalid value '{}' for '{}', expected {}", value, name, expected),
+            XmlErrorKind::OutOfRange { name, value, first, last } =>
+                write!(f, "value {} for '{}' is out of range {}...{}", value, name, first, last),
+            XmlErrorKind::WrongCount { name, count, min, max } if min == max =>
+                write!(f, "found {} <{}> elements, expected {}", count, name, min),
+            XmlErrorKind::WrongCount { name, count, min, max } =>
+                write!(f, "found {} <{}> elements, expected {}...{}", count, name, min, max),
+            XmlErrorKind::UnexpectedText(text) =>
+                write!(f, "unexpected text '{}'", text),
+            XmlErrorKind::Include { href, reason } =>
+                write!(f, "cannot include '{}': {}", href, reason),
+        }
+    }
+}
+
+/// Error in an XML document, with its location.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct XmlError {
+    pub kind: XmlErrorKind,
+    pub location: Location,
+}
+
+impl fmt::Display for XmlError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.location, self.kind)
+    }
+}
+
+impl std::error::Error for XmlError {}
+
+/// An element in an XML document, with its attributes, child elements
+/// and text content. Attributes with a namespace prefix are stored
+/// with the prefix, like "xml:base".
+#[derive(Debug, Clone)]
+pub struct Element {
+    pub name: String,
+    pub namespace: Option<String>,
+    pub attributes: Vec<(String, String)>,
+    pub children: Vec<Element>,
+    pub text: String,
+    pub location: Location,
+}
+
+impl Element {
+    /// Makes an error located at this element.
+    pub fn error(&self, kind: XmlErrorKind) -> XmlError {
+        XmlError { kind, location: self.location.clone() }
+    }
+
+    /// Gets the value of an attribute, if it exists.
+    pub fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes.iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Gets the value of an attribute that must exist.
+    pub fn required_attribute(&self, name: &str) -> Result<&str, XmlError> {
+        self.attribute(name)
+            .ok_or_else(|| self.error(XmlErrorKind::MissingAttribute(name.to_string())))
+    }
+
+    /// Gets an attribute value as a ranged value, rejecting values
+    /// that are not integers or are out of range.
+    pub fn ranged_attribute<T: Ranged>(&self, name: &str) -> Result<T, XmlError> {
+        let value = self.required_attribute(name)?;
+        parse_ranged(value, name).map_err(|kind| self.error(kind))
+    }
+
+    /// Gets an attribute value as an integer in the range first...last.
+    pub fn integer_attribute(&self, name: &str, first: i32, last: i32) -> Result<i32, XmlError> {
+        let value = self.required_attribute(name)?;
+        parse_integer(value, name, first, last).map_err(|kind| self.error(kind))
+    }
+
+    /// Gets an attribute value as a Boolean ("true" or "false").
+    pub fn bool_attribute(&self, name: &str) -> Result<bool, XmlError> {
+        let value = self.required_attribute(name)?;
+        match value {
+            "true" => Ok(true),
+            "false" => Ok(false),
+            _ => Err(self.error(XmlErrorKind::InvalidValue {
+                name: name.to_string(),
+                value: value.to_string(),
+                expected: String::from("true or false"),
+            }))
+        }
+    }
+
+    /// Checks that the element has no attributes other than the allowed ones.
+    /// Attributes with a namespace prefix are always allowed.
+    pub fn check_attributes(&self, allowed: &[&str]) -> Result<(), XmlError> {
+        for (name, _) in &self.attributes {
+            if !name.contains(':') && !allowed.contains(&name.as_str()) {
+                return Err(self.error(XmlErrorKind::UnknownAttribute(name.clone())));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that the element has no child elements other than the allowed ones,
+    /// and no text content unless `text_allowed` is true.
+    pub fn check_children(&self, allowed: &[&str], text_allowed: bool) -> Result<(), XmlError> {
+        for child in &self.children {
+            if !allowed.contains(&child.name.as_str()) {
+                return Err(child.error(XmlErrorKind::UnexpectedElement(child.name.clone())));
+            }
+        }
+        if !text_allowed && !self.text.is_empty() {
+            return Err(self.error(XmlErrorKind::UnexpectedText(self.text.clone())));
+        }
+        Ok(())
+    }
+
+    /// Gets all the child elements with the given name.
+    pub fn children_named(&self, name: &str) -> Vec<&Element> {
+        self.children.iter().filter(|c| c.name == name).collect()
+    }
+
+    /// Gets the only child element with the given name.
+    pub fn child(&self, name: &str) -> Result<&Element, XmlError> {
+        let children = self.children_named(name);
+        match children.as_slice() {
+            [] => Err(self.error(XmlErrorKind::MissingElement(name.to_string()))),
+            [child] => Ok(child),
+            [_, duplicate, ..] => Err(duplicate.error(XmlErrorKind::DuplicateElement(name.to_string()))),
+        }
+    }
+
+    // Sets the paths of this element and its children, given the path of the
+    // parent element. Elements that have siblings with the same name get
+    // an index, like in XPath.
+    fn assign_paths(&mut self, parent_path: &str, index: Option<usize>) {
+        self.location.path = match index {
+            Some(i) => format!("{}/{}[{}]", parent_path, self.name, i),
+            None => format!("{}/{}", parent_path, self.name),
+        };
+
+        let names: Vec<String> = self.children.iter().map(|c| c.name.clone()).collect();
+        let path = self.location.path.clone();
+        for (i, child) in self.children.iter_mut().enumerate() {
+            let count = names.iter().filter(|n| **n == child.name).count();
+            let index = if count > 1 {
+                Some(names[..=i].iter().filter(|n| **n == child.name).count())
+            } else {
+                None
+            };
+            child.assign_paths(&path, index);
+        }
+    }
+
+    // Sets the file of this element and its children.
+    fn assign_file(&mut self, file: &Arc<PathBuf>) {
+        self.location.file = Some(Arc::clone(file));
+        for child in self.children.iter_mut() {
+            child.assign_file(file);
+        }
+    }
+
+    /// Returns true if this is an XInclude `include` element.
+    pub fn is_include(&self) -> bool {
+        self.name == "include" && self.namespace.as_deref() == Some(XINCLUDE_NAMESPACE)
+    }
+}
+
+// Parses an integer value for a ranged type.
+fn parse_ranged<T: Ranged>(value: &str, name: &str) -> Result<T, XmlErrorKind> {
+    parse_integer(value, name, T::FIRST, T::LAST).map(T::new)
+}
+
+// Parses an integer value in the range first...last.
+fn parse_integer(value: &str, name: &str, first: i32, last: i32) -> Result<i32, XmlErrorKind> {
+    let Ok(number) = value.trim().parse::<i32>() else {
+        return Err(XmlErrorKind::InvalidValue {
+            name: name.to_string(),
+            value: value.to_string(),
+            expected: format!("an integer {}...{}", first, last),
+        });
+    };
+
+    if number < first || number > last {
+        return Err(XmlErrorKind::OutOfRange {
+            name: name.to_string(),
+            value: number,
+            first,
+            last,
+        });
+    }
+
+    Ok(number)
+}
+
+/// Reads an XML document into a tree of elements, and returns the root element.
+pub fn read_document<R: Read>(source: R) -> Result<Element, XmlError> {
+    let config = ParserConfig::new()
+        .trim_whitespace(true)
+        .cdata_to_characters(true)
+        .ignore_comments(true);
+    let mut reader = config.create_reader(source);
+
+    let mut stack: Vec<Element> = Vec::new();
+    let mut root: Option<Element> = None;
+
+    loop {
+        // After an event is read, the position is that of its start.
+        let event = reader.next();
+        let position = reader.position();
+        let location = Location {
+            file: None,
+            line: position.row + 1,
+            column: position.column + 1,
+            path: stack.last().map(|e| e.location.path.clone()).unwrap_or_default(),
+        };
+
+        match event {
+            Ok(XmlEvent::StartElement { name, attributes, .. }) => {
+                let attributes = attributes.into_iter()
+                    .map(|a| {
+                        let name = match a.name.prefix {
+                            Some(prefix) => format!("{}:{}", prefix, a.name.local_name),
+                            None => a.name.local_name,
+                        };
+                        (name, a.value)
+                    })
+                    .collect();
+
+                // The path is set properly after the whole document is read,
+                // in the meantime use a simple one for syntax errors.
+                let path = format!("{}/{}", location.path, name.local_name);
+                stack.push(Element {
+                    name: name.local_name,
+                    namespace: name.namespace,
+                    attributes,
+                    children: Vec::new(),
+                    text: String::new(),
+                    location: Location { path, ..location },
+                });
+            },
+            Ok(XmlEvent::EndElement { .. }) => {
+                let element = stack.pop().expect("end element should match a start element");
+                match stack.last_mut() {
+                    Some(parent) => parent.children.push(element),
+                    None => root = Some(element),
+                }
+            },
+            Ok(XmlEvent::Characters(text)) => {
+                if let Some(element) = stack.last_mut() {
+                    element.text.push_str(&text);
+                }
+            },
+            Ok(XmlEvent::EndDocument) => {
+                break;
+            },
+            Ok(_) => {},
+            Err(e) => {
+                // The message of the parser error starts with the position,
+                // which is already in the location.
+                let position = e.position();
+                let message = e.to_string();
+                let message = message
+                    .strip_prefix(&format!("{} ", position))
+                    .unwrap_or(&message);
+                return Err(XmlError {
+                    kind: XmlErrorKind::Syntax(message.to_string()),
+                    location: Location {
+                        file: None,
+                        line: position.row + 1,
+                        column: position.column + 1,
+                        path: location.path,
+                    },
+                });
+            }
+        }
+    }
+
+    let Some(mut root) = root else {
+        return Err(XmlError {
+            kind: XmlErrorKind::Syntax(String::from("no root element")),
+            location: Location { file: None, line: 1, column: 1, path: String::new() },
+        });
+    };
+    root.assign_paths("", None);
+    Ok(root)
+}
+
+/// The namespace of XInclude elements.
+pub const XINCLUDE_NAMESPACE: &str = "http://www.w3.org/2001/XInclude";
+
+/// Replaces the XInclude elements in a document with the root elements
+/// of the documents they refer to, and does the same for the included
+/// documents. Relative references are resolved against the directory
+/// of the including document, starting from `path`. Only whole XML
+/// documents can be included, so `xpointer` and `parse="text"` are not
+/// supported. A document that includes itself, directly or through
+/// other documents, is an error.
+pub fn resolve_includes(root: Element, path: &Path) -> Result<Element, XmlError> {
+    let mut including = vec![fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())];
+    let mut root = resolve_element(root, path, &mut including)?;
+    root.assign_paths("", None);
+    Ok(root)
+}
+
+// Resolves the XInclude elements in an element and its children.
+// The stack of documents being included is used to detect recursion.
+fn resolve_element(mut element: Element, path: &Path, including: &mut Vec<PathBuf>) -> Result<Element, XmlError> {
+    if element.is_include() {
+        return include_document(&element, path, including);
+    }
+
+    element.children = element.children.into_iter()
+        .map(|child| resolve_element(child, path, including))
+        .collect::<Result<_, _>>()?;
+    Ok(element)
+}
+
+// Reads the document referred to by an XInclude element,
+// and resolves the XInclude elements in it.
+fn include_document(element: &Element, path: &Path, including: &mut Vec<PathBuf>) -> Result<Element, XmlError> {
+    element.check_attributes(&["href", "parse"])?;
+    element.check_children(&[], false)?;
+
+    if let Some(parse) = element.attribute("parse") {
+        if parse != "xml" {
+            return Err(element.error(XmlErrorKind::InvalidValue {
+                name: String::from("parse"),
+                value: parse.to_string(),
+                expected: String::from("xml"),
+            }));
+        }
+    }
+
+    let href = element.required_attribute("href")?;
+    let include_error = |reason: String| element.error(XmlErrorKind::Include {
+        href: href.to_string(),
+        reason,
+    });
+
+    let target = path.parent().unwrap_or(Path::new("")).join(href);
+    let file = File::open(&target).map_err(|e| include_error(e.to_string()))?;
+
+    let canonical = fs::canonicalize(&target).map_err(|e| include_error(e.to_string()))?;
+    if including.contains(&canonical) {
+        return Err(include_error(String::from("the document includes itself")));
+    }
+
+    let file_path = Arc::new(target.clone());
+    let mut included = read_document(BufReader::new(file)).map_err(|mut e| {
+        e.location.file = Some(Arc::clone(&file_path));
+        e
+    })?;
+    included.assign_file(&file_path);
+
+    including.push(canonical);
+    let result = resolve_element(included, &target, including);
+    including.pop();
+    result
+}
+
+/// Converts the root element of a document into a voice or a cartridge.
+pub fn parse_document(root: &Element) -> Result<Document, XmlError> {
+    match root.name.as_str() {
+        "voice" => Ok(Document::Voice(Box::new(parse_voice(root)?))),
+        "cartridge" => Ok(Document::Cartridge(parse_cartridge(root)?)),
+        _ => Err(root.error(XmlErrorKind::UnexpectedElement(root.name.clone()))),
+    }
+}
+
+/// Converts a `cartridge` element into a cartridge.
+/// It must contain exactly 32 voices.
+pub fn parse_cartridge(element: &Element) -> Result<Cartridge, XmlError> {
+    element.check_attributes(&[])?;
+    element.check_children(&["voices"], false)?;
+
+    let voices_element = element.child("voices")?;
+    voices_element.check_attributes(&[])?;
+    voices_element.check_children(&["voice"], false)?;
+
+    let count = voices_element.children.len();
+    if count != VOICE_COUNT {
+        return Err(voices_element.error(XmlErrorKind::WrongCount {
+            name: String::from("voice"),
+            count,
+            min: VOICE_COUNT,
+            max: VOICE_COUNT,
+        }));
+    }
+
+    let mut voices = Vec::<Voice>::new();
+    for voice_element in voices_element.children_named("voice") {
+        voices.push(parse_voice(voice_element)?);
+    }
+
+    Ok(Cartridge { voices })
+}
+
+/// Converts a `voice` element into a voice.
+pub fn parse_voice(element: &Element) -> Result<Voice, XmlError> {
+    element.check_attributes(&[
+        "name", "algorithm", "transpose", "feedback",
+        "oscillatorSync", "pitchModulationSensitivity",
+    ])?;
+    element.check_children(&["peg", "lfo", "operators", "supplement"], false)?;
+
+    let name = element.required_attribute("name")?;
+    if !is_valid_voice_name(name) {
+        return Err(element.error(XmlErrorKind::InvalidValue {
+            name: String::from("name"),
+            value: name.to_string(),
+            expected: String::from("at most 10 printable ASCII characters"),
+        }));
+    }
+
+    let operators_element = element.child("operators")?;
+    operators_element.check_attributes(&[])?;
+    operators_element.check_children(&["operator"], false)?;
+
+    let count = operators_element.children.len();
+    if count != OPERATOR_COUNT {
+        return Err(operators_element.error(XmlErrorKind::WrongCount {
+            name: String::from("operator"),
+            count,
+            min: OPERATOR_COUNT,
+            max: OPERATOR_COUNT,
+        }));
+    }
+
+    let mut voice = Voice::new();
+    for (index, operator_element) in operators_element.children_named("operator").into_iter().enumerate() {
+        voice.operators[index] = parse_operator(operator_element)?;
+    }
+
+    voice.name = VoiceName::new(name);
+    voice.alg = element.ranged_attribute::<Algorithm>("algorithm")?;
+    voice.transpose = element.ranged_attribute::<Transpose>("transpose")?;
+    voice.feedback = element.ranged_attribute::<Depth>("feedback")?;
+    voice.osc_sync = element.bool_attribute("oscillatorSync")?;
+    voice.pitch_mod_sens = element.ranged_attribute::<Depth>("pitchModulationSensitivity")?;
+    voice.peg = parse_envelope(element.child("peg")?)?;
+    voice.lfo = parse_lfo(element.child("lfo")?)?;
+
+    Ok(voice)
+}
+
+/// Converts an `operator` element into an operator.
+pub fn parse_operator(element: &Element) -> Result<Operator, XmlError> {
+    element.check_attributes(&[
+        "level", "mode", "coarse", "fine", "detune",
+        "amplitudeModulationSensitivity", "keyVelocitySensitivity", "keyboardRateScaling",
+    ])?;
+    element.check_children(&["eg", "keyboardLevelScaling"], false)?;
+
+    let mode = element.required_attribute("mode")?;
+    let mode = match mode {
+        "ratio" => OperatorMode::Ratio,
+        "fixed" => OperatorMode::Fixed,
+        _ => {
+            return Err(element.error(XmlErrorKind::InvalidValue {
+                name: String::from("mode"),
+                value: mode.to_string(),
+                expected: String::from("ratio or fixed"),
+            }));
+        }
+    };
+
+    Ok(Operator {
+        eg: parse_envelope(element.child("eg")?)?,
+        kbd_level_scaling: parse_kls(element.child("keyboardLevelScaling")?)?,
+        kbd_rate_scaling: element.ranged_attribute::<Depth>("keyboardRateScaling")?,
+        amp_mod_sens: element.ranged_attribute::<Sensitivity>("amplitudeModulationSensitivity")?,
+        key_vel_sens: element.ranged_attribute::<Depth>("keyVelocitySensitivity")?,
+        output_level: element.ranged_attribute::<Level>("level")?,
+        mode,
+        coarse: element.ranged_attribute::<Coarse>("coarse")?,
+        fine: element.ranged_attribute::<Level>("fine")?,
+        detune: element.ranged_attribute::<Detune>("detune")?,
+    })
+}
+
+// Parses the text content of a `rates` or `levels` element,
+// which must be four integers separated by whitespace.
+fn parse_four<T: Ranged + Copy>(element: &Element) -> Result<[T; 4], XmlError> {
+    element.check_attributes(&[])?;
+    element.check_children(&[], true)?;
+
+    let parts: Vec<&str> = element.text.split_whitespace().collect();
+    if parts.len() != 4 {
+        return Err(element.error(XmlErrorKind::InvalidValue {
+            name: element.name.clone(),
+            value: element.text.clone(),
+            expected: String::from("four integers separated by spaces"),
+        }));
+    }
+
+    let mut values = [T::new(T::DEFAULT); 4];
+    for (index, part) in parts.iter().enumerate() {
+        let name = format!("{}[{}]", element.name, index + 1);
+        values[index] = parse_ranged(part, &name).map_err(|kind| element.error(kind))?;
+    }
+    Ok(values)
+}
+
+/// Converts an `eg` or `peg` element into an envelope.
+pub fn parse_envelope(element: &Element) -> Result<Envelope, XmlError> {
+    element.check_attributes(&[])?;
+    element.check_children(&["rates", "levels"], false)?;
+
+    let rates: Rates = parse_four::<Rate>(element.child("rates")?)?;
+    let levels: Levels = parse_four::<Level>(element.child("levels")?)?;
+    Ok(Envelope::new_rate_level(rates, levels))
+}
+
+// Parses a keyboard level scaling curve like "+LIN".
+fn parse_curve(element: &Element, name: &str) -> Result<ScalingCurve, XmlError> {
+    let value = element.required_attribute(name)?;
+    match value {
+        "+LIN" => Ok(ScalingCurve::lin_pos()),
+        "-LIN" => Ok(ScalingCurve::lin_neg()),
+        "+EXP" => Ok(ScalingCurve::exp_pos()),
+        "-EXP" => Ok(ScalingCurve::exp_neg()),
+        _ => Err(element.error(XmlErrorKind::InvalidValue {
+            name: name.to_string(),
+            value: value.to_string(),
+            expected: String::from("+LIN, -LIN, +EXP or -EXP"),
+        }))
+    }
+}
+
+/// Converts a `keyboardLevelScaling` element.
+pub fn parse_kls(element: &Element) -> Result<KeyboardLevelScaling, XmlError> {
+    element.check_attributes(&["breakpoint"])?;
+    element.check_children(&["depth", "curve"], false)?;
+
+    let depth = element.child("depth")?;
+    depth.check_attributes(&["left", "right"])?;
+    depth.check_children(&[], false)?;
+
+    let curve = element.child("curve")?;
+    curve.check_attributes(&["left", "right"])?;
+    curve.check_children(&[], false)?;
+
+    Ok(KeyboardLevelScaling {
+        breakpoint: element.ranged_attribute::<Key>("breakpoint")?,
+        left: Scaling {
+            depth: depth.ranged_attribute::<Level>("left")?,
+            curve: parse_curve(curve, "left")?,
+        },
+        right: Scaling {
+            depth: depth.ranged_attribute::<Level>("right")?,
+            curve: parse_curve(curve, "right")?,
+        },
+    })
+}
+
+/// Converts an `lfo` element into an LFO.
+pub fn parse_lfo(element: &Element) -> Result<Lfo, XmlError> {
+    element.check_attributes(&["speed", "delay", "pmd", "amd", "sync", "wave"])?;
+    element.check_children(&[], false)?;
+
+    let wave = element.required_attribute("wave")?;
+    let waveform = match wave {
+        "triangle" => LfoWaveform::Triangle,
+        "saw-down" => LfoWaveform::SawDown,
+        "saw-up" => LfoWaveform::SawUp,
+        "square" => LfoWaveform::Square,
+        "sine" => LfoWaveform::Sine,
+        "sample-and-hold" => LfoWaveform::SampleAndHold,
+        _ => {
+            return Err(element.error(XmlErrorKind::InvalidValue {
+                name: String::from("wave"),
+                value: wave.to_string(),
+                expected: String::from("triangle, saw-down, saw-up, square, sine or sample-and-hold"),
+            }));
+        }
+    };
+
+    Ok(Lfo {
+        speed: element.ranged_attribute::<Level>("speed")?,
+        delay: element.ranged_attribute::<Level>("delay")?,
+        pmd: element.ranged_attribute::<Level>("pmd")?,
+        amd: element.ranged_attribute::<Level>("amd")?,
+        sync: element.bool_attribute("sync")?,
+        waveform,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sevenate::dx7::sysex::SystemExclusiveData;
+    use crate::dx7::Document;
+
+    const ROM1A: [u8; 4096] = include!("../rom1asyx.in");
+
+    fn rom1a() -> Cartridge {
+        Cartridge::parse(&ROM1A).expect("ROM1A should parse")
+    }
+
+    #[test]
+    fn voices_round_trip() {
+        for voice in rom1a().voices {
+            let xml = crate::to_xml(&Document::Voice(Box::new(voice.clone())));
+            let root = read_document(xml.as_bytes()).unwrap();
+            let parsed = parse_voice(&root).unwrap();
+            assert_eq!(parsed.to_bytes(), voice.to_bytes(), "voice '{}'", voice.name.value());
+        }
+    }
+
+    #[test]
+    fn cartridge_round_trips() {
+        let xml = crate::to_xml(&Document::Cartridge(rom1a()));
+        let root = read_document(xml.as_bytes()).unwrap();
+        let parsed = parse_cartridge(&root).unwrap();
+        assert_eq!(parsed.to_bytes(), ROM1A.to_vec());
+    }
+}
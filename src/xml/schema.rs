@@ -0,0 +1,427 @@
+//! Validation of XML documents against the rules of the dx7-xml schema
+//! (https://github.com/coniferprod/dx7-xml). The rules are embedded here,
+//! so there is no need for the schema files or an external validator.
+//! Unlike converting a document, validation reports every violation.
+//! TX802 performances are not in the dx7-xml schema, so their rules
+//! are an extension in the same style.
+
+use sevenate::Ranged;
+use sevenate::dx7::{Algorithm, Transpose, Depth, Level, Coarse, Detune, Sensitivity};
+use sevenate::dx7::voice::OPERATOR_COUNT;
+use sevenate::dx7::cartridge::VOICE_COUNT;
+use sevenate::dx7::operator::Key;
+use sevenate::dx7::envelope::Rate;
+
+use crate::dx7::is_name_character;
+use crate::dx7::supplement;
+use crate::tx802::{self, PERFORMANCE_COUNT, TONE_GENERATOR_COUNT};
+use crate::xml::{Element, XmlError, XmlErrorKind};
+
+/// The type of an attribute value or text content.
+#[derive(Debug, Clone, Copy)]
+pub enum ValueType {
+    /// Integer in the range first...last
+    Integer(i32, i32),
+    /// "true" or "false"
+    Boolean,
+    /// One of the listed values
+    Enumeration(&'static [&'static str]),
+    /// Printable ASCII characters, at most this many
+    Text(usize),
+    /// This many integers in the range first...last, separated by whitespace
+    IntegerList(usize, i32, i32),
+}
+
+/// Rule for an attribute of an element.
+#[derive(Debug)]
+pub struct AttributeRule {
+    pub name: &'static str,
+    pub value_type: ValueType,
+}
+
+/// Rule for the number of child elements with the same name.
+#[derive(Debug)]
+pub struct ChildRule {
+    pub name: &'static str,
+    pub min: usize,
+    pub max: usize,
+}
+
+/// Rule for an element. All the attributes are required.
+#[derive(Debug)]
+pub struct ElementRule {
+    pub name: &'static str,
+    pub attributes: &'static [AttributeRule],
+    pub children: &'static [ChildRule],
+    pub text: Option<ValueType>,
+}
+
+const fn attribute(name: &'static str, value_type: ValueType) -> AttributeRule {
+    AttributeRule { name, value_type }
+}
+
+const fn one(name: &'static str) -> ChildRule {
+    ChildRule { name, min: 1, max: 1 }
+}
+
+const fn ranged<T: Ranged>() -> ValueType {
+    ValueType::Integer(T::FIRST, T::LAST)
+}
+
+// The rules for the attributes of a TX802 tone generator, one for each parameter.
+const TONE_GENERATOR_ATTRIBUTES: [AttributeRule; tx802::PARAMETERS.len()] = {
+    let mut rules = [const { attribute("", ValueType::Boolean) }; tx802::PARAMETERS.len()];
+    let mut index = 0;
+    while index < rules.len() {
+        let parameter = &tx802::PARAMETERS[index];
+        rules[index] = attribute(parameter.attribute, ValueType::Integer(0, parameter.maximum as i32));
+        index += 1;
+    }
+    rules
+};
+
+// The rules for the attributes of a DX7II supplement, one for each parameter
+// and one for the reserved bytes.
+const SUPPLEMENT_ATTRIBUTES: [AttributeRule; supplement::PARAMETERS.len() + 1] = {
+    let mut rules = [const { attribute("", ValueType::Boolean) }; supplement::PARAMETERS.len() + 1];
+    let mut index = 0;
+    while index < supplement::PARAMETERS.len() {
+        let parameter = &supplement::PARAMETERS[index];
+        rules[index] = attribute(parameter.attribute, ValueType::Integer(0, parameter.maximum as i32));
+        index += 1;
+    }
+    rules[index] = attribute("reserved",
+        ValueType::IntegerList(supplement::SUPPLEMENT_SIZE - supplement::RESERVED_OFFSET, 0, 127));
+    rules
+};
+
+const ENVELOPE_CHILDREN: &[ChildRule] = &[one("rates"), one("levels")];
+
+const CURVES: &[&str] = &["+LIN", "-LIN", "+EXP", "-EXP"];
+
+/// The rules of the schema. Element names have the same meaning
+/// wherever they appear, so the rules are looked up by name.
+pub static SCHEMA: &[ElementRule] = &[
+    ElementRule {
+        name: "cartridge",
+        attributes: &[],
+        children: &[one("voices")],
+        text: None,
+    },
+    ElementRule {
+        name: "voices",
+        attributes: &[],
+        children: &[ChildRule { name: "voice", min: VOICE_COUNT, max: VOICE_COUNT }],
+        text: None,
+    },
+    ElementRule {
+        name: "voice",
+        attributes: &[
+            attribute("name", ValueType::Text(10)),
+            attribute("algorithm", ranged::<Algorithm>()),
+            attribute("transpose", ranged::<Transpose>()),
+            attribute("feedback", ranged::<Depth>()),
+            attribute("oscillatorSync", ValueType::Boolean),
+            attribute("pitchModulationSensitivity", ranged::<Depth>()),
+        ],
+        children: &[one("peg"), one("lfo"), one("operators"), ChildRule { name: "supplement", min: 0, max: 1 }],
+        text: None,
+    },
+    ElementRule {
+        name: "peg",
+        attributes: &[],
+        children: ENVELOPE_CHILDREN,
+        text: None,
+    },
+    ElementRule {
+        name: "eg",
+        attributes: &[],
+        children: ENVELOPE_CHILDREN,
+        text: None,
+    },
+    ElementRule {
+        name: "rates",
+        attributes: &[],
+        children: &[],
+        text: Some(ValueType::IntegerList(4, Rate::FIRST, Rate::LAST)),
+    },
+    ElementRule {
+        name: "levels",
+        attributes: &[],
+        children: &[],
+        text: Some(ValueType::IntegerList(4, Level::FIRST, Level::LAST)),
+    },
+    ElementRule {
+        name: "lfo",
+        attributes: &[
+            attribute("speed", ranged::<Level>()),
+            attribute("delay", ranged::<Level>()),
+            attribute("pmd", ranged::<Level>()),
+            attribute("amd", ranged::<Level>()),
+            attribute("sync", ValueType::Boolean),
+            attribute("wave", ValueType::Enumeration(&[
+                "triangle", "saw-down", "saw-up", "square", "sine", "sample-and-hold"
+            ])),
+        ],
+        children: &[],
+        text: None,
+    },
+    ElementRule {
+        name: "operators",
+        attributes: &[],
+        children: &[ChildRule { name: "operator", min: OPERATOR_COUNT, max: OPERATOR_COUNT }],
+        text: None,
+    },
+    ElementRule {
+        name: "operator",
+        attributes: &[
+            attribute("level", ranged::<Level>()),
+            attribute("mode", ValueType::Enumeration(&["ratio", "fixed"])),
+            attribute("coarse", ranged::<Coarse>()),
+            attribute("fine", ranged::<Level>()),
+            attribute("detune", ranged::<Detune>()),
+            attribute("amplitudeModulationSensitivity", ranged::<Sensitivity>()),
+            attribute("keyVelocitySensitivity", ranged::<Depth>()),
+            attribute("keyboardRateScaling", ranged::<Depth>()),
+        ],
+        children: &[one("eg"), one("keyboardLevelScaling")],
+        text: None,
+    },
+    ElementRule {
+        name: "keyboardLevelScaling",
+        attributes: &[attribute("breakpoint", ranged::<Key>())],
+        children: &[one("depth"), one("curve")],
+        text: None,
+    },
+    ElementRule {
+        name: "depth",
+        attributes: &[
+            attribute("left", ranged::<Level>()),
+            attribute("right", ranged::<Level>()),
+        ],
+        children: &[],
+        text: None,
+    },
+    ElementRule {
+        name: "curve",
+        attributes: &[
+            attribute("left", ValueType::Enumeration(CURVES)),
+            attribute("right", ValueType::Enumeration(CURVES)),
+        ],
+        children: &[],
+        text: None,
+    },
+    ElementRule {
+        name: "supplement",
+        attributes: &SUPPLEMENT_ATTRIBUTES,
+        children: &[],
+        text: None,
+    },
+    ElementRule {
+        name: "performances",
+        attributes: &[],
+        children: &[ChildRule { name: "performance", min: PERFORMANCE_COUNT, max: PERFORMANCE_COUNT }],
+        text: None,
+    },
+    ElementRule {
+        name: "performance",
+        attributes: &[attribute("name", ValueType::Text(tx802::NAME_LENGTH))],
+        children: &[ChildRule { name: "toneGenerator", min: TONE_GENERATOR_COUNT, max: TONE_GENERATOR_COUNT }],
+        text: None,
+    },
+    ElementRule {
+        name: "toneGenerator",
+        attributes: &TONE_GENERATOR_ATTRIBUTES,
+        children: &[],
+        text: None,
+    },
+];
+
+/// Finds the rule for an element by name.
+pub fn find_rule(name: &str) -> Option<&'static ElementRule> {
+    SCHEMA.iter().find(|rule| rule.name == name)
+}
+
+/// Validates a document, given its root element, which must be
+/// `voice`, `cartridge` or `performances`. Returns all the violations found,
+/// in document order. An empty result means that the document is valid.
+pub fn validate(root: &Element) -> Vec<XmlError> {
+    let mut errors = Vec::new();
+
+    if root.name == "voice" || root.name == "cartridge" || root.name == "performances" {
+        validate_element(root, &mut errors);
+    } else {
+        errors.push(root.error(XmlErrorKind::UnexpectedElement(root.name.clone())));
+    }
+
+    errors
+}
+
+fn validate_element(element: &Element, errors: &mut Vec<XmlError>) {
+    let Some(rule) = find_rule(&element.name) else {
+        errors.push(element.error(XmlErrorKind::UnexpectedElement(element.name.clone())));
+        return;
+    };
+
+    // Attributes with a namespace prefix (like "xml:base") are always allowed.
+    for (name, _) in &element.attributes {
+        if !name.contains(':') && !rule.attributes.iter().any(|a| a.name == name) {
+            errors.push(element.error(XmlErrorKind::UnknownAttribute(name.clone())));
+        }
+    }
+
+    for attribute_rule in rule.attributes {
+        match element.attribute(attribute_rule.name) {
+            Some(value) => {
+                if let Err(kind) = check_value(attribute_rule.name, value, attribute_rule.value_type) {
+                    errors.push(element.error(kind));
+                }
+            },
+            None => {
+                errors.push(element.error(XmlErrorKind::MissingAttribute(attribute_rule.name.to_string())));
+            }
+        }
+    }
+
+    match rule.text {
+        Some(value_type) => {
+            if let Err(kind) = check_value(&element.name, &element.text, value_type) {
+                errors.push(element.error(kind));
+            }
+        },
+        None => {
+            if !element.text.is_empty() {
+                errors.push(element.error(XmlErrorKind::UnexpectedText(element.text.clone())));
+            }
+        }
+    }
+
+    for child_rule in rule.children {
+        let count = element.children_named(child_rule.name).len();
+        if count == 0 && child_rule.min > 0 {
+            errors.push(element.error(XmlErrorKind::MissingElement(child_rule.name.to_string())));
+        } else if child_rule.max == 1 && count > 1 {
+            let duplicate = element.children_named(child_rule.name)[1];
+            errors.push(duplicate.error(XmlErrorKind::DuplicateElement(child_rule.name.to_string())));
+        } else if count < child_rule.min || count > child_rule.max {
+            errors.push(element.error(XmlErrorKind::WrongCount {
+                name: child_rule.name.to_string(),
+                count,
+                min: child_rule.min,
+                max: child_rule.max,
+            }));
+        }
+    }
+
+    for child in &element.children {
+        if rule.children.iter().any(|c| c.name == child.name) {
+            validate_element(child, errors);
+        } else {
+            errors.push(child.error(XmlErrorKind::UnexpectedElement(child.name.clone())));
+        }
+    }
+}
+
+// Checks that a value matches its type.
+fn check_value(name: &str, value: &str, value_type: ValueType) -> Result<(), XmlErrorKind> {
+    let invalid = |expected: String| XmlErrorKind::InvalidValue {
+        name: name.to_string(),
+        value: value.to_string(),
+        expected,
+    };
+
+    match value_type {
+        ValueType::Integer(first, last) => {
+            check_integer(name, value, first, last)
+        },
+        ValueType::Boolean => {
+            if value == "true" || value == "false" {
+                Ok(())
+            } else {
+                Err(invalid(String::from("true or false")))
+            }
+        },
+        ValueType::Enumeration(values) => {
+            if values.contains(&value) {
+                Ok(())
+            } else {
+                Err(invalid(format!("one of {}", values.join(", "))))
+            }
+        },
+        ValueType::Text(max_length) => {
+            if value.chars().count() <= max_length && value.chars().all(is_name_character) {
+                Ok(())
+            } else {
+                Err(invalid(format!("at most {} printable ASCII characters", max_length)))
+            }
+        },
+        ValueType::IntegerList(count, first, last) => {
+            let parts: Vec<&str> = value.split_whitespace().collect();
+            if parts.len() != count {
+                return Err(invalid(format!("{} integers separated by spaces", count)));
+            }
+            for (index, part) in parts.iter().enumerate() {
+                check_integer(&format!("{}[{}]", name, index + 1), part, first, last)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+fn check_integer(name: &str, value: &str, first: i32, last: i32) -> Result<(), XmlErrorKind> {
+    match value.trim().parse::<i32>() {
+        Ok(number) if number >= first && number <= last => Ok(()),
+        Ok(number) => Err(XmlErrorKind::OutOfRange {
+            name: name.to_string(),
+            value: number,
+            first,
+            last,
+        }),
+        Err(_) => Err(XmlErrorKind::InvalidValue {
+            name: name.to_string(),
+            value: value.to_string(),
+            expected: format!("an integer {}...{}", first, last),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sevenate::dx7::voice::Voice;
+    use crate::dx7::Document;
+    use crate::dx7::supplement::{Supplement, SupplementedDocument};
+    use crate::xml::read_document;
+
+    // Reads a voice element with a supplement.
+    fn supplemented_voice() -> Element {
+        let document = SupplementedDocument {
+            document: Document::Voice(Box::new(Voice::new())),
+            supplements: vec![Supplement::new()],
+        };
+        read_document(crate::supplemented_xml(&document).as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn accepts_one_supplement() {
+        assert_eq!(validate(&supplemented_voice()), vec![]);
+    }
+
+    #[test]
+    fn reports_duplicate_supplement() {
+        let mut voice = supplemented_voice();
+        let supplement = voice.children_named("supplement")[0].clone();
+        voice.children.push(supplement);
+
+        let kinds: Vec<XmlErrorKind> = validate(&voice).into_iter().map(|e| e.kind).collect();
+        assert_eq!(kinds, vec![XmlErrorKind::DuplicateElement(String::from("supplement"))]);
+    }
+
+    #[test]
+    fn describes_range_of_count() {
+        let kind = XmlErrorKind::WrongCount { name: String::from("voice"), count: 3, min: 0, max: 2 };
+        assert_eq!(kind.to_string(), "found 3 <voice> elements, expected 0...2");
+        let kind = XmlErrorKind::WrongCount { name: String::from("voice"), count: 3, min: 32, max: 32 };
+        assert_eq!(kind.to_string(), "found 3 <voice> elements, expected 32");
+    }
+}